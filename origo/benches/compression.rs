@@ -0,0 +1,142 @@
+//! Write latency vs. size of journals and snapshots per compression
+//!
+//! `cargo bench -p origo --features zstd,lz4 --bench compression`
+
+use bincode::{Decode, Encode};
+use origo::{
+    storage::{Compression, DiskStorage, JournalReader, Storage},
+    Bincode, Command, CommandMeta, EngineState, Envelope, ExecutionContext,
+};
+use std::{collections::BTreeMap, fs, path::Path, time::Instant};
+
+const COMMANDS: usize = 2_000;
+const ORDERS: usize = 200_000;
+
+#[derive(Encode, Decode, Default)]
+struct Model {
+    orders: BTreeMap<usize, Order>,
+}
+
+#[derive(Encode, Decode, Clone)]
+struct Order {
+    order_id: usize,
+    name: String,
+    address: String,
+    transport_id: usize,
+}
+
+#[derive(Encode, Decode)]
+struct InsertOrder(Order);
+
+impl Command<Model> for InsertOrder {
+    fn execute(&self, model: &mut Model, _ctx: &mut ExecutionContext) {
+        model.orders.insert(self.0.order_id, self.0.clone());
+    }
+}
+
+fn order(i: usize) -> Order {
+    Order {
+        order_id: i,
+        name: format!("Customer {} of the compression benchmark", i % 5_000),
+        address: format!("Street {}, {} City", i % 300, i % 40),
+        transport_id: i % 1_000,
+    }
+}
+
+fn main() {
+    let model = Model {
+        orders: (0..ORDERS).map(|i| (i, order(i))).collect(),
+    };
+
+    println!(
+        "{:<12} {:>9} {:>9} {:>14} {:>14} {:>15} {:>12} {:>13}",
+        "compression",
+        "p50 µs",
+        "p99 µs",
+        "records KiB",
+        "no dict. KiB",
+        "dictionary KiB",
+        "snapshot ms",
+        "snapshot KiB"
+    );
+
+    for compression in [
+        Compression::None,
+        Compression::Lz4,
+        Compression::Zstd { level: 1 },
+        Compression::Zstd { level: 3 },
+        Compression::Zstd { level: 9 },
+    ] {
+        let directory = std::env::temp_dir().join(format!("origo-bench-{:?}", compression));
+        let _ = fs::remove_dir_all(&directory);
+        let journal = directory.join("journal.origors");
+
+        let mut storage = DiskStorage::new(&journal).compression(compression);
+        let mut state = EngineState::default();
+        storage.restore::<Model, Bincode>(&Default::default(), &mut state, &mut |_, _| {});
+
+        // The first journal has no dictionary, the snapshot trains one on its records for the next journal
+        write_commands(&mut storage, 0);
+        let (first_records, _) = journal_size(&journal);
+
+        let instant = Instant::now();
+        storage.snapshot::<Model, Bincode>(&state, &model);
+        let snapshot_ms = instant.elapsed().as_millis();
+
+        let latencies = write_commands(&mut storage, COMMANDS);
+        let (records, header) = journal_size(&journal);
+
+        println!(
+            "{:<12} {:>9} {:>9} {:>14} {:>14} {:>15} {:>12} {:>13}",
+            format!("{:?}", compression)
+                .replace(" { level: ", "-")
+                .replace(" }", ""),
+            latencies[COMMANDS / 2],
+            latencies[COMMANDS * 99 / 100],
+            records / 1024,
+            first_records / 1024,
+            header / 1024,
+            snapshot_ms,
+            file_size(&directory.join(origo::storage::SNAPSHOT_FILE)) / 1024,
+        );
+
+        drop(storage);
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
+
+/// Commits `COMMANDS` commands after `start`, returns the sorted latencies
+fn write_commands(storage: &mut DiskStorage, start: usize) -> Vec<u128> {
+    let mut latencies = Vec::with_capacity(COMMANDS);
+    for i in start..start + COMMANDS {
+        let envelope = Envelope {
+            sequence: i as u64 + 1,
+            timestamp: 0,
+            seed: 0,
+            idempotency_key: None,
+            meta: CommandMeta::default(),
+        };
+        let command = InsertOrder(order(ORDERS + i));
+
+        let instant = Instant::now();
+        storage.prepare::<Model, Bincode, _>(&envelope, "InsertOrder", &command);
+        storage.commit();
+        latencies.push(instant.elapsed().as_micros());
+    }
+    latencies.sort_unstable();
+    latencies
+}
+
+/// Bytes of the records and of the header, which holds the dictionary
+fn journal_size(path: &Path) -> (u64, u64) {
+    let header = JournalReader::open(path)
+        .expect("Failed to open journal")
+        .records_offset();
+    (file_size(path) - header, header)
+}
+
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path)
+        .map(|metadata| metadata.len())
+        .unwrap_or(0)
+}
@@ -0,0 +1,135 @@
+//! Commit throughput and latency of the journal writers
+//!
+//! `cargo bench -p origo --bench writer`, with `--features mmap-writer` to include the mapped writer
+
+use bincode::{Decode, Encode};
+use origo::{
+    storage::{DiskStorage, JournalWriter, Storage},
+    Bincode, Command, CommandMeta, EngineState, Envelope, ExecutionContext,
+};
+use std::{
+    fs::{self, File},
+    io::Write,
+    os::unix::fs::FileExt,
+    time::Instant,
+};
+
+const COMMANDS: usize = 5_000;
+
+#[derive(Encode, Decode, Default)]
+struct Model {
+    orders: Vec<Order>,
+}
+
+#[derive(Encode, Decode, Clone)]
+struct Order {
+    order_id: usize,
+    name: String,
+    transport_id: usize,
+}
+
+#[derive(Encode, Decode)]
+struct InsertOrder(Order);
+
+impl Command<Model> for InsertOrder {
+    fn execute(&self, model: &mut Model, _ctx: &mut ExecutionContext) {
+        model.orders.push(self.0.clone());
+    }
+}
+
+fn main() {
+    println!(
+        "{:<40} {:>12} {:>9} {:>9}",
+        "writer", "commits/s", "p50 µs", "p99 µs"
+    );
+
+    let directory = std::env::temp_dir().join("origo-bench-writer");
+
+    // The commit of format versions before 1: append, rewrite the record count in the header, `sync_all`
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    let mut journal = File::create(directory.join("journal.origors")).unwrap();
+    journal.write_all(&0u64.to_le_bytes()).unwrap();
+    let mut buffer = Vec::new();
+    measure("Buffered, sync_all and header count", |i| {
+        buffer.clear();
+        bincode::encode_into_std_write(envelope(i), &mut buffer, bincode::config::standard())
+            .unwrap();
+        bincode::encode_into_std_write(command(i), &mut buffer, bincode::config::standard())
+            .unwrap();
+        journal.write_all(&buffer).unwrap();
+        journal
+            .write_all_at(&(i as u64 + 1).to_le_bytes(), 0)
+            .unwrap();
+        journal.sync_all().unwrap();
+    });
+    drop(journal);
+
+    let journal_writers = [
+        JournalWriter::Buffered,
+        JournalWriter::Preallocated {
+            segment_len: 1 << 20,
+        },
+        JournalWriter::Preallocated {
+            segment_len: 64 << 20,
+        },
+        #[cfg(feature = "mmap-writer")]
+        JournalWriter::Mapped { grow_by: 1 << 20 },
+        #[cfg(feature = "mmap-writer")]
+        JournalWriter::Mapped { grow_by: 64 << 20 },
+    ];
+    for journal_writer in journal_writers {
+        let _ = fs::remove_dir_all(&directory);
+
+        let mut storage =
+            DiskStorage::new(directory.join("journal.origors")).journal_writer(journal_writer);
+        let mut state = EngineState::default();
+        storage.restore::<Model, Bincode>(&Default::default(), &mut state, &mut |_, _| {});
+
+        measure(&format!("{:?}", journal_writer), |i| {
+            storage.prepare::<Model, Bincode, _>(&envelope(i), "InsertOrder", &command(i));
+            storage.commit();
+        });
+        drop(storage);
+    }
+    let _ = fs::remove_dir_all(&directory);
+}
+
+fn envelope(i: usize) -> Envelope {
+    Envelope {
+        sequence: i as u64 + 1,
+        timestamp: 0,
+        seed: 0,
+        idempotency_key: None,
+        meta: CommandMeta::default(),
+    }
+}
+
+fn command(i: usize) -> InsertOrder {
+    InsertOrder(Order {
+        order_id: i,
+        name: format!("Customer {}", i),
+        transport_id: i % 1_000,
+    })
+}
+
+/// Runs `commit` for every command, prints the throughput and the latencies of the commits
+fn measure(name: &str, mut commit: impl FnMut(usize)) {
+    let mut latencies = Vec::with_capacity(COMMANDS);
+    let instant = Instant::now();
+    for i in 0..COMMANDS {
+        let start = Instant::now();
+        commit(i);
+        latencies.push(start.elapsed().as_micros());
+    }
+    let elapsed = instant.elapsed();
+    latencies.sort_unstable();
+
+    println!(
+        "{:<40} {:>12.0} {:>9} {:>9}",
+        name,
+        COMMANDS as f64 / elapsed.as_secs_f64(),
+        latencies[COMMANDS / 2],
+        latencies[COMMANDS * 99 / 100],
+    );
+}
@@ -0,0 +1,77 @@
+use arc_swap::ArcSwap;
+use parking_lot::RwLock;
+use std::sync::Arc;
+
+/// Holds the model of an [`crate::Engine`]
+///
+/// Writes are serialized by the engine, a cell only has to handle concurrent reads
+pub trait ModelCell<TModel>: Send + Sync {
+    fn new(model: TModel) -> Self;
+
+    fn read<R, F: FnOnce(&TModel) -> R>(&self, f: F) -> R;
+
+    /// The new state must not be visible to readers before `f` returns
+    fn write<R, F: FnOnce(&mut TModel) -> R>(&self, f: F) -> R;
+}
+
+/// The default cell, the model is ReadWriteLocked
+///
+/// Multiple queries can run at the same time but a command blocks all queries and vice versa
+pub struct Locked<TModel> {
+    model: RwLock<TModel>,
+}
+
+impl<TModel: Send + Sync> ModelCell<TModel> for Locked<TModel> {
+    fn new(model: TModel) -> Self {
+        Locked {
+            model: RwLock::new(model),
+        }
+    }
+
+    #[inline(always)]
+    fn read<R, F: FnOnce(&TModel) -> R>(&self, f: F) -> R {
+        f(&self.model.read())
+    }
+
+    fn write<R, F: FnOnce(&mut TModel) -> R>(&self, f: F) -> R {
+        f(&mut self.model.write())
+    }
+}
+
+/// Copy-on-write cell, every command clones the current version, executes against the clone
+/// and publishes it as the new version atomically
+///
+/// Queries never block and never block commands,
+/// the cost is a clone of the model per command so it should be cheap to clone,
+/// e.g. `Arc`'ed parts or persistent data structures.
+/// Indexes aren't versioned, [`crate::Engine::query_index`] waits for the running command
+pub struct Versioned<TModel> {
+    current: ArcSwap<TModel>,
+}
+
+impl<TModel> Versioned<TModel> {
+    /// The current version of the model, it never changes after it's returned
+    pub fn load(&self) -> Arc<TModel> {
+        self.current.load_full()
+    }
+}
+
+impl<TModel: Clone + Send + Sync> ModelCell<TModel> for Versioned<TModel> {
+    fn new(model: TModel) -> Self {
+        Versioned {
+            current: ArcSwap::from_pointee(model),
+        }
+    }
+
+    #[inline(always)]
+    fn read<R, F: FnOnce(&TModel) -> R>(&self, f: F) -> R {
+        f(&self.current.load())
+    }
+
+    fn write<R, F: FnOnce(&mut TModel) -> R>(&self, f: F) -> R {
+        let mut next = TModel::clone(&self.current.load());
+        let result = f(&mut next);
+        self.current.store(Arc::new(next));
+        result
+    }
+}
@@ -0,0 +1,170 @@
+use crate::storage::BINCODE_CONFIG;
+
+use std::{fmt, io::Write};
+
+/// Serialization format of the model and the commands in the journal and snapshot
+///
+/// A codec implements this for every type it can serialize,
+/// the envelopes and file headers are always bincode.
+/// The name of the codec is recorded in the file headers, restore fails if it doesn't match
+///
+/// Select the codec with [`crate::EngineBuilder::codec`] or the `codec` prefix of [`crate::origo_engine`]
+pub trait Codec<T>: Send + Sync + 'static {
+    const NAME: &'static str;
+
+    /// Alignment of the encoded model in the snapshot, for codecs that access it in place
+    const ALIGN: usize = 1;
+
+    fn encode<W: Write>(value: &T, writer: &mut W) -> Result<(), CodecError>;
+
+    /// Decodes `data`, which must contain exactly one value
+    fn decode(data: &[u8]) -> Result<T, CodecError>;
+}
+
+#[derive(Debug)]
+pub struct CodecError(pub String);
+
+impl CodecError {
+    fn new<E: fmt::Display>(e: E) -> Self {
+        CodecError(e.to_string())
+    }
+
+    fn trailing(length: usize) -> Self {
+        CodecError(format!("{} trailing bytes", length))
+    }
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// [`Codec::NAME`] of [`Bincode`], files written before the codec was recorded are bincode
+pub(crate) const BINCODE_CODEC: &str = "bincode";
+
+/// bincode with the standard configuration, the default codec
+pub struct Bincode;
+
+impl<T: bincode::Encode + bincode::Decode> Codec<T> for Bincode {
+    const NAME: &'static str = BINCODE_CODEC;
+
+    fn encode<W: Write>(value: &T, writer: &mut W) -> Result<(), CodecError> {
+        bincode::encode_into_std_write(value, writer, BINCODE_CONFIG)
+            .map(|_| ())
+            .map_err(CodecError::new)
+    }
+
+    fn decode(data: &[u8]) -> Result<T, CodecError> {
+        let (value, length) =
+            bincode::decode_from_slice(data, BINCODE_CONFIG).map_err(CodecError::new)?;
+        match length == data.len() {
+            true => Ok(value),
+            false => Err(CodecError::trailing(data.len() - length)),
+        }
+    }
+}
+
+/// serde + postcard, compact like bincode for types that only derive serde
+#[cfg(feature = "postcard")]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> Codec<T> for Postcard {
+    const NAME: &'static str = "postcard";
+
+    fn encode<W: Write>(value: &T, writer: &mut W) -> Result<(), CodecError> {
+        postcard::to_io(value, writer)
+            .map(|_| ())
+            .map_err(CodecError::new)
+    }
+
+    fn decode(data: &[u8]) -> Result<T, CodecError> {
+        let (value, rest) = postcard::take_from_bytes(data).map_err(CodecError::new)?;
+        match rest.is_empty() {
+            true => Ok(value),
+            false => Err(CodecError::trailing(rest.len())),
+        }
+    }
+}
+
+/// serde + MessagePack
+#[cfg(feature = "msgpack")]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> Codec<T> for MessagePack {
+    const NAME: &'static str = "msgpack";
+
+    fn encode<W: Write>(value: &T, writer: &mut W) -> Result<(), CodecError> {
+        rmp_serde::encode::write(writer, value).map_err(CodecError::new)
+    }
+
+    fn decode(data: &[u8]) -> Result<T, CodecError> {
+        let mut rest = data;
+        let value = T::deserialize(&mut rmp_serde::Deserializer::new(&mut rest))
+            .map_err(CodecError::new)?;
+        match rest.is_empty() {
+            true => Ok(value),
+            false => Err(CodecError::trailing(rest.len())),
+        }
+    }
+}
+
+/// serde + JSON, slow and large but readable, meant for debugging during development
+#[cfg(feature = "json")]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> Codec<T> for Json {
+    const NAME: &'static str = "json";
+
+    fn encode<W: Write>(value: &T, writer: &mut W) -> Result<(), CodecError> {
+        serde_json::to_writer(writer, value).map_err(CodecError::new)
+    }
+
+    fn decode(data: &[u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(data).map_err(CodecError::new)
+    }
+}
+
+/// rkyv, the model is validated and deserialized straight from the memory-mapped snapshot
+/// and can be queried without deserializing through [`crate::storage::MappedSnapshot`]
+#[cfg(feature = "rkyv")]
+pub struct Rkyv;
+
+#[cfg(feature = "rkyv")]
+impl<T> Codec<T> for Rkyv
+where
+    T: rkyv::Archive
+        + for<'a> rkyv::Serialize<
+            rkyv::api::high::HighSerializer<
+                rkyv::util::AlignedVec,
+                rkyv::ser::allocator::ArenaHandle<'a>,
+                rkyv::rancor::Error,
+            >,
+        >,
+    T::Archived: for<'a> rkyv::bytecheck::CheckBytes<rkyv::api::high::HighValidator<'a, rkyv::rancor::Error>>
+        + rkyv::Deserialize<T, rkyv::api::high::HighDeserializer<rkyv::rancor::Error>>,
+{
+    const NAME: &'static str = "rkyv";
+    const ALIGN: usize = 16;
+
+    fn encode<W: Write>(value: &T, writer: &mut W) -> Result<(), CodecError> {
+        let bytes = rkyv::to_bytes::<rkyv::rancor::Error>(value).map_err(CodecError::new)?;
+        writer.write_all(&bytes).map_err(CodecError::new)
+    }
+
+    fn decode(data: &[u8]) -> Result<T, CodecError> {
+        // Journal records aren't aligned, the snapshot is
+        match data.as_ptr() as usize % <Self as Codec<T>>::ALIGN {
+            0 => rkyv::from_bytes::<T, rkyv::rancor::Error>(data),
+            _ => {
+                let mut aligned = rkyv::util::AlignedVec::<16>::with_capacity(data.len());
+                aligned.extend_from_slice(data);
+                rkyv::from_bytes::<T, rkyv::rancor::Error>(&aligned)
+            }
+        }
+        .map_err(CodecError::new)
+    }
+}
@@ -0,0 +1,192 @@
+use bincode::{Decode, Encode};
+use std::{
+    any::Any,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    ops::Range,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Ids from [`ExecutionContext::next_id`] are `sequence << ID_SEQUENCE_SHIFT | n`
+const ID_SEQUENCE_SHIFT: u32 = 20;
+
+/// Caller supplied metadata that is journaled together with a command
+///
+/// Used for auditing, none of the fields are interpreted by the engine
+#[derive(Encode, Decode, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommandMeta {
+    /// The user/actor that issued the command
+    pub actor: Option<String>,
+    /// Correlation/request id, typically taken from the incoming request
+    pub correlation_id: Option<String>,
+    /// Free-form headers
+    pub headers: Vec<(String, String)>,
+}
+
+impl CommandMeta {
+    pub fn actor<T: Into<String>>(mut self, actor: T) -> Self {
+        self.actor = Some(actor.into());
+        self
+    }
+
+    pub fn correlation_id<T: Into<String>>(mut self, correlation_id: T) -> Self {
+        self.correlation_id = Some(correlation_id.into());
+        self
+    }
+
+    pub fn header<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.headers.push((key.into(), value.into()));
+        self
+    }
+}
+
+/// The envelope that is journaled in front of every command
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct Envelope {
+    /// Sequence number of the command, starts at 1 and survives snapshots
+    pub sequence: u64,
+    /// Wall-clock commit time in milliseconds since the unix epoch
+    pub timestamp: u64,
+    /// Seed for [`ExecutionContext::rng`]
+    pub seed: u64,
+    /// Set when executed with [`crate::Engine::execute_idempotent`]
+    pub idempotency_key: Option<String>,
+    pub meta: CommandMeta,
+}
+
+impl Envelope {
+    pub(crate) fn new(sequence: u64, idempotency_key: Option<String>, meta: CommandMeta) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System clock is before the unix epoch")
+            .as_millis() as u64;
+
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(sequence);
+        hasher.write_u64(timestamp);
+
+        Envelope {
+            sequence,
+            timestamp,
+            seed: hasher.finish(),
+            idempotency_key,
+            meta,
+        }
+    }
+}
+
+/// Passed to [`crate::Command::execute`]
+///
+/// Built from the journaled [`Envelope`], both when executing live and when replaying,
+/// so commands should take time, randomness and ids from here instead of calling
+/// `SystemTime::now()` or `rand`, otherwise the replayed model differs from the live one
+pub struct ExecutionContext<'a> {
+    envelope: &'a Envelope,
+    rng: ContextRng,
+    next_id: u64,
+    touched: Vec<Box<dyn Any>>,
+}
+
+impl<'a> ExecutionContext<'a> {
+    pub(crate) fn new(envelope: &'a Envelope) -> Self {
+        ExecutionContext {
+            envelope,
+            rng: ContextRng::new(envelope.seed),
+            next_id: 0,
+            touched: Vec::new(),
+        }
+    }
+
+    /// The time the command was committed, the same value is returned during replay
+    pub fn now(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.envelope.timestamp)
+    }
+
+    /// Random number generator seeded from the journal record
+    pub fn rng(&mut self) -> &mut ContextRng {
+        &mut self.rng
+    }
+
+    /// Returns an id that is unique across the whole journal
+    ///
+    /// Ids are derived from the sequence number of the command,
+    /// so a single command can generate up to 2^20 ids
+    pub fn next_id(&mut self) -> u64 {
+        assert!(
+            self.next_id < 1 << ID_SEQUENCE_SHIFT,
+            "Command({}) generated too many ids",
+            self.envelope.sequence
+        );
+
+        let id = self.envelope.sequence << ID_SEQUENCE_SHIFT | self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    /// Reports that the command inserted, changed or removed the entry with `key` in an indexed map,
+    /// so only that entry is updated in the indexes, see [`crate::Index`]
+    ///
+    /// A command that reports keys must report every entry it changed in indexed maps.
+    /// Indexes are rebuilt from the whole map after commands that don't report any keys
+    pub fn touched<K: Any>(&mut self, key: K) {
+        self.touched.push(Box::new(key));
+    }
+
+    /// The keys reported with [`ExecutionContext::touched`]
+    pub(crate) fn touched_keys(&self) -> &[Box<dyn Any>] {
+        &self.touched
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.envelope.sequence
+    }
+
+    pub fn meta(&self) -> &CommandMeta {
+        &self.envelope.meta
+    }
+
+    pub fn envelope(&self) -> &Envelope {
+        self.envelope
+    }
+}
+
+/// SplitMix64, small and fast, NOT cryptographically secure
+pub struct ContextRng {
+    state: u64,
+}
+
+impl ContextRng {
+    fn new(seed: u64) -> Self {
+        ContextRng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    /// Uniform value in `[0, 1)`
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+    }
+
+    /// Value in `range`, panics if the range is empty
+    pub fn gen_range(&mut self, range: Range<u64>) -> u64 {
+        assert!(range.start < range.end, "Empty range");
+        range.start + self.next_u64() % (range.end - range.start)
+    }
+
+    pub fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
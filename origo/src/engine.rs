@@ -1,122 +1,468 @@
-use bincode::{Decode, Encode};
-use parking_lot::{Mutex, RwLock};
+use parking_lot::{Mutex, MutexGuard, RwLock};
 use std::{
-    any::TypeId,
+    any::{Any, TypeId},
     collections::HashMap,
-    sync::{atomic::AtomicU64, Arc},
+    hash::Hash,
+    io,
+    marker::PhantomData,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc,
+    },
+    time::Duration,
 };
 
-use crate::storage::Storage;
+use crate::{
+    cell::{Locked, ModelCell, Versioned},
+    codec::{Bincode, Codec, CodecError},
+    context::{CommandMeta, Envelope, ExecutionContext},
+    index::{AnyIndex, Index, IndexEntries, IndexSource},
+    lazy::{Catchup, CatchupObserver, Consistency, Read},
+    restore::RestoreObserver,
+    state::{EngineState, Idempotent, Receipt},
+    storage::{BackupManifest, Storage, TOMBSTONE_COMMAND},
+};
+#[cfg(feature = "rkyv")]
+use crate::{
+    codec::Rkyv,
+    storage::{model_id, MappedSnapshot},
+};
+#[cfg(feature = "rkyv")]
+use rkyv::{
+    api::high::{HighDeserializer, HighValidator},
+    bytecheck::CheckBytes,
+    rancor, Archive, Deserialize,
+};
 
+/// Decodes a command of the journal, called on the worker threads of the restore
 pub type CommandRestoreFn<TModel> =
-    Box<dyn Fn(&[u8], &mut TModel, bincode::config::Configuration) -> bool>;
+    Box<dyn Fn(&[u8]) -> Result<DecodedCommand<TModel>, CodecError> + Send + Sync>;
+
+/// A command decoded by a [`CommandRestoreFn`], executed against the model in journal order
+pub type DecodedCommand<TModel> = Box<dyn FnOnce(&Envelope, &mut TModel) + Send>;
+
+/// Decodes an encoded command and returns its [`Command::compaction_key`]
+pub type CompactionKeyFn = Box<dyn Fn(&[u8]) -> Result<Option<String>, CodecError> + Send + Sync>;
+
+/// The command has to be serializable by the [`Codec`] of the engine
+pub trait Command<TModel> {
+    fn execute(&self, model: &mut TModel, ctx: &mut ExecutionContext);
 
-pub trait Command<TModel>: Encode + Decode {
-    fn execute(&self, model: &mut TModel);
+    /// Key of the state the command overwrites, `None` by default
+    ///
+    /// Journal compaction keeps only the latest command of each key, see
+    /// [`crate::storage::DiskStorage::compact_journal`]. Only return a key if the command is a pure overwrite:
+    /// it replaces everything earlier commands with the same key did, its result doesn't depend on that state
+    /// (no increments, appends or checks of the previous value) and it doesn't change state of other keys.
+    /// For example an `InsertOrder` keyed by `order_id` that overwrites the whole order. Keys are shared by all commands
+    fn compaction_key(&self) -> Option<String> {
+        None
+    }
 }
 
-pub struct Engine<TModel, TStorage> {
-    model: Arc<RwLock<TModel>>,
+type Indexes<TModel> = HashMap<String, Box<dyn AnyIndex<TModel>>>;
+
+/// The engine, `TCell` decides how the model is shared between commands and queries,
+/// see [`Locked`] (default) and [`Versioned`]. `TCodec` serializes the model and commands, see [`Codec`]
+pub struct Engine<TModel, TStorage, TCell = Locked<TModel>, TCodec = Bincode> {
+    model: Arc<TCell>,
+    indexes: Arc<RwLock<Indexes<TModel>>>,
     storage: Arc<Mutex<TStorage>>,
+    state: Arc<Mutex<EngineState>>,
     typeid_names: Arc<HashMap<TypeId, String>>,
     snapshot_command_count: Arc<AtomicU64>,
+    verify_determinism: bool,
+    catchup: Arc<Catchup>,
+    codec: PhantomData<fn() -> TCodec>,
 }
 
-impl<TModel: Encode + Decode + Send + Sync + 'static, TStorage: Storage + Send + 'static>
-    Engine<TModel, TStorage>
+/// Engine where queries never block, see [`Versioned`]
+pub type VersionedEngine<TModel, TStorage, TCodec = Bincode> =
+    Engine<TModel, TStorage, Versioned<TModel>, TCodec>;
+
+impl<TModel, TStorage, TCell, TCodec> Engine<TModel, TStorage, TCell, TCodec>
+where
+    TModel: Send + Sync + 'static,
+    TStorage: Storage + Send + 'static,
+    TCell: ModelCell<TModel> + 'static,
+    TCodec: Codec<TModel>,
 {
     /// How many commands are allowed before (automatically) taking a snapshot
     pub fn snapshot_command_count(&self, count: u64) {
-        self.snapshot_command_count
-            .store(count, std::sync::atomic::Ordering::Relaxed);
+        self.snapshot_command_count.store(count, Ordering::Relaxed);
     }
 
     /// Execute the given command against the current model
     ///
     /// Commands execute in exclusive mode,
     /// meaning that no other writes OR queries will happen until the command finishes
-    /// (The model is ReadWriteLocked)
+    /// (The model is ReadWriteLocked, unless it's a [`VersionedEngine`])
     ///
     /// Before executing the command it's written to the journal
-    pub fn execute<T>(&self, command: T)
+    pub fn execute<T>(&self, command: T) -> Receipt
+    where
+        T: Command<TModel> + 'static,
+        TCodec: Codec<T>,
+    {
+        self.execute_with(CommandMeta::default(), command)
+    }
+
+    /// Same as [`Engine::execute`] but journals the given [`CommandMeta`] with the command
+    ///
+    /// The commit timestamp, sequence number and rng seed are taken here
+    /// and passed to the command through the [`ExecutionContext`]
+    pub fn execute_with<T>(&self, meta: CommandMeta, command: T) -> Receipt
+    where
+        T: Command<TModel> + 'static,
+        TCodec: Codec<T>,
+    {
+        // We lock storage before the model so we can allow queries during the possible storage IO
+        // This is the reason for storing `storage` and `model` in separate locks
+        let mut storage = self.lock_storage();
+        self.execute_locked(&mut storage, None, meta, command)
+    }
+
+    /// Execute the command unless a command with the same `key` was already executed
+    ///
+    /// The key is journaled with the command, so duplicates are detected across restarts,
+    /// as long as the key is still in the table, see [`EngineBuilder::idempotency_limits`].
+    /// A duplicate returns the [`Receipt`] of the original execution, not a result of the command
+    pub fn execute_idempotent<K, T>(&self, key: K, command: T) -> Idempotent
     where
+        K: Into<String>,
         T: Command<TModel> + 'static,
+        TCodec: Codec<T>,
+    {
+        self.execute_idempotent_with(key, CommandMeta::default(), command)
+    }
+
+    /// Same as [`Engine::execute_idempotent`] but journals the given [`CommandMeta`] with the command
+    pub fn execute_idempotent_with<K, T>(&self, key: K, meta: CommandMeta, command: T) -> Idempotent
+    where
+        K: Into<String>,
+        T: Command<TModel> + 'static,
+        TCodec: Codec<T>,
+    {
+        let key = key.into();
+        let mut storage = self.lock_storage();
+
+        if let Some(receipt) = self.state.lock().idempotency().get(&key) {
+            return Idempotent::Duplicate(receipt);
+        }
+
+        Idempotent::Executed(self.execute_locked(&mut storage, Some(key), meta, command))
+    }
+
+    /// Locks the storage, the replay of a lazy restore holds the lock until it caught up
+    ///
+    /// # Panics
+    ///
+    /// Panics if the replay failed
+    fn lock_storage(&self) -> MutexGuard<'_, TStorage> {
+        let storage = self.storage.lock();
+        self.catchup.check();
+        storage
+    }
+
+    /// Journals and executes the command, the caller must hold the storage lock
+    fn execute_locked<T>(
+        &self,
+        storage: &mut TStorage,
+        idempotency_key: Option<String>,
+        meta: CommandMeta,
+        command: T,
+    ) -> Receipt
+    where
+        T: Command<TModel> + 'static,
+        TCodec: Codec<T>,
     {
         let name: &str = self
             .typeid_names
             .get(&TypeId::of::<T>())
             .expect("Couldn't find command name");
 
-        // We lock storage before the model so we can allow queries during the possible storage IO
-        // This is the reason for storing `storage` and `model` in separate locks
-        let mut storage = self.storage.lock();
+        let mut state = self.state.lock();
+
+        let envelope = Envelope::new(state.sequence() + 1, idempotency_key, meta);
+        storage.prepare::<TModel, TCodec, T>(&envelope, name, &command);
 
-        storage.prepare(name, &command);
+        if self.verify_determinism {
+            self.model.read(|model| {
+                verify_determinism::<TModel, TCodec, T>(model, &envelope, name, &command)
+            });
+        }
 
         // Here we lock the model so no queries can happen before the new state is applied
         // and committed.
-        let mut model = self.model.write();
-        command.execute(&mut model);
-        let command_count = storage.commit();
+        // The indexes are locked for the whole write so `query_index` sees them in sync with the model
+        let mut indexes = self.indexes.write();
+        let command_count = self.model.write(|model| {
+            let mut ctx = ExecutionContext::new(&envelope);
+            command.execute(model, &mut ctx);
+            let touched = ctx.touched_keys();
+            for index in indexes.values_mut() {
+                if touched.is_empty() {
+                    index.update(model);
+                } else {
+                    index.update_keys(model, touched);
+                }
+            }
+            storage.commit()
+        });
+        drop(indexes);
+        state.apply(&envelope);
+        drop(state);
 
         // Since we still hold the lock on storage (and no writes can happen until we release it)
         // we check if we should take a snapshot
-        if command_count
-            == self
-                .snapshot_command_count
-                .load(std::sync::atomic::Ordering::Relaxed)
-        {
+        if command_count == self.snapshot_command_count.load(Ordering::Relaxed) {
             let clone = self.clone();
             std::thread::spawn(move || {
                 let mut storage2 = clone.storage.lock();
-                let model2 = clone.model.read();
-                storage2.snapshot(&*model2);
+                let state2 = clone.state.lock();
+                clone
+                    .model
+                    .read(|model2| storage2.snapshot::<TModel, TCodec>(&state2, model2));
             });
         }
+
+        Receipt::from(&envelope)
+    }
+
+    /// Writes a consistent backup to the directory `path`: the snapshot, sealed journal segments and the journal up to the last commit,
+    /// and a manifest with their checksums. Restore it with [`crate::storage::restore_from_backup`]
+    ///
+    /// Writes are only stopped while the storage collects the files, they're copied while commands execute.
+    /// If a snapshot resets the journal during the copy, the files are copied again with writes stopped,
+    /// right after a snapshot the journal is short
+    pub fn backup_to<P: AsRef<Path>>(&self, path: P) -> io::Result<BackupManifest> {
+        let mut stop_writes = false;
+        loop {
+            let mut storage = self.lock_storage();
+            let sequence = self.state.lock().sequence();
+            let files = storage.backup_files()?;
+            let _stopped = stop_writes.then_some(storage);
+
+            match files.write_to(path.as_ref(), sequence)? {
+                Some(manifest) => {
+                    log::info!(
+                        "Backup {:?} written up to sequence {}",
+                        path.as_ref(),
+                        sequence
+                    );
+                    return Ok(manifest);
+                }
+                None => {
+                    log::debug!(
+                        "Journal was reset during the backup, copying it again with writes stopped"
+                    );
+                    stop_writes = true;
+                }
+            }
+        }
     }
 
     /// Execute the given query against the current model
     ///
     /// Multiple queries can execute against the model at the same time
-    /// but no writes will happen during queries (The model is ReadWriteLocked)
+    /// but no writes will happen during queries (The model is ReadWriteLocked, unless it's a [`VersionedEngine`])
+    ///
+    /// Waits until the journal is replayed if the engine was built with [`EngineBuilder::build_lazy`]
     #[inline(always)]
     pub fn query<R, F: FnOnce(&TModel) -> R>(&self, query: F) -> R {
-        let model = self.model.read();
-        query(&model)
+        self.catchup.wait();
+        self.model.read(query)
+    }
+
+    /// Same as [`Engine::query`], with [`Consistency::AllowStale`] the query doesn't wait for the replay
+    /// of [`EngineBuilder::build_lazy`] and runs against the snapshot
+    pub fn query_with<R, F: FnOnce(&TModel) -> R>(
+        &self,
+        consistency: Consistency,
+        query: F,
+    ) -> Read<R> {
+        let stale = match consistency {
+            Consistency::CaughtUp => None,
+            Consistency::AllowStale => self.catchup.stale(),
+        };
+
+        match stale {
+            Some((applied, target)) => Read::Stale {
+                value: self.model.read(query),
+                applied,
+                target,
+            },
+            None => Read::CaughtUp(self.query(query)),
+        }
+    }
+
+    /// Adds a secondary index, the index is built from the current model
+    /// and updated after every command
+    ///
+    /// Indexes aren't persisted, add them again after restoring the engine
+    pub fn add_index<C, K>(&self, name: &str, index: Index<TModel, C, K>)
+    where
+        C: IndexSource + 'static,
+        C::Key: Hash + Eq + Clone + Send + Sync + 'static,
+        K: Hash + Eq + Clone + Send + Sync + 'static,
+    {
+        let mut indexes = self.indexes.write();
+        let mut index: Box<dyn AnyIndex<TModel>> = Box::new(index);
+        self.model.read(|model| index.update(model));
+
+        if indexes.insert(name.to_string(), index).is_some() {
+            panic!("Index with name {} already added", name);
+        }
+    }
+
+    /// Execute the given query against the current model and the entries of the index `name`
+    ///
+    /// The indexes are locked while a command executes and commits, so they are in sync with the model.
+    /// Unlike [`Engine::query`] this also blocks on a [`VersionedEngine`] until the running command,
+    /// including its journal write, is done
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no index `name` with key `K` over a map with key `PK`
+    pub fn query_index<K, PK, R, F>(&self, name: &str, query: F) -> R
+    where
+        K: 'static,
+        PK: 'static,
+        F: FnOnce(&TModel, &IndexEntries<K, PK>) -> R,
+    {
+        self.catchup.wait();
+        let indexes = self.indexes.read();
+        let entries = indexes
+            .get(name)
+            .unwrap_or_else(|| panic!("No index named {}", name))
+            .entries()
+            .downcast_ref::<IndexEntries<K, PK>>()
+            .unwrap_or_else(|| panic!("Index {} has other key types", name));
+
+        self.model.read(|model| query(model, entries))
     }
 }
 
-impl<TModel, TStorage> Clone for Engine<TModel, TStorage> {
+impl<TModel, TStorage, TCodec> VersionedEngine<TModel, TStorage, TCodec> {
+    /// The current version of the model
+    ///
+    /// The version is immutable, hold on to it to run several queries against the same state.
+    /// Commands executed after this call publish new versions and don't affect it
+    pub fn version(&self) -> Arc<TModel> {
+        self.catchup.wait();
+        self.model.load()
+    }
+}
+
+impl<TModel, TStorage, TCell, TCodec> Clone for Engine<TModel, TStorage, TCell, TCodec> {
     fn clone(&self) -> Self {
         Self {
             model: self.model.clone(),
+            indexes: self.indexes.clone(),
             storage: self.storage.clone(),
+            state: self.state.clone(),
             typeid_names: self.typeid_names.clone(),
             snapshot_command_count: self.snapshot_command_count.clone(),
+            verify_determinism: self.verify_determinism,
+            catchup: self.catchup.clone(),
+            codec: PhantomData,
         }
     }
 }
 
+/// Executes `command` against two copies of `model` and panics if the results differ
+fn verify_determinism<TModel, C: Codec<TModel>, T: Command<TModel>>(
+    model: &TModel,
+    envelope: &Envelope,
+    name: &str,
+    command: &T,
+) {
+    let encode = |model: &TModel| {
+        let mut bytes = Vec::new();
+        C::encode(model, &mut bytes).expect("Failed to encode model");
+        bytes
+    };
+    let before = encode(model);
+
+    let [first, second] = [(); 2].map(|_| {
+        let mut copy = C::decode(&before).expect("Failed to decode model");
+        command.execute(&mut copy, &mut ExecutionContext::new(envelope));
+        encode(&copy)
+    });
+
+    assert!(
+        first == second,
+        "Command {}({}) is not deterministic, use the ExecutionContext for time, randomness and ids",
+        name,
+        envelope.sequence
+    );
+}
+
 /// Used to build and restore an engine for `TModel` with `TStorage`
 ///
-/// **DON'T USE THIS, use the [`crate::origo_engine`] macro**
-pub struct EngineBuilder<TModel, TStorage> {
+/// The [`crate::origo_engine`] macro is shorter, use the builder for options like [`EngineBuilder::restore_observer`]
+pub struct EngineBuilder<TModel, TStorage, TCodec = Bincode> {
     model: TModel,
     storage: TStorage,
     restore_fns: HashMap<String, CommandRestoreFn<TModel>>,
+    compaction_key_fns: HashMap<String, CompactionKeyFn>,
+    restore_observer: Option<Arc<dyn RestoreObserver>>,
+    verify_determinism: bool,
+    state: EngineState,
+    model_id: Option<String>,
     typeid_names: HashMap<TypeId, String>,
+    codec: PhantomData<fn() -> TCodec>,
 }
 
-impl<TModel: Default + Decode, TStorage: Storage> EngineBuilder<TModel, TStorage> {
+impl<TModel: Default, TStorage: Storage> EngineBuilder<TModel, TStorage> {
     pub fn new(model: TModel, storage: TStorage) -> EngineBuilder<TModel, TStorage> {
         EngineBuilder {
             model,
             storage,
             restore_fns: HashMap::new(),
+            compaction_key_fns: HashMap::new(),
+            restore_observer: None,
+            verify_determinism: cfg!(debug_assertions),
+            state: EngineState::default(),
+            model_id: None,
             typeid_names: HashMap::new(),
+            codec: PhantomData,
         }
     }
 
+    /// Serialize the model and commands with `C` instead of bincode
+    ///
+    /// # Panics
+    ///
+    /// Panics if commands were already registered
+    pub fn codec<C: Codec<TModel>>(self) -> EngineBuilder<TModel, TStorage, C> {
+        assert!(
+            self.restore_fns.is_empty(),
+            "Select the codec before registering commands"
+        );
+
+        EngineBuilder {
+            model: self.model,
+            storage: self.storage,
+            restore_fns: self.restore_fns,
+            compaction_key_fns: self.compaction_key_fns,
+            restore_observer: self.restore_observer,
+            verify_determinism: self.verify_determinism,
+            state: self.state,
+            model_id: self.model_id,
+            typeid_names: self.typeid_names,
+            codec: PhantomData,
+        }
+    }
+}
+
+impl<TModel, TStorage, TCodec> EngineBuilder<TModel, TStorage, TCodec>
+where
+    TModel: Default,
+    TStorage: Storage,
+    TCodec: Codec<TModel>,
+{
     /// Register command that the engine should be able to execute AND store + restore
     ///
     /// It works by taking the `TypeId` of `T` while also receiving the name of the command,
@@ -124,25 +470,36 @@ impl<TModel: Default + Decode, TStorage: Storage> EngineBuilder<TModel, TStorage
     ///
     /// The `TypeId` and name is used by the engine to map between the Command -> TypeId -> Name when:
     /// - Executing a command, we get the `TypeId` of the executing command and with that we get the name,
-    /// the name is then stored with the serialized data.
+    ///   the name is then stored with the serialized data.
     /// - Restoring the model, we have the names stored with the serialized data and for example:
-    /// The [`crate::storage::DiskStorage`] uses that name to fetch the [`CommandRestoreFn`],
-    /// then it knows how to deserialize that command from the journal
-    pub fn register_command<T: Command<TModel> + 'static>(
+    ///   The [`crate::storage::DiskStorage`] uses that name to fetch the [`CommandRestoreFn`],
+    ///   then it knows how to deserialize that command from the journal
+    ///
+    /// Commands are decoded on worker threads during the restore, so they have to be `Send`
+    pub fn register_command<T: Command<TModel> + Send + 'static>(
         mut self,
         persistent_identifier: &str,
-    ) -> Self {
+    ) -> Self
+    where
+        TCodec: Codec<T>,
+    {
         log::debug!("Registering command: {}", persistent_identifier);
+        assert!(
+            persistent_identifier != TOMBSTONE_COMMAND,
+            "Command name {} is reserved for journal compaction",
+            persistent_identifier
+        );
 
-        let restore_fn: CommandRestoreFn<TModel> = Box::new(|data, model, config| {
-            match bincode::decode_from_slice::<T, _>(data, config) {
-                Ok((command, _)) => {
-                    command.execute(model);
-                    true
-                }
-                Err(_) => false, // TODO: report the error to caller in some way
-            }
+        let restore_fn: CommandRestoreFn<TModel> = Box::new(|data| {
+            let command = <TCodec as Codec<T>>::decode(data)?;
+            Ok(Box::new(move |envelope, model| {
+                command.execute(model, &mut ExecutionContext::new(envelope))
+            }))
         });
+        let compaction_key_fn: CompactionKeyFn =
+            Box::new(|data| Ok(<TCodec as Codec<T>>::decode(data)?.compaction_key()));
+        self.compaction_key_fns
+            .insert(persistent_identifier.to_string(), compaction_key_fn);
 
         match self
             .restore_fns
@@ -160,14 +517,267 @@ impl<TModel: Default + Decode, TStorage: Storage> EngineBuilder<TModel, TStorage
         self
     }
 
-    pub fn build(mut self) -> Engine<TModel, TStorage> {
-        self.model = self.storage.restore(&self.restore_fns);
+    /// Reports the phases and progress of the restore in [`EngineBuilder::build`] to `observer`,
+    /// pass an `Arc` to keep a handle to it
+    pub fn restore_observer<O: RestoreObserver + 'static>(mut self, observer: O) -> Self {
+        self.restore_observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Execute every command twice against copies of the model and panic if the results differ,
+    /// on by default in debug builds
+    ///
+    /// Catches commands that read the clock or randomness from outside the [`ExecutionContext`],
+    /// those produce a different model on replay. Every command encodes and decodes the whole model,
+    /// so keep it for tests and debug builds, not production.
+    ///
+    /// The copies are compared by their encoded bytes so the model needs a deterministic encoding,
+    /// for example no `HashMap` with the (randomly seeded) std `RandomState`. Turn it off for other models
+    pub fn verify_determinism(mut self, verify: bool) -> Self {
+        self.verify_determinism = verify;
+        self
+    }
+
+    /// Bounds the idempotency key table used by [`Engine::execute_idempotent`]
+    ///
+    /// Keys are forgotten when there are more than `max_keys` of them or they are older than `max_age`,
+    /// the default is 100 000 keys for a day. The limits aren't journaled, they apply to the restore as well
+    pub fn idempotency_limits(mut self, max_keys: usize, max_age: Duration) -> Self {
+        self.state.idempotency_mut().set_limits(max_keys, max_age);
+        self
+    }
+
+    /// Identifies the model in the headers of the journal and snapshot, the type name of the model by default
+    ///
+    /// Restoring files written for another model id panics, set an id that stays the same when the model type
+    /// is renamed or moved. Files written with the default id are opened by passing their type name
+    pub fn model_id<T: Into<String>>(mut self, id: T) -> Self {
+        self.model_id = Some(id.into());
+        self
+    }
+
+    pub fn build(self) -> Engine<TModel, TStorage, Locked<TModel>, TCodec>
+    where
+        TModel: Send + Sync,
+    {
+        self.build_with()
+    }
+
+    /// Build a [`VersionedEngine`], where queries never block
+    pub fn build_versioned(self) -> VersionedEngine<TModel, TStorage, TCodec>
+    where
+        TModel: Clone + Send + Sync,
+    {
+        self.build_with()
+    }
+
+    /// Returns the engine right after the snapshot is loaded, the journal is replayed on a background thread
+    ///
+    /// Commands, [`Engine::query`] and [`Engine::query_index`] wait until the replay caught up,
+    /// [`Engine::query_with`] with [`Consistency::AllowStale`] runs against the snapshot instead.
+    /// The snapshot is cloned for those queries, so the model is held twice until the replay caught up.
+    /// Indexes added during the replay are built again from the restored model
+    ///
+    /// # Panics
+    ///
+    /// Panics if the snapshot can't be restored. If the replay fails, the engine panics on its next use
+    pub fn build_lazy(self) -> Engine<TModel, TStorage, Locked<TModel>, TCodec>
+    where
+        TModel: Clone + Send + Sync + 'static,
+        TStorage: Send + 'static,
+    {
+        self.build_replaying(None)
+    }
+
+    /// Starts the replay thread, returns once the snapshot is loaded or right away
+    /// if `archive` serves queries until then, see [`Catchup::snapshot_mapped`]
+    fn build_replaying(
+        mut self,
+        archive: Option<(u64, Arc<dyn Any + Send + Sync>)>,
+    ) -> Engine<TModel, TStorage, Locked<TModel>, TCodec>
+    where
+        TModel: Clone + Send + Sync + 'static,
+        TStorage: Send + 'static,
+    {
+        let catchup = Arc::new(Catchup::replaying());
+        let mapped = archive.is_some();
+        if let Some((sequence, archive)) = archive {
+            catchup.snapshot_mapped(sequence, archive);
+        }
+        if let Some(id) = &self.model_id {
+            self.storage.model_id(id);
+        }
+        self.storage.compaction_keys(self.compaction_key_fns);
+        self.storage.restore_observer(Arc::new(CatchupObserver {
+            catchup: catchup.clone(),
+            observer: self.restore_observer,
+        }));
+        let engine: Engine<_, _, Locked<TModel>, TCodec> = Engine::new(
+            self.model,
+            self.storage,
+            self.state.clone(),
+            self.typeid_names,
+            self.verify_determinism,
+            catchup,
+        );
+
+        let (locked, storage) = mpsc::channel();
+        let (loaded, snapshot) = mpsc::channel();
+        let replay = engine.clone();
+        let restore_fns = self.restore_fns;
+        let mut state = self.state;
+        std::thread::Builder::new()
+            .name("origo-replay".to_string())
+            .spawn(move || {
+                // Commands wait for the storage until the replay caught up
+                let mut storage = replay.storage.lock();
+                let _ = locked.send(());
+                let _fail_on_panic = replay.catchup.fail_on_panic();
+
+                let model = storage.restore::<TModel, TCodec>(
+                    &restore_fns,
+                    &mut state,
+                    &mut |state, model| {
+                        let model = model.clone();
+                        replay.model.write(|current| *current = model);
+                        replay.catchup.snapshot_loaded(state.sequence());
+                        let _ = loaded.send(());
+                    },
+                );
+
+                let mut indexes = replay.indexes.write();
+                replay.model.write(|current| {
+                    *current = model;
+                    for index in indexes.values_mut() {
+                        index.update(current);
+                    }
+                });
+                drop(indexes);
+                *replay.state.lock() = state;
+                replay.catchup.finish(false);
+            })
+            .expect("Failed to start replay");
+
+        // Commands executed once the engine is returned must queue behind the replay
+        storage
+            .recv()
+            .expect("Storage can't be restored, the replay thread stopped");
+        if !mapped {
+            snapshot
+                .recv()
+                .expect("Snapshot can't be restored, the replay thread stopped");
+        }
+        engine
+    }
 
+    fn build_with<TCell: ModelCell<TModel>>(mut self) -> Engine<TModel, TStorage, TCell, TCodec> {
+        let mut state = self.state;
+        if let Some(id) = &self.model_id {
+            self.storage.model_id(id);
+        }
+        self.storage.compaction_keys(self.compaction_key_fns);
+        if let Some(observer) = self.restore_observer {
+            self.storage.restore_observer(observer);
+        }
+        self.model =
+            self.storage
+                .restore::<TModel, TCodec>(&self.restore_fns, &mut state, &mut |_, _| {});
+
+        Engine::new(
+            self.model,
+            self.storage,
+            state,
+            self.typeid_names,
+            self.verify_determinism,
+            Arc::new(Catchup::caught_up()),
+        )
+    }
+}
+
+#[cfg(feature = "rkyv")]
+impl<TModel, TStorage> EngineBuilder<TModel, TStorage, Rkyv>
+where
+    TModel: Default + Clone + Send + Sync + Archive + 'static,
+    TModel::Archived: for<'a> CheckBytes<HighValidator<'a, rancor::Error>>
+        + Deserialize<TModel, HighDeserializer<rancor::Error>>,
+    TStorage: Storage + Send + 'static,
+    Rkyv: Codec<TModel>,
+{
+    /// Like [`EngineBuilder::build_lazy`], but returns once the snapshot is memory-mapped and validated,
+    /// before it's deserialized. Until then [`Engine::query_archived`] runs against the archived snapshot,
+    /// [`Engine::query_with`] with [`Consistency::AllowStale`] against the default model
+    ///
+    /// Without a snapshot file, or with a compressed or encrypted snapshot, it's the same as `build_lazy`
+    pub fn build_mapped(self) -> Engine<TModel, TStorage, Locked<TModel>, Rkyv> {
+        let model_id = model_id::<TModel>(self.model_id.as_deref());
+        let archive = self.storage.snapshot_file().and_then(|path| {
+            match MappedSnapshot::<TModel>::open_with_model_id(&path, model_id) {
+                Ok(snapshot) => Some((
+                    snapshot.state().sequence(),
+                    Arc::new(snapshot) as Arc<dyn Any + Send + Sync>,
+                )),
+                Err(e) => {
+                    log::debug!("Snapshot {:?} isn't mapped, {}", path, e);
+                    None
+                }
+            }
+        });
+        self.build_replaying(archive)
+    }
+}
+
+#[cfg(feature = "rkyv")]
+impl<TModel, TStorage, TCell> Engine<TModel, TStorage, TCell, Rkyv>
+where
+    TModel: Send + Sync + Archive + 'static,
+    TModel::Archived: for<'a> CheckBytes<HighValidator<'a, rancor::Error>>
+        + Deserialize<TModel, HighDeserializer<rancor::Error>>,
+    TStorage: Storage + Send + 'static,
+    TCell: ModelCell<TModel> + 'static,
+    Rkyv: Codec<TModel>,
+{
+    /// Same as [`Engine::query_with`] with [`Consistency::AllowStale`], but while the snapshot of
+    /// [`EngineBuilder::build_mapped`] is deserialized `archived` runs against the memory-mapped snapshot
+    /// instead of waiting for it
+    pub fn query_archived<R>(
+        &self,
+        query: impl FnOnce(&TModel) -> R,
+        archived: impl FnOnce(&TModel::Archived) -> R,
+    ) -> Read<R> {
+        let archive = self.catchup.archive();
+        let snapshot = archive
+            .as_ref()
+            .and_then(|archive| archive.downcast_ref::<MappedSnapshot<TModel>>());
+        match (snapshot, self.catchup.stale()) {
+            (Some(snapshot), Some((applied, target))) => Read::Stale {
+                value: archived(snapshot.archived()),
+                applied,
+                target,
+            },
+            _ => self.query_with(Consistency::AllowStale, query),
+        }
+    }
+}
+
+impl<TModel, TStorage, TCell: ModelCell<TModel>, TCodec> Engine<TModel, TStorage, TCell, TCodec> {
+    fn new(
+        model: TModel,
+        storage: TStorage,
+        state: EngineState,
+        typeid_names: HashMap<TypeId, String>,
+        verify_determinism: bool,
+        catchup: Arc<Catchup>,
+    ) -> Self {
         Engine {
-            model: Arc::new(RwLock::new(self.model)),
-            storage: Arc::new(Mutex::new(self.storage)),
-            typeid_names: Arc::new(self.typeid_names),
+            model: Arc::new(TCell::new(model)),
+            indexes: Arc::new(RwLock::new(HashMap::new())),
+            storage: Arc::new(Mutex::new(storage)),
+            state: Arc::new(Mutex::new(state)),
+            typeid_names: Arc::new(typeid_names),
             snapshot_command_count: Arc::new(AtomicU64::new(u64::MAX)),
+            verify_determinism,
+            catchup,
+            codec: PhantomData,
         }
     }
 }
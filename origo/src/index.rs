@@ -0,0 +1,184 @@
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap, HashSet},
+    hash::{BuildHasher, Hash},
+};
+
+/// A map in the model that can be indexed
+pub trait IndexSource {
+    type Key;
+    type Value;
+
+    fn for_each<F: FnMut(&Self::Key, &Self::Value)>(&self, f: F);
+
+    fn get(&self, key: &Self::Key) -> Option<&Self::Value>;
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> IndexSource for HashMap<K, V, S> {
+    type Key = K;
+    type Value = V;
+
+    fn for_each<F: FnMut(&K, &V)>(&self, mut f: F) {
+        self.iter().for_each(|(k, v)| f(k, v));
+    }
+
+    fn get(&self, key: &K) -> Option<&V> {
+        HashMap::get(self, key)
+    }
+}
+
+impl<K: Ord, V> IndexSource for BTreeMap<K, V> {
+    type Key = K;
+    type Value = V;
+
+    fn for_each<F: FnMut(&K, &V)>(&self, mut f: F) {
+        self.iter().for_each(|(k, v)| f(k, v));
+    }
+
+    fn get(&self, key: &K) -> Option<&V> {
+        BTreeMap::get(self, key)
+    }
+}
+
+/// Secondary index over a map in the model, for example orders by `transport_id`
+///
+/// Indexes are not persisted, they are built from the model when added to the engine
+/// and kept up to date after every command. Commands report the entries they changed with
+/// [`crate::ExecutionContext::touched`], otherwise the whole map is scanned after the command
+///
+/// ```ignore
+/// db.add_index(
+///     "orders_by_transport",
+///     Index::new(|m: &EcomModel| &m.orders, |o: &Order| o.transport_id),
+/// );
+/// ```
+pub struct Index<TModel, C: IndexSource, K> {
+    source: fn(&TModel) -> &C,
+    key: fn(&C::Value) -> K,
+    entries: IndexEntries<K, C::Key>,
+}
+
+impl<TModel, C, K> Index<TModel, C, K>
+where
+    C: IndexSource,
+    C::Key: Hash + Eq + Clone,
+    K: Hash + Eq + Clone,
+{
+    pub fn new(source: fn(&TModel) -> &C, key: fn(&C::Value) -> K) -> Self {
+        Index {
+            source,
+            key,
+            entries: IndexEntries {
+                by_key: HashMap::new(),
+                by_primary: HashMap::new(),
+            },
+        }
+    }
+}
+
+/// The entries of an [`Index`], `K` is the indexed key and `PK` the key of the indexed map
+pub struct IndexEntries<K, PK> {
+    by_key: HashMap<K, HashSet<PK>>,
+    by_primary: HashMap<PK, K>,
+}
+
+impl<K: Hash + Eq + Clone, PK: Hash + Eq + Clone> IndexEntries<K, PK> {
+    /// Primary keys of the entries with the given key
+    pub fn get(&self, key: &K) -> impl Iterator<Item = &PK> {
+        self.by_key.get(key).into_iter().flatten()
+    }
+
+    /// Amount of entries with the given key
+    pub fn count(&self, key: &K) -> usize {
+        self.by_key.get(key).map_or(0, HashSet::len)
+    }
+
+    /// All distinct keys in the index
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.by_key.keys()
+    }
+
+    fn set(&mut self, primary: &PK, key: K) {
+        match self.by_primary.get(primary) {
+            Some(current) if *current == key => return,
+            Some(_) => self.remove(primary),
+            None => {}
+        }
+
+        self.by_key
+            .entry(key.clone())
+            .or_default()
+            .insert(primary.clone());
+        self.by_primary.insert(primary.clone(), key);
+    }
+
+    fn remove(&mut self, primary: &PK) {
+        if let Some(key) = self.by_primary.remove(primary) {
+            if let Some(primaries) = self.by_key.get_mut(&key) {
+                primaries.remove(primary);
+                if primaries.is_empty() {
+                    self.by_key.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+/// Type erased [`Index`] so the engine can keep indexes of different types
+pub(crate) trait AnyIndex<TModel>: Send + Sync {
+    /// Brings the index up to date with `model`
+    ///
+    /// Scans the indexed map, only entries that changed are written
+    fn update(&mut self, model: &TModel);
+
+    /// Updates the entries with the primary keys a command reported with
+    /// [`crate::ExecutionContext::touched`], keys of other types are skipped
+    fn update_keys(&mut self, model: &TModel, keys: &[Box<dyn Any>]);
+
+    /// The [`IndexEntries`], as `Any` so the engine can downcast it to the queried types
+    fn entries(&self) -> &dyn Any;
+}
+
+impl<TModel, C, K> AnyIndex<TModel> for Index<TModel, C, K>
+where
+    C: IndexSource,
+    C::Key: Hash + Eq + Clone + Send + Sync + 'static,
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+{
+    fn update(&mut self, model: &TModel) {
+        let source = (self.source)(model);
+        let mut count = 0;
+        source.for_each(|primary, value| {
+            self.entries.set(primary, (self.key)(value));
+            count += 1;
+        });
+
+        if count != self.entries.by_primary.len() {
+            let removed: Vec<C::Key> = self
+                .entries
+                .by_primary
+                .keys()
+                .filter(|primary| source.get(primary).is_none())
+                .cloned()
+                .collect();
+
+            for primary in removed {
+                self.entries.remove(&primary);
+            }
+        }
+    }
+
+    fn update_keys(&mut self, model: &TModel, keys: &[Box<dyn Any>]) {
+        let source = (self.source)(model);
+        for primary in keys.iter().filter_map(|key| key.downcast_ref::<C::Key>()) {
+            match source.get(primary) {
+                Some(value) => self.entries.set(primary, (self.key)(value)),
+                None => self.entries.remove(primary),
+            }
+        }
+    }
+
+    fn entries(&self) -> &dyn Any {
+        &self.entries
+    }
+}
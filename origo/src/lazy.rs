@@ -0,0 +1,183 @@
+use parking_lot::{Condvar, Mutex};
+use std::{
+    any::Any,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::restore::{RestoreObserver, RestorePhase, RestoreProgress};
+
+/// How [`crate::Engine::query_with`] answers while the journal of an engine built with
+/// [`crate::EngineBuilder::build_lazy`] is replayed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Consistency {
+    /// Wait until the replay caught up, how [`crate::Engine::query`] answers
+    #[default]
+    CaughtUp,
+    /// Answer right away, from the snapshot while the journal is replayed
+    AllowStale,
+}
+
+/// Returned from [`crate::Engine::query_with`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Read<R> {
+    /// The query ran against the model with every committed command
+    CaughtUp(R),
+    /// The query ran against the snapshot, the journal is still replayed
+    Stale {
+        value: R,
+        /// Sequence of the last command in the snapshot the query ran against
+        applied: u64,
+        /// Sequence of the last command in the journal, the replay is caught up once it's applied
+        target: u64,
+    },
+}
+
+/// Replay state of an engine, shared by its clones and the replay thread of a lazy restore
+pub(crate) struct Catchup {
+    /// Set once the replay caught up or failed, checked without locking
+    done: AtomicBool,
+    failed: AtomicBool,
+    applied: AtomicU64,
+    target: AtomicU64,
+    /// The memory-mapped snapshot of [`crate::EngineBuilder::build_mapped`] until the snapshot is loaded
+    archive: Mutex<Option<Arc<dyn Any + Send + Sync>>>,
+    lock: Mutex<()>,
+    finished: Condvar,
+}
+
+impl Catchup {
+    /// State of an engine that was restored before it was returned
+    pub(crate) fn caught_up() -> Self {
+        let catchup = Catchup::replaying();
+        catchup.done.store(true, Ordering::Release);
+        catchup
+    }
+
+    pub(crate) fn replaying() -> Self {
+        Catchup {
+            done: AtomicBool::new(false),
+            failed: AtomicBool::new(false),
+            applied: AtomicU64::new(0),
+            target: AtomicU64::new(0),
+            archive: Mutex::new(None),
+            lock: Mutex::new(()),
+            finished: Condvar::new(),
+        }
+    }
+
+    /// The snapshot up to `sequence` is the model until the replay caught up,
+    /// the target is already set if the storage reports its restore
+    pub(crate) fn snapshot_loaded(&self, sequence: u64) {
+        self.applied.store(sequence, Ordering::Relaxed);
+        self.target.fetch_max(sequence, Ordering::Relaxed);
+        *self.archive.lock() = None;
+    }
+
+    /// The memory-mapped snapshot up to `sequence` answers until the snapshot is loaded
+    pub(crate) fn snapshot_mapped(&self, sequence: u64, archive: Arc<dyn Any + Send + Sync>) {
+        self.applied.store(sequence, Ordering::Relaxed);
+        self.target.store(sequence, Ordering::Relaxed);
+        *self.archive.lock() = Some(archive);
+    }
+
+    /// The memory-mapped snapshot while it isn't loaded, see [`Catchup::snapshot_mapped`]
+    #[cfg(feature = "rkyv")]
+    pub(crate) fn archive(&self) -> Option<Arc<dyn Any + Send + Sync>> {
+        self.archive.lock().clone()
+    }
+
+    pub(crate) fn finish(&self, failed: bool) {
+        *self.archive.lock() = None;
+        let _lock = self.lock.lock();
+        self.failed.store(failed, Ordering::Relaxed);
+        self.done.store(true, Ordering::Release);
+        self.finished.notify_all();
+    }
+
+    /// Marks the replay failed when the current thread panics before the guard is dropped
+    pub(crate) fn fail_on_panic(&self) -> FailOnPanic<'_> {
+        FailOnPanic(self)
+    }
+
+    /// Blocks until the replay caught up
+    ///
+    /// # Panics
+    ///
+    /// Panics if the replay failed
+    #[inline(always)]
+    pub(crate) fn wait(&self) {
+        if !self.done.load(Ordering::Acquire) {
+            let mut lock = self.lock.lock();
+            while !self.done.load(Ordering::Acquire) {
+                self.finished.wait(&mut lock);
+            }
+        }
+        self.check();
+    }
+
+    /// `applied` and `target` of [`Read::Stale`] while the journal is replayed
+    pub(crate) fn stale(&self) -> Option<(u64, u64)> {
+        if self.done.load(Ordering::Acquire) {
+            self.check();
+            return None;
+        }
+        Some((
+            self.applied.load(Ordering::Relaxed),
+            self.target.load(Ordering::Relaxed),
+        ))
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the replay failed
+    #[inline(always)]
+    pub(crate) fn check(&self) {
+        assert!(
+            !self.failed.load(Ordering::Relaxed),
+            "The journal replay failed, the engine can't be used"
+        );
+    }
+}
+
+pub(crate) struct FailOnPanic<'a>(&'a Catchup);
+
+impl Drop for FailOnPanic<'_> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.0.finish(true);
+        }
+    }
+}
+
+/// Sets the `target` of the [`Catchup`] to the last sequence of the journal, forwards to the observer of the builder
+pub(crate) struct CatchupObserver {
+    pub(crate) catchup: Arc<Catchup>,
+    pub(crate) observer: Option<Arc<dyn RestoreObserver>>,
+}
+
+impl RestoreObserver for CatchupObserver {
+    fn phase_started(&self, phase: RestorePhase) {
+        if let Some(observer) = &self.observer {
+            observer.phase_started(phase);
+        }
+    }
+
+    fn phase_finished(&self, phase: RestorePhase, elapsed: Duration) {
+        if let Some(observer) = &self.observer {
+            observer.phase_finished(phase, elapsed);
+        }
+    }
+
+    fn progress(&self, progress: &RestoreProgress) {
+        self.catchup
+            .target
+            .store(progress.last_sequence, Ordering::Relaxed);
+        if let Some(observer) = &self.observer {
+            observer.progress(progress);
+        }
+    }
+}
@@ -1,9 +1,55 @@
+mod cell;
+mod codec;
+mod context;
 mod engine;
+mod index;
+mod lazy;
+mod restore;
+mod state;
 pub mod storage;
+pub use cell::*;
+pub use codec::*;
+pub use context::*;
 pub use engine::*;
+pub use index::*;
+pub use lazy::*;
+pub use restore::*;
+pub use state::*;
 
+/// Creates and restores an engine for the model, storage and commands
+///
+/// Prefix with `versioned` to create a [`VersionedEngine`] and/or with `codec <Codec>,`
+/// to serialize with another [`Codec`] than [`Bincode`]
+///
+/// ```ignore
+/// let db = origo_engine! { versioned codec Json, EcomModel, DiskStorage::new("data/dev.origors"), InsertOrder, };
+/// ```
 #[macro_export]
 macro_rules! origo_engine {
+    (versioned codec $codec:ty, $model:ty, $storage:expr, $($y:ty,)+) => {{
+        let mut engine = $crate::EngineBuilder::new(<$model>::default(), $storage).codec::<$codec>();
+        $crate::origo_engine! {
+            engine $model, $($y),+
+        }
+        engine.build_versioned()
+    }};
+
+    (versioned $model:ty, $storage:expr, $($y:ty,)+) => {{
+        let mut engine = $crate::EngineBuilder::new(<$model>::default(), $storage);
+        $crate::origo_engine! {
+            engine $model, $($y),+
+        }
+        engine.build_versioned()
+    }};
+
+    (codec $codec:ty, $model:ty, $storage:expr, $($y:ty,)+) => {{
+        let mut engine = $crate::EngineBuilder::new(<$model>::default(), $storage).codec::<$codec>();
+        $crate::origo_engine! {
+            engine $model, $($y),+
+        }
+        engine.build()
+    }};
+
     ($model:ty, $storage:expr, $($y:ty,)+) => {{
         let mut engine = $crate::EngineBuilder::new(<$model>::default(), $storage);
         $crate::origo_engine! {
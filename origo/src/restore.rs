@@ -0,0 +1,190 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// How often [`RestoreObserver::progress`] is called during the replay
+pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Records applied between two checks of [`PROGRESS_INTERVAL`]
+const PROGRESS_CHECK_RECORDS: u64 = 256;
+
+/// A phase of the restore in [`crate::EngineBuilder::build`], `Snapshot` is skipped without a snapshot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RestorePhase {
+    /// Reading and decoding the snapshot
+    Snapshot,
+    /// Applying the commands journaled after the snapshot
+    Replay,
+}
+
+/// Progress of the replay, see [`RestoreObserver::progress`]
+#[derive(Clone, Debug)]
+pub struct RestoreProgress<'a> {
+    /// Bytes of the journal read so far
+    pub bytes_read: u64,
+    /// Bytes of the journal when the replay started
+    pub bytes_total: u64,
+    /// Records applied so far
+    pub records_applied: u64,
+    /// Sequence of the command applied last
+    pub sequence: u64,
+    /// Sequence of the last command in the journal, found before the replay started.
+    /// The restore is done once `sequence` reaches it
+    pub last_sequence: u64,
+    /// Name of the command applied last
+    pub command: &'a str,
+    /// Time left at the throughput so far, `None` before the first record
+    pub eta: Option<Duration>,
+}
+
+/// Receives the progress of a restore, see [`crate::EngineBuilder::restore_observer`]
+///
+/// Called on the restoring thread, slow callbacks slow down the restore.
+/// Storages that don't report their restore never call it
+pub trait RestoreObserver: Send + Sync {
+    fn phase_started(&self, _phase: RestorePhase) {}
+
+    fn phase_finished(&self, _phase: RestorePhase, _elapsed: Duration) {}
+
+    /// Called once at the start of the replay, every [`PROGRESS_INTERVAL`] during it and once at its end
+    fn progress(&self, _progress: &RestoreProgress) {}
+}
+
+impl<T: RestoreObserver + ?Sized> RestoreObserver for Arc<T> {
+    fn phase_started(&self, phase: RestorePhase) {
+        (**self).phase_started(phase)
+    }
+
+    fn phase_finished(&self, phase: RestorePhase, elapsed: Duration) {
+        (**self).phase_finished(phase, elapsed)
+    }
+
+    fn progress(&self, progress: &RestoreProgress) {
+        (**self).progress(progress)
+    }
+}
+
+/// Runs `load` as the [`RestorePhase::Snapshot`] of `observer`
+pub(crate) fn observe_snapshot<R, F: FnOnce() -> R>(
+    observer: Option<&dyn RestoreObserver>,
+    load: F,
+) -> R {
+    let Some(observer) = observer else {
+        return load();
+    };
+
+    let started = Instant::now();
+    observer.phase_started(RestorePhase::Snapshot);
+    let loaded = load();
+    observer.phase_finished(RestorePhase::Snapshot, started.elapsed());
+    loaded
+}
+
+/// Counts the replayed records, reports them to the observer and logs the throughput at the end
+pub(crate) struct ReplayProgress {
+    observer: Option<Arc<dyn RestoreObserver>>,
+    started: Instant,
+    reported: Instant,
+    bytes_total: u64,
+    /// Bytes of the files replayed before the current one
+    bytes_done: u64,
+    bytes_read: u64,
+    records: u64,
+    sequence: u64,
+    last_sequence: u64,
+    /// Name of the command applied last, only kept with an observer
+    command: String,
+}
+
+impl ReplayProgress {
+    /// Starts the [`RestorePhase::Replay`] of `bytes_total` bytes after the command `sequence`
+    /// up to the command `last_sequence`
+    pub(crate) fn start(
+        observer: Option<Arc<dyn RestoreObserver>>,
+        bytes_total: u64,
+        sequence: u64,
+        last_sequence: u64,
+    ) -> Self {
+        if let Some(observer) = &observer {
+            observer.phase_started(RestorePhase::Replay);
+        }
+        let started = Instant::now();
+        let progress = ReplayProgress {
+            observer,
+            started,
+            reported: started,
+            bytes_total,
+            bytes_done: 0,
+            bytes_read: 0,
+            records: 0,
+            sequence,
+            last_sequence: last_sequence.max(sequence),
+            command: String::new(),
+        };
+        progress.report();
+        progress
+    }
+
+    /// The record of `sequence` that ends at `offset` of the current file was applied
+    pub(crate) fn applied(&mut self, command: &str, offset: u64, sequence: u64) {
+        self.records += 1;
+        self.sequence = sequence;
+        self.bytes_read = self.bytes_done + offset;
+        if self.observer.is_some() {
+            self.command.clear();
+            self.command.push_str(command);
+            if self.records.is_multiple_of(PROGRESS_CHECK_RECORDS)
+                && self.reported.elapsed() >= PROGRESS_INTERVAL
+            {
+                self.reported = Instant::now();
+                self.report();
+            }
+        }
+    }
+
+    /// The current file of `len` bytes is replayed, the next records are from the next file
+    pub(crate) fn file_finished(&mut self, len: u64) {
+        self.bytes_done += len;
+        self.bytes_read = self.bytes_done;
+    }
+
+    /// Ends the phase, returns the amount of replayed records
+    pub(crate) fn finish(self) -> u64 {
+        let elapsed = self.started.elapsed();
+        if let Some(observer) = &self.observer {
+            self.report();
+            observer.phase_finished(RestorePhase::Replay, elapsed);
+        }
+
+        log::info!(
+            "Replayed {} records in {}ms, {:.0} records/s",
+            self.records,
+            elapsed.as_millis(),
+            self.records as f64 / elapsed.as_secs_f64().max(1e-6)
+        );
+        self.records
+    }
+
+    fn report(&self) {
+        let Some(observer) = &self.observer else {
+            return;
+        };
+
+        let eta = (self.bytes_read > 0).then(|| {
+            let left = self.bytes_total.saturating_sub(self.bytes_read);
+            self.started
+                .elapsed()
+                .mul_f64(left as f64 / self.bytes_read as f64)
+        });
+        observer.progress(&RestoreProgress {
+            bytes_read: self.bytes_read,
+            bytes_total: self.bytes_total,
+            records_applied: self.records,
+            sequence: self.sequence,
+            last_sequence: self.last_sequence.max(self.sequence),
+            command: &self.command,
+            eta,
+        });
+    }
+}
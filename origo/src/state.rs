@@ -0,0 +1,182 @@
+use bincode::{
+    de::Decoder,
+    enc::Encoder,
+    error::{DecodeError, EncodeError},
+    Decode, Encode,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use crate::context::Envelope;
+
+/// Engine bookkeeping that is stored with the snapshot and rebuilt from the journal
+#[derive(Encode, Decode, Clone, Debug, Default)]
+pub struct EngineState {
+    sequence: u64,
+    idempotency: IdempotencyTable,
+}
+
+impl EngineState {
+    /// Sequence number of the last applied command
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Amount of idempotency keys currently remembered
+    pub fn idempotency_keys(&self) -> usize {
+        self.idempotency.len()
+    }
+
+    pub(crate) fn idempotency(&self) -> &IdempotencyTable {
+        &self.idempotency
+    }
+
+    pub(crate) fn idempotency_mut(&mut self) -> &mut IdempotencyTable {
+        &mut self.idempotency
+    }
+
+    /// Continues from the state of a snapshot, keeps the idempotency limits of the engine
+    pub(crate) fn restore_snapshot(&mut self, snapshot: EngineState) {
+        self.sequence = snapshot.sequence;
+        self.idempotency.restore(snapshot.idempotency);
+    }
+
+    pub(crate) fn apply(&mut self, envelope: &Envelope) {
+        self.sequence = envelope.sequence;
+
+        if let Some(key) = &envelope.idempotency_key {
+            self.idempotency.insert(key, Receipt::from(envelope));
+        }
+    }
+
+    /// Moves the sequence to the end of a compacted journal segment, its last commands may have been dropped
+    pub(crate) fn skip_to(&mut self, sequence: u64) {
+        self.sequence = self.sequence.max(sequence);
+    }
+}
+
+/// Identifies a committed command
+#[derive(Encode, Decode, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Receipt {
+    pub sequence: u64,
+    /// Commit time in milliseconds since the unix epoch
+    pub timestamp: u64,
+}
+
+impl From<&Envelope> for Receipt {
+    fn from(envelope: &Envelope) -> Self {
+        Receipt {
+            sequence: envelope.sequence,
+            timestamp: envelope.timestamp,
+        }
+    }
+}
+
+/// Returned from [`crate::Engine::execute_idempotent`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Idempotent {
+    /// First time the key was seen, the command was executed
+    Executed(Receipt),
+    /// The key was already used, the command was NOT executed again.
+    /// Holds the receipt from the original execution, commands don't return values,
+    /// query the model for what the original execution changed
+    Duplicate(Receipt),
+}
+
+/// Idempotency keys of the latest commands, bounded by count and age
+///
+/// Eviction uses the commit timestamps of the commands (not the wall-clock),
+/// so the table looks the same after replay.
+/// The limits aren't stored with the snapshot, they're set on the [`crate::EngineBuilder`] before the restore
+#[derive(Clone, Debug)]
+pub(crate) struct IdempotencyTable {
+    keys: HashMap<String, Receipt>,
+    order: VecDeque<String>,
+    max_keys: u64,
+    max_age_ms: u64,
+}
+
+impl Default for IdempotencyTable {
+    fn default() -> Self {
+        IdempotencyTable {
+            keys: HashMap::new(),
+            order: VecDeque::new(),
+            max_keys: 100_000,
+            max_age_ms: 24 * 60 * 60 * 1000,
+        }
+    }
+}
+
+impl IdempotencyTable {
+    pub(crate) fn get(&self, key: &str) -> Option<Receipt> {
+        self.keys.get(key).copied()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub(crate) fn set_limits(&mut self, max_keys: usize, max_age: Duration) {
+        self.max_keys = max_keys as u64;
+        self.max_age_ms = max_age.as_millis() as u64;
+    }
+
+    fn insert(&mut self, key: &str, receipt: Receipt) {
+        if self.keys.insert(key.to_string(), receipt).is_none() {
+            self.order.push_back(key.to_string());
+        }
+        self.evict(receipt.timestamp);
+    }
+
+    /// Takes the keys of a snapshot and evicts the ones over the limits of this table
+    fn restore(&mut self, snapshot: IdempotencyTable) {
+        self.keys = snapshot.keys;
+        self.order = snapshot.order;
+        if let Some(latest) = self.order.back() {
+            self.evict(self.keys[latest].timestamp);
+        }
+    }
+
+    /// Evicts the keys over `max_keys` and those older than `max_age` at `now`
+    fn evict(&mut self, now: u64) {
+        let oldest_allowed = now.saturating_sub(self.max_age_ms);
+        while let Some(oldest) = self.order.front() {
+            let expired = self.keys[oldest].timestamp < oldest_allowed;
+            if !expired && self.order.len() as u64 <= self.max_keys {
+                break;
+            }
+
+            let oldest = self.order.pop_front().unwrap();
+            self.keys.remove(&oldest);
+        }
+    }
+}
+
+/// Only the keys are encoded, in insertion order
+impl Encode for IdempotencyTable {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        // Same encoding as the `Vec<(String, Receipt)>` it's decoded as
+        (self.order.len() as u64).encode(encoder)?;
+        for key in &self.order {
+            key.encode(encoder)?;
+            self.keys[key].encode(encoder)?;
+        }
+        Ok(())
+    }
+}
+
+impl Decode for IdempotencyTable {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let entries: Vec<(String, Receipt)> = Decode::decode(decoder)?;
+        let mut table = IdempotencyTable::default();
+        for (key, receipt) in entries {
+            table.order.push_back(key.clone());
+            table.keys.insert(key, receipt);
+        }
+        Ok(table)
+    }
+}
+
+bincode::impl_borrow_decode!(IdempotencyTable);
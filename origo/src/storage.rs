@@ -1,21 +1,227 @@
+mod archive;
+pub use archive::{
+    restore_archive, DirectoryStore, ObjectStore, ARCHIVE_DIRECTORY, ARCHIVE_STOP_TIMEOUT,
+};
+
+mod backup;
+pub use backup::{
+    restore_from_backup, BackupFile, BackupFiles, BackupManifest, BackupRole, BACKUP_MANIFEST,
+};
+
+mod compaction;
+pub use compaction::{SEGMENT_DIRECTORY, TOMBSTONE_COMMAND};
+
+mod compression;
+pub use compression::Compression;
+
 mod disk;
 pub use disk::DiskStorage;
 
+mod encryption;
+pub use encryption::Encryption;
+#[cfg(not(feature = "encryption"))]
+pub(crate) use encryption::KeyProvider;
+#[cfg(feature = "encryption")]
+pub use encryption::{Key, KeyProvider, KeyRing, StaticKey};
+
+#[cfg(feature = "fault-injection")]
+mod faulty;
+#[cfg(feature = "fault-injection")]
+pub use faulty::{Fault, Faults, FaultyStorage};
+
+mod format;
+#[cfg(feature = "rkyv")]
+pub(crate) use format::model_id;
+pub use format::{FileHeader, FormatError, FORMAT_VERSION, JOURNAL_MAGIC, SNAPSHOT_MAGIC};
+
+mod journal;
+pub use journal::{JournalError, JournalReader, JournalRecord};
+
+#[cfg(feature = "rkyv")]
+mod mapped;
+#[cfg(feature = "rkyv")]
+pub use mapped::MappedSnapshot;
+
+mod lock;
+pub use lock::{DirectoryLock, LOCK_FILE};
+
+mod memory;
+pub use memory::MemoryStorage;
+
 mod noop;
 pub use noop::NoopStorage;
 
-use crate::engine::{Command, CommandRestoreFn};
-use std::collections::HashMap;
+mod replay;
+
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "s3")]
+pub use s3::{S3Store, S3_PART_SIZE};
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use sqlite::{Durability, SqliteStorage};
+
+mod writer;
+pub use writer::JournalWriter;
+
+use crate::{
+    codec::Codec,
+    codec::CodecError,
+    context::Envelope,
+    engine::{Command, CommandRestoreFn, CompactionKeyFn, DecodedCommand},
+    restore::RestoreObserver,
+    state::EngineState,
+};
+use bincode::config::Configuration;
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    path::PathBuf,
+    sync::Arc,
+};
+
+/// Name of the snapshot file, in the directory of the journal
+pub const SNAPSHOT_FILE: &str = "snap.origors";
+
+/// The bincode configuration of the file headers and envelopes, and of the [`crate::Bincode`] codec
+pub static BINCODE_CONFIG: Configuration = bincode::config::standard();
 
 pub trait Storage {
-    fn prepare<TModel, T: Command<TModel>>(&mut self, command_name: &str, command: &T);
+    /// Encodes the command with `C` and buffers the record until [`Storage::commit`]
+    fn prepare<TModel, C: Codec<T>, T: Command<TModel>>(
+        &mut self,
+        envelope: &Envelope,
+        command_name: &str,
+        command: &T,
+    );
 
     fn commit(&mut self) -> u64;
 
-    fn snapshot<TModel: bincode::Encode>(&mut self, model: &TModel);
+    fn snapshot<TModel, C: Codec<TModel>>(&mut self, state: &EngineState, model: &TModel);
 
-    fn restore<TModel: Default + bincode::Decode>(
+    /// Restores the model and `state` from the snapshot and the journal
+    ///
+    /// `snapshot_loaded` is called with the snapshot, or the empty model without one, before the journal is replayed.
+    /// Storages that report their restore start the [`crate::RestorePhase::Replay`] before it,
+    /// so [`crate::RestoreProgress::last_sequence`] is known once the snapshot is loaded
+    fn restore<TModel: Default, C: Codec<TModel>>(
         &mut self,
         restore_fns: &HashMap<String, CommandRestoreFn<TModel>>,
+        state: &mut EngineState,
+        snapshot_loaded: &mut dyn FnMut(&EngineState, &TModel),
     ) -> TModel;
+
+    /// Receives the [`crate::Command::compaction_key`] of every registered command before [`Storage::restore`],
+    /// storages that don't compact their journal ignore them
+    fn compaction_keys(&mut self, _key_fns: HashMap<String, CompactionKeyFn>) {}
+
+    /// Receives the observer of [`crate::EngineBuilder::restore_observer`] before [`Storage::restore`],
+    /// storages that don't report their restore ignore it
+    fn restore_observer(&mut self, _observer: Arc<dyn RestoreObserver>) {}
+
+    /// Receives the id of [`crate::EngineBuilder::model_id`] before [`Storage::restore`], storages write it
+    /// to new files and check it in existing ones instead of the type name of the model
+    fn model_id(&mut self, _id: &str) {}
+
+    /// The snapshot file if there is one, [`crate::EngineBuilder::build_mapped`] memory-maps it.
+    /// Storages that don't keep the snapshot in a file return `None`
+    fn snapshot_file(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// Collects the files of a backup at the last commit, called by [`crate::Engine::backup_to`]
+    /// while writes are stopped. Storages that can't be backed up fail with [`ErrorKind::Unsupported`]
+    fn backup_files(&mut self) -> io::Result<BackupFiles> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "the storage doesn't support backups",
+        ))
+    }
+}
+
+/// Applies a single journal record (envelope + command) to `model` and `state`
+///
+/// Shared by the storages so replay behaves the same no matter where the journal is kept
+///
+/// # Panics
+///
+/// Panics if the command isn't registered or the record can't be decoded
+pub fn replay_record<TModel>(
+    restore_fns: &HashMap<String, CommandRestoreFn<TModel>>,
+    state: &mut EngineState,
+    model: &mut TModel,
+    command_name: &str,
+    data: &[u8],
+    config: Configuration,
+) {
+    let decoded = decode_record(restore_fns, command_name, data, config);
+    apply_record(state, model, command_name, decoded);
+}
+
+/// Why a journal record can't be restored, reported by [`apply_record`] in journal order
+pub(crate) enum RecordError {
+    UnknownCommand,
+    Envelope,
+    Command { sequence: u64, error: CodecError },
+}
+
+/// Decodes the envelope and command of a journal record, can run on any thread
+pub(crate) fn decode_record<TModel>(
+    restore_fns: &HashMap<String, CommandRestoreFn<TModel>>,
+    command_name: &str,
+    data: &[u8],
+    config: Configuration,
+) -> Result<(Envelope, DecodedCommand<TModel>), RecordError> {
+    let restore_fn = match restore_fns.get(command_name) {
+        Some(restore_fn) => Some(restore_fn),
+        None if command_name == TOMBSTONE_COMMAND => None,
+        None => return Err(RecordError::UnknownCommand),
+    };
+
+    let (envelope, envelope_length): (Envelope, usize) =
+        bincode::decode_from_slice(data, config).map_err(|_| RecordError::Envelope)?;
+    let Some(restore_fn) = restore_fn else {
+        return Ok((envelope, Box::new(|_, _| {})));
+    };
+
+    match restore_fn(&data[envelope_length..]) {
+        Ok(command) => Ok((envelope, command)),
+        Err(error) => Err(RecordError::Command {
+            sequence: envelope.sequence,
+            error,
+        }),
+    }
+}
+
+/// Executes a record decoded by [`decode_record`] against `model` and applies it to `state`
+///
+/// # Panics
+///
+/// Panics if the record couldn't be decoded
+pub(crate) fn apply_record<TModel>(
+    state: &mut EngineState,
+    model: &mut TModel,
+    command_name: &str,
+    decoded: Result<(Envelope, DecodedCommand<TModel>), RecordError>,
+) {
+    match decoded {
+        Ok((envelope, command)) => {
+            command(&envelope, model);
+            state.apply(&envelope);
+        }
+        Err(RecordError::UnknownCommand) => {
+            panic!("No restore registered for command {}", command_name)
+        }
+        Err(RecordError::Envelope) => panic!(
+            "Corrupt journal, failed to read envelope of {}({})",
+            command_name,
+            state.sequence() + 1
+        ),
+        Err(RecordError::Command { sequence, error }) => panic!(
+            "Corrupt journal, failed to restore {}({}), {}",
+            command_name, sequence, error
+        ),
+    }
 }
@@ -0,0 +1,391 @@
+use crate::storage::{
+    compaction::segment_name, disk::sync_directory, DirectoryLock, SEGMENT_DIRECTORY, SNAPSHOT_FILE,
+};
+
+use std::{
+    fs::File,
+    io::{self, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+/// Name of the directory in the data directory where files wait for their upload
+pub const ARCHIVE_DIRECTORY: &str = "archive";
+
+/// How long the archiver waits before it retries failed uploads
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long dropping the archiver waits for the staged files to be uploaded
+pub const ARCHIVE_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Off-box storage of archived snapshots and journal segments, see [`crate::storage::DiskStorage::archive`]
+///
+/// Keys are file names like `snapshot-00000000000000001000.origors`, see [`restore_archive`]
+pub trait ObjectStore: Send + Sync + 'static {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()>;
+
+    /// Uploads the `len` bytes of `reader`, how staged files are uploaded.
+    /// Reads them into memory for [`ObjectStore::put`] by default, stores that can stream or upload in parts override it
+    fn put_reader(&self, key: &str, reader: &mut dyn Read, len: u64) -> io::Result<()> {
+        let mut data = Vec::with_capacity(len as usize);
+        reader.take(len).read_to_end(&mut data)?;
+        self.put(key, &data)
+    }
+
+    /// Fails with [`ErrorKind::NotFound`] if there's no object with `key`
+    fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+
+    /// Keys of all objects, in any order
+    fn list(&self) -> io::Result<Vec<String>>;
+}
+
+/// Objects as files in a directory, for example a mounted network share
+pub struct DirectoryStore {
+    directory: PathBuf,
+}
+
+impl DirectoryStore {
+    /// The directory is created by the first upload
+    pub fn new<P: AsRef<Path>>(directory: P) -> Self {
+        DirectoryStore {
+            directory: directory.as_ref().to_owned(),
+        }
+    }
+}
+
+impl ObjectStore for DirectoryStore {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        std::fs::create_dir_all(&self.directory)?;
+        let path = self.directory.join(key);
+        write_durable(&path, |file| file.write_all(data))
+    }
+
+    fn put_reader(&self, key: &str, reader: &mut dyn Read, len: u64) -> io::Result<()> {
+        std::fs::create_dir_all(&self.directory)?;
+        let path = self.directory.join(key);
+        write_durable(&path, |file| {
+            io::copy(&mut reader.take(len), file).map(|_| ())
+        })
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        std::fs::read(self.directory.join(key))
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        let entries = match std::fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut keys = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                if let Some(key) = entry.file_name().to_str() {
+                    if !key.ends_with(".tmp") {
+                        keys.push(key.to_owned());
+                    }
+                }
+            }
+        }
+        Ok(keys)
+    }
+}
+
+/// Key of the snapshot that ends at `sequence`
+pub(crate) fn snapshot_key(sequence: u64) -> String {
+    format!("snapshot-{:020}.origors", sequence)
+}
+
+/// Key of the sealed journal segment with the commands `start..=end`
+pub(crate) fn segment_key(start: u64, end: u64) -> String {
+    format!("journal-{:020}-{:020}.origors", start, end)
+}
+
+/// What a key of the archive holds
+enum ArchiveKey {
+    Snapshot { end: u64 },
+    Segment { start: u64, end: u64 },
+}
+
+impl ArchiveKey {
+    fn parse(key: &str) -> Option<Self> {
+        let name = key.strip_suffix(".origors")?;
+        if let Some(end) = name.strip_prefix("snapshot-") {
+            return Some(ArchiveKey::Snapshot {
+                end: end.parse().ok()?,
+            });
+        }
+
+        let (start, end) = name.strip_prefix("journal-")?.split_once('-')?;
+        Some(ArchiveKey::Segment {
+            start: start.parse().ok()?,
+            end: end.parse().ok()?,
+        })
+    }
+}
+
+/// Uploads the files staged in the archive directory of a [`crate::storage::DiskStorage`]
+///
+/// Files are deleted once uploaded, uploads that fail stay staged and are retried
+/// later or after a restart. Dropping the archiver waits up to [`ARCHIVE_STOP_TIMEOUT`]
+/// for the staged files to be uploaded, the files left are uploaded after the next start
+pub(crate) struct Archiver {
+    directory: PathBuf,
+    wake: Option<Sender<()>>,
+    /// Set when the archiver is dropped and the upload didn't finish in time, the thread stops after the current file
+    stop: Arc<AtomicBool>,
+    stopped: Receiver<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Archiver {
+    /// Starts the upload thread, files left from a previous run are uploaded right away
+    pub(crate) fn start(directory: PathBuf, store: Box<dyn ObjectStore>) -> Self {
+        std::fs::create_dir_all(&directory).expect("Failed to create archive directory");
+
+        let (wake, woken) = mpsc::channel();
+        let (finished, stopped) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::Builder::new()
+            .name("origo-archiver".to_string())
+            .spawn({
+                let directory = directory.clone();
+                let stop = stop.clone();
+                move || loop {
+                    let last = match woken.recv_timeout(RETRY_INTERVAL) {
+                        Ok(()) | Err(RecvTimeoutError::Timeout) => false,
+                        Err(RecvTimeoutError::Disconnected) => true,
+                    };
+                    if let Err(e) = upload_staged(&directory, store.as_ref(), &stop) {
+                        log::error!("Archive upload failed, retrying later, {}", e);
+                    }
+                    if last {
+                        let _ = finished.send(());
+                        return;
+                    }
+                }
+            })
+            .expect("Failed to start archiver");
+
+        wake.send(()).expect("Archiver stopped");
+        Archiver {
+            directory,
+            wake: Some(wake),
+            stop,
+            stopped,
+            thread: Some(thread),
+        }
+    }
+
+    /// Stages the journal segment `start..=end`, the first `len` bytes of the journal
+    pub(crate) fn stage_segment(&self, journal_path: &Path, len: u64, start: u64, end: u64) {
+        let staged = self.directory.join(segment_key(start, end));
+        let copied = File::open(journal_path).and_then(|journal| {
+            write_durable(&staged, |file| {
+                io::copy(&mut journal.take(len), file).map(|_| ())
+            })
+        });
+        if let Err(e) = copied {
+            log::error!("Failed to stage journal segment {:?}, {}", staged, e);
+        }
+    }
+
+    /// Stages the snapshot that ends at `sequence`, a hard link as snapshots are replaced and never changed
+    pub(crate) fn stage_snapshot(&self, snapshot_path: &Path, sequence: u64) {
+        let staged = self.directory.join(snapshot_key(sequence));
+        let linked = match std::fs::hard_link(snapshot_path, &staged) {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(()),
+            Err(_) => std::fs::copy(snapshot_path, &staged).map(|_| ()),
+            linked => linked,
+        };
+        if let Err(e) = linked {
+            log::error!("Failed to stage snapshot {:?}, {}", staged, e);
+        }
+    }
+
+    /// Uploads the staged files in the background
+    pub(crate) fn wake(&self) {
+        if let Some(wake) = &self.wake {
+            let _ = wake.send(());
+        }
+    }
+}
+
+impl Drop for Archiver {
+    fn drop(&mut self) {
+        self.wake = None;
+        match self.stopped.recv_timeout(ARCHIVE_STOP_TIMEOUT) {
+            Err(RecvTimeoutError::Timeout) => {
+                // The thread is left to finish the current file, the rest stays staged
+                self.stop.store(true, Ordering::Relaxed);
+                log::warn!(
+                    "Archive upload didn't finish in {}s, the staged files are uploaded after the next start",
+                    ARCHIVE_STOP_TIMEOUT.as_secs()
+                );
+            }
+            _ => {
+                if let Some(thread) = self.thread.take() {
+                    let _ = thread.join();
+                }
+            }
+        }
+    }
+}
+
+/// Uploads the staged files, segments before the snapshot that contains them. Returns early once `stop` is set
+fn upload_staged(directory: &Path, store: &dyn ObjectStore, stop: &AtomicBool) -> io::Result<()> {
+    let mut keys = DirectoryStore::new(directory).list()?;
+    keys.sort();
+    for key in keys {
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+        let path = directory.join(&key);
+        let mut file = File::open(&path)?;
+        let len = file.metadata()?.len();
+        store.put_reader(&key, &mut file, len)?;
+        std::fs::remove_file(&path)?;
+        log::debug!("Archived {}", key);
+    }
+    Ok(())
+}
+
+/// Writes `path` through a temporary file that is synced and renamed, the temporary file is removed if `write` fails
+pub(crate) fn write_durable<F: FnOnce(&mut File) -> io::Result<()>>(
+    path: &Path,
+    write: F,
+) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let mut file = File::create(&temp_path)?;
+    let written = write(&mut file)
+        .and_then(|_| file.sync_all())
+        .and_then(|_| std::fs::rename(&temp_path, path));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    written
+}
+
+/// Rebuilds the data directory of the journal at `journal_path` from an archive
+///
+/// Picks the snapshot, and the journal segments that continue it, with the most commands.
+/// A segment continues a snapshot when the upload of the next snapshot failed, or the journal
+/// is compacted instead of snapshotted. The last segment becomes the journal, the others are
+/// restored into the segments directory. Returns the sequence of the last restored command, open the journal with
+/// [`crate::storage::DiskStorage::new`] afterwards. Encrypted files need the same keys as before
+///
+/// Fails if the directory already has a journal or snapshot, or the archive is empty
+pub fn restore_archive<O: ObjectStore + ?Sized, P: AsRef<Path>>(
+    store: &O,
+    journal_path: P,
+) -> io::Result<u64> {
+    let journal_path = journal_path.as_ref();
+    let (_lock, snapshot_path) = lock_empty_directory(journal_path)?;
+
+    let keys = store.list()?;
+    let archived: Vec<(&String, ArchiveKey)> = keys
+        .iter()
+        .filter_map(|key| ArchiveKey::parse(key).map(|parsed| (key, parsed)))
+        .collect();
+
+    // The segment with the most commands after a sequence, sequence 0 for the first journal
+    let segment_after = |sequence: u64| {
+        archived
+            .iter()
+            .filter_map(|(key, parsed)| match parsed {
+                ArchiveKey::Segment { start, end } if *start == sequence + 1 && end >= start => {
+                    Some((*key, *start, *end))
+                }
+                _ => None,
+            })
+            .max_by_key(|(_, _, end)| *end)
+    };
+    let segments_after = |mut sequence: u64| {
+        let mut segments = Vec::new();
+        while let Some(segment) = segment_after(sequence) {
+            sequence = segment.2;
+            segments.push(segment);
+        }
+        (segments, sequence)
+    };
+    let snapshots = archived.iter().filter_map(|(key, parsed)| match parsed {
+        ArchiveKey::Snapshot { end } => Some((Some(*key), *end)),
+        ArchiveKey::Segment { .. } => None,
+    });
+    let (snapshot, mut segments, end) = snapshots
+        .chain([(None, 0)])
+        .map(|(snapshot, end)| {
+            let (segments, end) = segments_after(end);
+            (snapshot, segments, end)
+        })
+        .max_by_key(|(snapshot, segments, end)| {
+            (*end, snapshot.is_some(), usize::MAX - segments.len())
+        })
+        .filter(|(snapshot, segments, _)| snapshot.is_some() || !segments.is_empty())
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "the archive is empty"))?;
+
+    if let Some(key) = snapshot {
+        write_durable(&snapshot_path, |file| file.write_all(&store.get(key)?))?;
+        log::info!("Restored snapshot {} from the archive", key);
+    }
+    if let Some((key, _, _)) = segments.pop() {
+        write_durable(journal_path, |file| file.write_all(&store.get(key)?))?;
+        log::info!("Restored journal segment {} from the archive", key);
+    }
+    for (key, start, end) in segments {
+        let directory = snapshot_path.with_file_name(SEGMENT_DIRECTORY);
+        std::fs::create_dir_all(&directory)?;
+        let path = directory.join(segment_name(start, end));
+        write_durable(&path, |file| file.write_all(&store.get(key)?))?;
+        sync_directory(&path)?;
+        log::info!("Restored sealed journal segment {} from the archive", key);
+    }
+    sync_directory(journal_path)?;
+
+    Ok(end)
+}
+
+/// Locks the data directory of the journal at `journal_path` for a restore,
+/// fails if it already has a journal, snapshot or sealed segments. Returns the path of the snapshot
+pub(crate) fn lock_empty_directory(journal_path: &Path) -> io::Result<(DirectoryLock, PathBuf)> {
+    let directory = journal_path.parent().unwrap_or(Path::new("")).to_owned();
+    std::fs::create_dir_all(&directory)?;
+    let lock = DirectoryLock::try_lock(&directory).map_err(|e| match e.kind() {
+        ErrorKind::WouldBlock => io::Error::new(
+            ErrorKind::WouldBlock,
+            format!(
+                "data directory {:?} is in use by another process",
+                directory
+            ),
+        ),
+        _ => e,
+    })?;
+
+    let snapshot_path = directory.join(SNAPSHOT_FILE);
+    let segment_directory = directory.join(SEGMENT_DIRECTORY);
+    let has_segments =
+        std::fs::read_dir(&segment_directory).is_ok_and(|mut entries| entries.next().is_some());
+    for path in [journal_path, &snapshot_path, &segment_directory] {
+        let exists = match path == segment_directory {
+            true => has_segments,
+            false => path.metadata().is_ok_and(|metadata| metadata.len() > 0),
+        };
+        if exists {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{:?} exists, restore into an empty data directory", path),
+            ));
+        }
+    }
+    Ok((lock, snapshot_path))
+}
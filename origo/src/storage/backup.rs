@@ -0,0 +1,336 @@
+use crate::storage::{
+    archive::{lock_empty_directory, write_durable},
+    disk::sync_directory,
+    SEGMENT_DIRECTORY,
+};
+
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::{self, ErrorKind, Read, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Name of the manifest in a backup directory, it's written after the files
+pub const BACKUP_MANIFEST: &str = "backup.manifest";
+
+/// First line of the manifest
+const MANIFEST_VERSION: &str = "origo-backup 1";
+
+/// What a file of a backup is restored as
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackupRole {
+    Snapshot,
+    /// A sealed journal segment, restored into the segments directory under its name
+    Segment,
+    Journal,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackupFile {
+    pub role: BackupRole,
+    /// Name of the file in the backup directory
+    pub name: String,
+    pub len: u64,
+    /// SHA-256 of the content, lowercase hex
+    pub sha256: String,
+}
+
+/// The files of a backup and the sequence they end at, see [`crate::Engine::backup_to`]
+///
+/// Stored as text in [`BACKUP_MANIFEST`]:
+/// ```text
+/// origo-backup 1
+/// sequence 1042
+/// created 1792353801439
+/// snapshot 443 <sha256> snap.origors
+/// journal 1041 <sha256> journal.origors
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackupManifest {
+    /// Sequence of the last command in the backup
+    pub sequence: u64,
+    /// Creation time in milliseconds since the unix epoch
+    pub created: u64,
+    pub files: Vec<BackupFile>,
+}
+
+impl BackupManifest {
+    /// Reads the manifest of the backup in `directory`
+    pub fn read_from<P: AsRef<Path>>(directory: P) -> io::Result<Self> {
+        let path = directory.as_ref().join(BACKUP_MANIFEST);
+        let text = std::fs::read_to_string(&path)?;
+        let invalid = |reason: &str| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{:?} is not a backup manifest, {}", path, reason),
+            )
+        };
+
+        let mut lines = text.lines();
+        if lines.next() != Some(MANIFEST_VERSION) {
+            return Err(invalid("unknown version"));
+        }
+        let mut number = |name: &str| {
+            lines
+                .next()
+                .and_then(|line| line.strip_prefix(name))
+                .and_then(|value| value.trim().parse().ok())
+                .ok_or_else(|| invalid(&format!("{} is missing", name)))
+        };
+        let sequence = number("sequence")?;
+        let created = number("created")?;
+
+        let files = lines
+            .map(|line| {
+                let mut parts = line.splitn(4, ' ');
+                let role = match parts.next() {
+                    Some("snapshot") => BackupRole::Snapshot,
+                    Some("segment") => BackupRole::Segment,
+                    Some("journal") => BackupRole::Journal,
+                    _ => return Err(invalid(&format!("unknown file {}", line))),
+                };
+                match (parts.next().map(str::parse), parts.next(), parts.next()) {
+                    (Some(Ok(len)), Some(sha256), Some(name)) => Ok(BackupFile {
+                        role,
+                        name: name.to_owned(),
+                        len,
+                        sha256: sha256.to_owned(),
+                    }),
+                    _ => Err(invalid(&format!("unknown file {}", line))),
+                }
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(BackupManifest {
+            sequence,
+            created,
+            files,
+        })
+    }
+
+    /// Checks the length and checksum of every file of the backup in `directory`
+    pub fn verify<P: AsRef<Path>>(&self, directory: P) -> io::Result<()> {
+        for file in &self.files {
+            let copied = copy_hashed(
+                File::open(directory.as_ref().join(&file.name))?,
+                &mut io::sink(),
+            )?;
+            file.check(copied)?;
+        }
+        Ok(())
+    }
+
+    fn write_to(&self, directory: &Path) -> io::Result<()> {
+        let mut text = format!(
+            "{}\nsequence {}\ncreated {}\n",
+            MANIFEST_VERSION, self.sequence, self.created
+        );
+        for file in &self.files {
+            let role = match file.role {
+                BackupRole::Snapshot => "snapshot",
+                BackupRole::Segment => "segment",
+                BackupRole::Journal => "journal",
+            };
+            text.push_str(&format!(
+                "{} {} {} {}\n",
+                role, file.len, file.sha256, file.name
+            ));
+        }
+        write_durable(&directory.join(BACKUP_MANIFEST), |manifest| {
+            manifest.write_all(text.as_bytes())
+        })
+    }
+}
+
+impl BackupFile {
+    /// Fails if `(len, sha256)` of a copy don't match the manifest
+    fn check(&self, (len, sha256): (u64, String)) -> io::Result<()> {
+        match len == self.len && sha256 == self.sha256 {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} is corrupt, it has {} bytes with checksum {} instead of {} bytes with checksum {}",
+                    self.name, len, sha256, self.len, self.sha256
+                ),
+            )),
+        }
+    }
+}
+
+/// A file of [`BackupFiles`], the first `len` bytes of `source`
+struct BackupSource {
+    role: BackupRole,
+    name: String,
+    source: Box<dyn Read + Send>,
+    len: u64,
+}
+
+/// The files of a backup, collected by [`crate::storage::Storage::backup_files`] while writes are
+/// stopped and copied after they resume
+#[derive(Default)]
+pub struct BackupFiles {
+    sources: Vec<BackupSource>,
+    /// Counter of the journal resets of the storage and its value when the files were collected
+    resets: Option<(Arc<AtomicU64>, u64)>,
+}
+
+impl BackupFiles {
+    pub fn new() -> Self {
+        BackupFiles::default()
+    }
+
+    /// Backs up the first `len` bytes of `source` as `name`, an open file keeps its content when it's replaced by a rename
+    pub fn file<R: Read + Send + 'static>(
+        mut self,
+        role: BackupRole,
+        name: &str,
+        source: R,
+        len: u64,
+    ) -> Self {
+        self.sources.push(BackupSource {
+            role,
+            name: name.to_owned(),
+            source: Box::new(source),
+            len,
+        });
+        self
+    }
+
+    /// Counter the storage increments when it changes a file in place, like a journal reset,
+    /// the copy is discarded if it changes
+    pub fn resets(mut self, resets: Arc<AtomicU64>) -> Self {
+        let count = resets.load(Ordering::Acquire);
+        self.resets = Some((resets, count));
+        self
+    }
+
+    /// Copies the files to `directory` and writes the manifest, `None` if a file changed during the copy
+    pub(crate) fn write_to(
+        self,
+        directory: &Path,
+        sequence: u64,
+    ) -> io::Result<Option<BackupManifest>> {
+        std::fs::create_dir_all(directory)?;
+        let changed = || {
+            self.resets
+                .as_ref()
+                .is_some_and(|(resets, count)| resets.load(Ordering::Acquire) != *count)
+        };
+
+        let mut files = Vec::new();
+        for source in self.sources {
+            let (len, sha256) = {
+                let mut copied = None;
+                write_durable(&directory.join(&source.name), |file| {
+                    copied = Some(copy_hashed(source.source.take(source.len), file)?);
+                    Ok(())
+                })?;
+                copied.expect("The file was copied")
+            };
+            if len != source.len {
+                // A file that was truncated during the copy is copied again
+                if changed() {
+                    return Ok(None);
+                }
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!(
+                        "{} ended after {} of {} bytes",
+                        source.name, len, source.len
+                    ),
+                ));
+            }
+            files.push(BackupFile {
+                role: source.role,
+                name: source.name,
+                len,
+                sha256,
+            });
+        }
+
+        if changed() {
+            return Ok(None);
+        }
+
+        let manifest = BackupManifest {
+            sequence,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_millis() as u64),
+            files,
+        };
+        manifest.write_to(directory)?;
+        sync_directory(&directory.join(BACKUP_MANIFEST))?;
+        Ok(Some(manifest))
+    }
+}
+
+/// Copies `reader` to `writer`, returns the length and SHA-256 of the content
+fn copy_hashed<R: Read, W: Write>(mut reader: R, writer: &mut W) -> io::Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut len = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buffer[..read]);
+        writer.write_all(&buffer[..read])?;
+        len += read as u64;
+    }
+    Ok((len, hex(&hasher.finalize())))
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Restores a backup of [`crate::Engine::backup_to`] into the data directory of the journal at `journal_path`
+///
+/// The files are checked against the manifest before anything is copied, open the journal
+/// with [`crate::storage::DiskStorage::new`] afterwards. Returns the sequence of the last command in the backup
+///
+/// Fails if the backup doesn't match its manifest or the directory already has a journal or snapshot
+pub fn restore_from_backup<B: AsRef<Path>, P: AsRef<Path>>(
+    backup: B,
+    journal_path: P,
+) -> io::Result<u64> {
+    let backup = backup.as_ref();
+    let journal_path = journal_path.as_ref();
+    let manifest = BackupManifest::read_from(backup)?;
+    manifest.verify(backup)?;
+
+    let (_lock, snapshot_path) = lock_empty_directory(journal_path)?;
+    let segment_directory = snapshot_path.with_file_name(SEGMENT_DIRECTORY);
+    for file in &manifest.files {
+        let target = match file.role {
+            BackupRole::Snapshot => snapshot_path.clone(),
+            BackupRole::Segment => {
+                std::fs::create_dir_all(&segment_directory)?;
+                segment_directory.join(&file.name)
+            }
+            BackupRole::Journal => journal_path.to_owned(),
+        };
+        write_durable(&target, |copy| {
+            file.check(copy_hashed(File::open(backup.join(&file.name))?, copy)?)
+        })?;
+        sync_directory(&target)?;
+    }
+
+    log::info!(
+        "Restored backup {:?} up to sequence {}",
+        backup,
+        manifest.sequence
+    );
+    Ok(manifest.sequence)
+}
@@ -0,0 +1,344 @@
+use crate::{
+    engine::CompactionKeyFn,
+    storage::{
+        archive::write_durable,
+        compression::{train_dictionary, DICTIONARY_SAMPLES},
+        disk::sync_directory,
+        encryption::Cipher,
+        journal::{
+            command_ids, encode_record_data, frame_record, record_compressor, seal_dictionary,
+            write_command_id,
+        },
+        Compression, JournalReader, KeyProvider, BINCODE_CONFIG, FORMAT_VERSION, JOURNAL_MAGIC,
+    },
+};
+
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::{self, BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Sender},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Name of the directory in the data directory with the sealed journal segments,
+/// see [`crate::storage::DiskStorage::compact_journal`]
+pub const SEGMENT_DIRECTORY: &str = "segments";
+
+/// Command name of the records a compaction writes for dropped commands that have an idempotency key.
+/// They hold only the envelope, replaying them restores the idempotency key without touching the model
+pub const TOMBSTONE_COMMAND: &str = "origo::Tombstone";
+
+/// File name of the sealed journal segment with the commands `start..=end`
+pub(crate) fn segment_name(start: u64, end: u64) -> String {
+    format!("segment-{:020}-{:020}.origors", start, end)
+}
+
+/// A sealed journal segment, a journal file with the commands `start..=end`.
+/// Compacted segments are missing the commands that were overwritten
+#[derive(Clone, Debug)]
+pub(crate) struct Segment {
+    pub(crate) path: PathBuf,
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+}
+
+impl Segment {
+    fn contains(&self, other: &Segment) -> bool {
+        self.start <= other.start && other.end <= self.end
+    }
+}
+
+/// The segments in `directory` by start, the longest first for the same start
+pub(crate) fn list_segments(directory: &Path) -> io::Result<Vec<Segment>> {
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut segments = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        let range = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("segment-")?.strip_suffix(".origors"))
+            .and_then(|range| range.split_once('-'))
+            .and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)));
+        if let Some((start, end)) = range.filter(|(start, end)| start <= end) {
+            segments.push(Segment {
+                path: entry.path(),
+                start,
+                end,
+            });
+        }
+    }
+    segments.sort_by_key(|segment| (segment.start, u64::MAX - segment.end));
+    Ok(segments)
+}
+
+/// The segments that continue after `sequence` one after another, the longest at every step
+pub(crate) fn segment_chain(segments: &[Segment], sequence: u64) -> Vec<Segment> {
+    let mut chain = Vec::new();
+    let mut end = sequence;
+    while let Some(segment) = segments.iter().find(|segment| segment.start == end + 1) {
+        end = segment.end;
+        chain.push(segment.clone());
+    }
+    chain
+}
+
+/// Removes the segments `remove` returns true for, while holding `lock`
+pub(crate) fn remove_segments<F: Fn(&Segment) -> bool>(
+    directory: &Path,
+    lock: &Mutex<()>,
+    remove: F,
+) -> io::Result<()> {
+    let _lock = lock.lock();
+    let mut removed = None;
+    for segment in list_segments(directory)?
+        .into_iter()
+        .filter(|segment| remove(segment))
+    {
+        std::fs::remove_file(&segment.path)?;
+        removed = Some(segment.path);
+    }
+    match removed {
+        Some(path) => sync_directory(&path),
+        None => Ok(()),
+    }
+}
+
+/// How the compactor rewrites segments
+pub(crate) struct Rewrite {
+    pub(crate) key_fns: HashMap<String, CompactionKeyFn>,
+    /// Command table of the compacted segments
+    pub(crate) commands: Vec<String>,
+    pub(crate) compression: Compression,
+    pub(crate) keys: Option<Arc<dyn KeyProvider>>,
+    /// Held while segments are removed, so a backup doesn't list a segment that is about to disappear
+    pub(crate) lock: Arc<Mutex<()>>,
+}
+
+/// Merges the sealed segments of a [`crate::storage::DiskStorage`] in the background,
+/// keeping only the latest command of each [`crate::Command::compaction_key`].
+/// Dropped commands with an idempotency key are replaced by a [`TOMBSTONE_COMMAND`]
+///
+/// The merged segment is written next to the segments it replaces before they're removed,
+/// after a crash in between the longer segment is restored and the others removed
+pub(crate) struct Compactor {
+    wake: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    /// Starts the compaction thread, segments left from a previous run are compacted right away
+    pub(crate) fn start(directory: PathBuf, rewrite: Rewrite) -> Self {
+        let (wake, woken) = mpsc::channel::<()>();
+        let thread = std::thread::Builder::new()
+            .name("origo-compactor".to_string())
+            .spawn(move || {
+                while woken.recv().is_ok() {
+                    if let Err(e) = compact(&directory, &rewrite) {
+                        log::error!(
+                            "Journal compaction failed, retrying after the next seal, {}",
+                            e
+                        );
+                    }
+                }
+            })
+            .expect("Failed to start compactor");
+
+        wake.send(()).expect("Compactor stopped");
+        Compactor {
+            wake: Some(wake),
+            thread: Some(thread),
+        }
+    }
+
+    /// Compacts the sealed segments in the background
+    pub(crate) fn wake(&self) {
+        if let Some(wake) = &self.wake {
+            let _ = wake.send(());
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.wake = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Merges the chain of segments at the start of `directory` into one segment
+fn compact(directory: &Path, rewrite: &Rewrite) -> io::Result<()> {
+    let segments = list_segments(directory)?;
+    let Some(first) = segments.first() else {
+        return Ok(());
+    };
+    let chain = segment_chain(&segments, first.start.saturating_sub(1));
+    let (start, end) = (first.start, chain.last().map_or(first.end, |last| last.end));
+    let merged = Segment {
+        path: directory.join(segment_name(start, end)),
+        start,
+        end,
+    };
+
+    if chain.len() > 1 {
+        let (before, after, tombstones) = rewrite_chain(&chain, &merged.path, rewrite)?;
+        sync_directory(&merged.path)?;
+        log::info!(
+            "Compacted journal segments {}..={} from {} to {} commands and {} tombstones",
+            start,
+            end,
+            before,
+            after,
+            tombstones
+        );
+    }
+
+    // Also removes the leftovers of a compaction that was interrupted before it removed its segments
+    remove_segments(directory, &rewrite.lock, |segment| {
+        segment.path != merged.path && merged.contains(segment)
+    })
+}
+
+fn open_segment(
+    segment: &Segment,
+    rewrite: &Rewrite,
+) -> io::Result<JournalReader<io::BufReader<std::fs::File>>> {
+    let reader = JournalReader::open(&segment.path).map_err(invalid(segment))?;
+    reader
+        .with_keys(rewrite.keys.as_deref())
+        .map_err(invalid(segment))
+}
+
+fn invalid<E: std::fmt::Display>(segment: &Segment) -> impl Fn(E) -> io::Error + '_ {
+    move |e| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("segment {:?} can't be read, {}", segment.path, e),
+        )
+    }
+}
+
+/// Writes the commands of `chain` that aren't overwritten by a later command with the same key to `path`,
+/// overwritten commands with an idempotency key as tombstones.
+/// Returns the amount of commands before and after, and the amount of tombstones
+///
+/// Dropping a command is only correct if the later command with its key is a pure overwrite,
+/// see [`crate::Command::compaction_key`]
+fn rewrite_chain(chain: &[Segment], path: &Path, rewrite: &Rewrite) -> io::Result<(u64, u64, u64)> {
+    let compaction_key = |name: &str, command: &[u8]| match rewrite.key_fns.get(name) {
+        Some(key_fn) => key_fn(command).map_err(|e| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("command {} can't be decoded, {}", name, e),
+            )
+        }),
+        None => Ok(None),
+    };
+
+    // Sequences of the commands that have a later command with the same key
+    let mut latest: HashMap<String, u64> = HashMap::new();
+    let mut overwritten = HashSet::new();
+    let mut samples = VecDeque::with_capacity(DICTIONARY_SAMPLES);
+    let mut before = 0;
+    for segment in chain {
+        for record in open_segment(segment, rewrite)? {
+            let record = record.map_err(invalid(segment))?;
+            let (envelope, command) = record.envelope(BINCODE_CONFIG).map_err(invalid(segment))?;
+            if let Some(key) = compaction_key(&record.name, command)? {
+                if let Some(sequence) = latest.insert(key, envelope.sequence) {
+                    overwritten.insert(sequence);
+                }
+            }
+            if samples.len() == DICTIONARY_SAMPLES {
+                samples.pop_front();
+            }
+            samples.push_back(record.data);
+            before += 1;
+        }
+    }
+
+    let mut header = open_segment(&chain[0], rewrite)?
+        .header()
+        .cloned()
+        .ok_or_else(|| invalid(&chain[0])("no file header"))?;
+    let cipher = rewrite
+        .keys
+        .as_ref()
+        .map(|keys| {
+            let (key_id, key) = keys.current();
+            Cipher::new(key_id, &key)
+        })
+        .transpose()?;
+    header.version = FORMAT_VERSION;
+    header.compression = rewrite.compression;
+    header.encryption = cipher.as_ref().map(Cipher::encryption);
+    header.commands = rewrite.commands.clone();
+    header.created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |created| created.as_millis() as u64);
+    header.start_sequence = chain[0].start;
+    header.dictionary = seal_dictionary(
+        train_dictionary(header.compression, samples.make_contiguous()),
+        cipher.as_ref(),
+    )?;
+    let mut compressor = record_compressor(&header, cipher.as_ref())?;
+    let ids = command_ids(&header.commands);
+
+    let (mut after, mut tombstones) = (0, 0);
+    write_durable(path, |file| {
+        let mut writer = BufWriter::new(file);
+        header.write_to(&JOURNAL_MAGIC, &mut writer)?;
+
+        let mut buffer = Vec::new();
+        for segment in chain {
+            for record in open_segment(segment, rewrite)? {
+                let record = record.map_err(invalid(segment))?;
+                let (envelope, command) =
+                    record.envelope(BINCODE_CONFIG).map_err(invalid(segment))?;
+                let (name, data) = match overwritten.contains(&envelope.sequence) {
+                    false => (record.name.as_str(), &record.data[..]),
+                    // Keeps the idempotency key, a retry of the command is still answered from it
+                    true if envelope.idempotency_key.is_some() => (
+                        TOMBSTONE_COMMAND,
+                        &record.data[..record.data.len() - command.len()],
+                    ),
+                    true => continue,
+                };
+
+                buffer.clear();
+                write_command_id(&mut buffer, &ids, name);
+                let data_offset = buffer.len();
+                buffer.extend_from_slice(data);
+                encode_record_data(
+                    &mut buffer,
+                    data_offset,
+                    &mut compressor,
+                    cipher.as_ref(),
+                    envelope.sequence,
+                    name,
+                )?;
+                frame_record(&mut buffer);
+                writer.write_all(&buffer)?;
+                match name == TOMBSTONE_COMMAND {
+                    true => tombstones += 1,
+                    false => after += 1,
+                }
+            }
+        }
+        writer.flush()
+    })?;
+    Ok((before, after, tombstones))
+}
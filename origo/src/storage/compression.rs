@@ -0,0 +1,283 @@
+use bincode::{
+    de::Decoder,
+    enc::Encoder,
+    error::{DecodeError, EncodeError},
+    Decode, Encode,
+};
+use std::io::{self, Read, Write};
+
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+use crate::storage::journal::{read_varint, write_varint};
+
+/// Size of the dictionaries trained for journal records
+const DICTIONARY_LEN: usize = 16 << 10;
+/// Records a dictionary is trained from, the most recent ones
+pub(crate) const DICTIONARY_SAMPLES: usize = 1_000;
+/// Fewer records don't make a useful dictionary
+const DICTIONARY_MIN_SAMPLES: usize = 16;
+
+/// Compression of journal records and snapshots, recorded in the file header
+///
+/// Journal records are compressed one by one with a dictionary trained on the records of the
+/// previous journal, see [`crate::storage::FileHeader::dictionary`], snapshots as a stream after the header.
+/// `Zstd` needs the `zstd` feature and `Lz4` the `lz4` feature,
+/// files compressed with a variant this build doesn't have fail to open
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Levels 1-22, 3 is a good default
+    #[cfg(feature = "zstd")]
+    Zstd { level: i32 },
+    /// Faster than zstd, compresses less
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+/// Same encoding as a derived `Encode` with all variants, the variant index doesn't depend on the features
+impl Encode for Compression {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        match self {
+            Compression::None => 0u32.encode(encoder),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { level } => {
+                1u32.encode(encoder)?;
+                level.encode(encoder)
+            }
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => 2u32.encode(encoder),
+        }
+    }
+}
+
+impl Decode for Compression {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        match u32::decode(decoder)? {
+            0 => Ok(Compression::None),
+            #[cfg(feature = "zstd")]
+            1 => Ok(Compression::Zstd {
+                level: i32::decode(decoder)?,
+            }),
+            #[cfg(feature = "lz4")]
+            2 => Ok(Compression::Lz4),
+            #[cfg(not(feature = "zstd"))]
+            1 => Err(needs_feature("zstd")),
+            #[cfg(not(feature = "lz4"))]
+            2 => Err(needs_feature("lz4")),
+            _ => Err(DecodeError::Other("unknown compression")),
+        }
+    }
+}
+
+#[cfg(any(not(feature = "zstd"), not(feature = "lz4")))]
+fn needs_feature(name: &str) -> DecodeError {
+    DecodeError::OtherString(format!(
+        "compressed with {}, it needs the cargo feature of the same name",
+        name
+    ))
+}
+
+bincode::impl_borrow_decode!(Compression);
+
+impl Compression {
+    /// Decompresses a compressed snapshot after its header
+    pub fn decompress_stream<R: Read>(&self, mut reader: R) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        match self {
+            Compression::None => reader.read_to_end(&mut data),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { .. } => zstd::Decoder::new(reader)?.read_to_end(&mut data),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => lz4_flex::frame::FrameDecoder::new(reader).read_to_end(&mut data),
+        }?;
+        Ok(data)
+    }
+
+    pub(crate) fn writer<W: Write>(&self, writer: W) -> io::Result<CompressWriter<W>> {
+        match self {
+            Compression::None => Ok(CompressWriter::None(writer)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { level } => {
+                Ok(CompressWriter::Zstd(zstd::Encoder::new(writer, *level)?))
+            }
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(CompressWriter::Lz4(lz4_flex::frame::FrameEncoder::new(
+                writer,
+            ))),
+        }
+    }
+}
+
+/// Compresses the records of one journal, each on its own with the dictionary of the journal header
+///
+/// A compressed record is `[length: varint][compressed]` with the length before compression,
+/// or `[0][record]` when compression doesn't make the record smaller
+pub(crate) enum RecordCompressor {
+    None,
+    #[cfg(feature = "zstd")]
+    Zstd(
+        zstd::bulk::Compressor<'static>,
+        zstd::bulk::Decompressor<'static>,
+    ),
+    #[cfg(feature = "lz4")]
+    Lz4 {
+        dictionary: Vec<u8>,
+    },
+}
+
+impl RecordCompressor {
+    #[cfg_attr(not(any(feature = "zstd", feature = "lz4")), allow(unused_variables))]
+    pub(crate) fn new(compression: Compression, dictionary: Vec<u8>) -> io::Result<Self> {
+        match compression {
+            Compression::None => Ok(RecordCompressor::None),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { level } => {
+                use zstd::zstd_safe::CParameter;
+
+                // The length is in front of the record and the dictionary in the header
+                let mut compressor = zstd::bulk::Compressor::with_dictionary(level, &dictionary)?;
+                compressor.set_parameter(CParameter::ContentSizeFlag(false))?;
+                compressor.set_parameter(CParameter::DictIdFlag(false))?;
+                Ok(RecordCompressor::Zstd(
+                    compressor,
+                    zstd::bulk::Decompressor::with_dictionary(&dictionary)?,
+                ))
+            }
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(RecordCompressor::Lz4 { dictionary }),
+        }
+    }
+
+    pub(crate) fn is_none(&self) -> bool {
+        matches!(self, RecordCompressor::None)
+    }
+
+    pub(crate) fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            RecordCompressor::None => Ok(data.to_vec()),
+            #[cfg(feature = "zstd")]
+            RecordCompressor::Zstd(compressor, _) => {
+                Ok(stored_if_larger(data, compressor.compress(data)?))
+            }
+            #[cfg(feature = "lz4")]
+            RecordCompressor::Lz4 { dictionary } => Ok(stored_if_larger(
+                data,
+                lz4_flex::block::compress_with_dict(data, dictionary),
+            )),
+        }
+    }
+
+    pub(crate) fn decompress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            RecordCompressor::None => Ok(data.to_vec()),
+            #[cfg(feature = "zstd")]
+            RecordCompressor::Zstd(_, decompressor) => unstore(data, |compressed, length| {
+                decompressor.decompress(compressed, length)
+            }),
+            #[cfg(feature = "lz4")]
+            RecordCompressor::Lz4 { dictionary } => unstore(data, |compressed, length| {
+                lz4_flex::block::decompress_with_dict(compressed, length, dictionary)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }),
+        }
+    }
+}
+
+/// Frames a compressed record, see [`RecordCompressor`]
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+fn stored_if_larger(data: &[u8], compressed: Vec<u8>) -> Vec<u8> {
+    let mut record = Vec::with_capacity(compressed.len().min(data.len()) + 10);
+    write_varint(&mut record, data.len() as u64);
+    match record.len() + compressed.len() < 1 + data.len() {
+        true => record.extend_from_slice(&compressed),
+        false => {
+            record.clear();
+            write_varint(&mut record, 0);
+            record.extend_from_slice(data);
+        }
+    }
+    record
+}
+
+/// Reads a record framed by [`stored_if_larger`], `decompress` gets the compressed data and its length before compression
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+fn unstore(
+    mut record: &[u8],
+    decompress: impl FnOnce(&[u8], usize) -> io::Result<Vec<u8>>,
+) -> io::Result<Vec<u8>> {
+    let (length, _) = read_varint(&mut record)?;
+    if length == 0 {
+        return Ok(record.to_vec());
+    }
+    let data = decompress(record, length as usize)?;
+    match data.len() as u64 == length {
+        true => Ok(data),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "decompressed record has the wrong length",
+        )),
+    }
+}
+
+/// Trains a dictionary for the records of a journal on `samples`, records of the previous journal.
+/// Empty without compression or with too few samples
+pub(crate) fn train_dictionary(compression: Compression, samples: &[Vec<u8>]) -> Vec<u8> {
+    if compression == Compression::None || samples.len() < DICTIONARY_MIN_SAMPLES {
+        return Vec::new();
+    }
+
+    #[cfg(feature = "zstd")]
+    if let Compression::Zstd { .. } = compression {
+        if let Ok(dictionary) = zstd::dict::from_samples(samples, DICTIONARY_LEN) {
+            return dictionary;
+        }
+    }
+
+    // The most recent records as raw content, zstd and lz4 both look for matches in it
+    let mut dictionary: Vec<u8> = samples.concat();
+    dictionary.drain(..dictionary.len().saturating_sub(DICTIONARY_LEN));
+    dictionary
+}
+
+/// Streaming compressor, [`CompressWriter::finish`] must be called to complete the stream
+pub(crate) enum CompressWriter<W: Write> {
+    None(W),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, W>),
+    #[cfg(feature = "lz4")]
+    Lz4(lz4_flex::frame::FrameEncoder<W>),
+}
+
+impl<W: Write> CompressWriter<W> {
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self {
+            CompressWriter::None(writer) => Ok(writer),
+            #[cfg(feature = "zstd")]
+            CompressWriter::Zstd(encoder) => encoder.finish(),
+            #[cfg(feature = "lz4")]
+            CompressWriter::Lz4(encoder) => encoder.finish().map_err(io::Error::other),
+        }
+    }
+}
+
+impl<W: Write> Write for CompressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CompressWriter::None(writer) => writer.write(buf),
+            #[cfg(feature = "zstd")]
+            CompressWriter::Zstd(encoder) => encoder.write(buf),
+            #[cfg(feature = "lz4")]
+            CompressWriter::Lz4(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CompressWriter::None(writer) => writer.flush(),
+            #[cfg(feature = "zstd")]
+            CompressWriter::Zstd(encoder) => encoder.flush(),
+            #[cfg(feature = "lz4")]
+            CompressWriter::Lz4(encoder) => encoder.flush(),
+        }
+    }
+}
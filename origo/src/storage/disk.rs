@@ -1,199 +1,711 @@
 use crate::{
-    engine::{Command, CommandRestoreFn},
-    storage::Storage,
+    codec::{Codec, BINCODE_CODEC},
+    context::{CommandMeta, Envelope},
+    engine::{Command, CommandRestoreFn, CompactionKeyFn},
+    restore::{observe_snapshot, ReplayProgress, RestoreObserver},
+    state::EngineState,
+    storage::{
+        archive::{write_durable, Archiver},
+        compaction::{
+            list_segments, remove_segments, segment_chain, segment_name, Compactor, Rewrite,
+            Segment, SEGMENT_DIRECTORY,
+        },
+        compression::{train_dictionary, RecordCompressor, DICTIONARY_SAMPLES},
+        encryption::{Cipher, EncryptWriter},
+        format::model_id,
+        journal::{
+            command_ids, encode_record_data, frame_record, record_compressor, seal_dictionary,
+            write_command_id,
+        },
+        replay::replay_records,
+        writer::Writer,
+        BackupFiles, BackupRole, Compression, DirectoryLock, FileHeader, FormatError, JournalError,
+        JournalReader, JournalWriter, KeyProvider, ObjectStore, Storage, ARCHIVE_DIRECTORY,
+        BINCODE_CONFIG, FORMAT_VERSION, JOURNAL_MAGIC, SNAPSHOT_FILE, SNAPSHOT_MAGIC,
+    },
 };
 
-use bincode::{config::Configuration, Decode};
+#[cfg(feature = "fault-injection")]
+use crate::storage::{faulty::SimulatedFile, Faults};
 
 use core::panic;
+use memmap2::Mmap;
+use parking_lot::Mutex;
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
     fs::File,
-    io::{BufReader, BufWriter, ErrorKind, Read, Seek, Write},
-    os::unix::prelude::FileExt,
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, Write},
     path::{Path, PathBuf},
-    time::Instant,
+    sync::{atomic::AtomicU64, atomic::Ordering, Arc},
+    time::{Instant, UNIX_EPOCH},
 };
 
 pub struct DiskStorage {
     journal_file: File,
+    journal_path: PathBuf,
     directory: std::path::PathBuf,
-    buf_writer: BufWriter<File>,
+    writer: Writer,
+    journal_writer: JournalWriter,
     command_count_current: u64,
     commit_buffer: Vec<u8>,
+    /// Compression of new journals and snapshots
+    compression: Compression,
+    /// Compressor of the current journal, with the dictionary from its header
+    journal_compressor: RecordCompressor,
+    /// The most recent records before compression, the dictionary of the next journal is trained on them
+    samples: VecDeque<Vec<u8>>,
+    /// Keys to encrypt new journals and snapshots with and to decrypt existing files
+    keys: Option<Arc<dyn KeyProvider>>,
+    /// Cipher of the current journal, from its header
+    journal_cipher: Option<Cipher>,
+    /// Names of the registered commands, the command table of new journals
+    commands: Vec<String>,
+    /// Command ids of the current journal
+    command_ids: HashMap<String, u64>,
+    /// Uploads snapshots and sealed journals, `None` without archive
+    archiver: Option<Archiver>,
+    /// Incremented when the journal is truncated, a backup copying the journal checks it didn't change
+    journal_resets: Arc<AtomicU64>,
+    /// Seal the journal into segments instead of writing snapshots, see [`DiskStorage::compact_journal`]
+    compact_journal: bool,
+    /// Handed to the compactor when it's started on restore
+    compaction_key_fns: HashMap<String, CompactionKeyFn>,
+    /// Merges the sealed segments, `None` without journal compaction
+    compactor: Option<Compactor>,
+    /// Held while segments are removed or listed for a backup
+    segments_lock: Arc<Mutex<()>>,
+    /// Receives the progress of the restore, see [`crate::EngineBuilder::restore_observer`]
+    restore_observer: Option<Arc<dyn RestoreObserver>>,
+    /// Id of the model in the file headers, see [`crate::EngineBuilder::model_id`]
+    model_id: Option<String>,
+    /// Decode workers of the replay, see [`DiskStorage::replay_workers`]
+    replay_workers: Option<usize>,
+    /// Applied by the journal file, see [`DiskStorage::faults`]
+    #[cfg(feature = "fault-injection")]
+    faults: Option<Faults>,
+    /// Held for as long as the storage lives
+    _lock: DirectoryLock,
 }
 
 const BUFFER_CAPACITY: usize = 32 * 1024;
-static BINCODE_CONFIG: Configuration = bincode::config::standard();
 
 impl DiskStorage {
+    /// Opens (or creates) the journal at `path`, snapshots are stored in the same directory
+    ///
+    /// # Panics
+    ///
+    /// Panics if the journal can't be opened or the directory is locked by another process,
+    /// only one engine at a time can use a data directory. The [`DirectoryLock`] is held until the
+    /// storage is dropped, `origo-tool repair` refuses to change a locked directory
     pub fn new<T: AsRef<Path>>(path: T) -> Self {
-        let journal_file = match path.as_ref().exists() {
-            true => {
-                let file = File::options()
-                    .read(true)
-                    .write(true)
-                    .open(path.as_ref())
-                    .expect("Failed to open journal");
-                file
-            }
-            false => {
-                if let Some(directory) = path.as_ref().parent() {
-                    std::fs::create_dir_all(directory)
-                        .expect("Failed to create directory structure");
-                }
-                let mut file = File::create(path.as_ref()).expect("Failed to create journal-file");
-                file.write_all(&[0u8; 8])
-                    .expect("Failed to initialize journal");
-                file
-            }
+        let directory = path.as_ref().parent().unwrap_or(Path::new("")).to_owned();
+        std::fs::create_dir_all(&directory).expect("Failed to create directory structure");
+
+        let lock = match DirectoryLock::try_lock(&directory) {
+            Ok(lock) => lock,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => panic!(
+                "Data directory {:?} is already in use by another process{}",
+                directory,
+                DirectoryLock::holder(&directory)
+                    .map(|pid| format!(" (pid {})", pid))
+                    .unwrap_or_default()
+            ),
+            Err(e) => panic!("Failed to lock data directory {:?}, {:?}", directory, e),
         };
 
+        // The header is written on restore, when the model type is known
+        let journal_file = open_journal(path.as_ref());
+
         DiskStorage {
-            directory: path.as_ref().parent().unwrap_or(Path::new("")).to_owned(),
-            buf_writer: BufWriter::with_capacity(
-                BUFFER_CAPACITY,
-                journal_file.try_clone().unwrap(),
-            ),
+            directory,
+            journal_path: path.as_ref().to_owned(),
+            writer: Writer::new(JournalWriter::Buffered, &journal_file)
+                .expect("Failed to create journal writer"),
+            journal_writer: JournalWriter::Buffered,
             journal_file,
             command_count_current: 0,
             commit_buffer: Vec::<u8>::with_capacity(BUFFER_CAPACITY),
+            compression: Compression::None,
+            journal_compressor: RecordCompressor::None,
+            samples: VecDeque::new(),
+            keys: None,
+            journal_cipher: None,
+            commands: Vec::new(),
+            command_ids: HashMap::new(),
+            archiver: None,
+            journal_resets: Arc::new(AtomicU64::new(0)),
+            compact_journal: false,
+            compaction_key_fns: HashMap::new(),
+            compactor: None,
+            segments_lock: Arc::new(Mutex::new(())),
+            restore_observer: None,
+            model_id: None,
+            replay_workers: None,
+            #[cfg(feature = "fault-injection")]
+            faults: None,
+            _lock: lock,
+        }
+    }
+
+    /// Compresses journal records and snapshots, off by default
+    ///
+    /// Applies to snapshots and journals written from now on, an existing journal keeps
+    /// the compression in its header until the next snapshot. Files are read with the
+    /// compression in their header, so it can be changed between restarts.
+    /// Each journal gets a dictionary trained on the last records of the previous one
+    #[cfg(any(feature = "zstd", feature = "lz4"))]
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// How journal records are written, [`JournalWriter::Buffered`] by default
+    pub fn journal_writer(mut self, journal_writer: JournalWriter) -> Self {
+        self.journal_writer = journal_writer;
+        self.writer = self.create_writer();
+        self
+    }
+
+    /// Threads that decrypt, decompress and decode the records of the journal on restore,
+    /// one less than the available parallelism by default. The records are applied in journal order
+    ///
+    /// # Panics
+    ///
+    /// Panics if `workers` is 0
+    pub fn replay_workers(mut self, workers: usize) -> Self {
+        assert!(workers > 0, "Replay needs at least one worker");
+        self.replay_workers = Some(workers);
+        self
+    }
+
+    /// Writes the journal through a simulated file that applies the [`super::Fault::TornWrite`] and
+    /// [`super::Fault::FailSync`] of `faults`, a write or sync fails and loses the unsynced end of
+    /// the journal. Needs the `fault-injection` feature, see [`crate::storage::FaultyStorage`]
+    #[cfg(feature = "fault-injection")]
+    pub fn faults(mut self, faults: Faults) -> Self {
+        self.faults = Some(faults);
+        self.writer = self.create_writer();
+        self
+    }
+
+    fn create_writer(&self) -> Writer {
+        let writer = Writer::new(self.journal_writer, &self.journal_file)
+            .expect("Failed to create journal writer");
+        #[cfg(feature = "fault-injection")]
+        if let Some(faults) = &self.faults {
+            let file = SimulatedFile::new(writer, &self.journal_file, faults.clone())
+                .expect("Failed to create journal writer");
+            return Writer::Simulated(Box::new(file));
         }
+        writer
+    }
+
+    /// Encrypts journal records and snapshots with the current key of `keys`, needs the `encryption` feature
+    ///
+    /// Like compression it applies to files written from now on, an unencrypted journal
+    /// is encrypted after the next snapshot. Encrypted files need the key they were written with
+    #[cfg(feature = "encryption")]
+    pub fn encryption<K: KeyProvider>(mut self, keys: K) -> Self {
+        self.keys = Some(Arc::new(keys));
+        self
     }
 
-    fn replay_journal<TModel: Default + Decode>(
+    /// Archives every snapshot and the journal it seals to `store`, see [`crate::storage::restore_archive`]
+    ///
+    /// Files are staged in the `archive` directory next to the journal and uploaded in the background,
+    /// failed uploads are retried. Dropping the storage waits up to [`crate::storage::ARCHIVE_STOP_TIMEOUT`]
+    /// for the staged files to be uploaded, the rest is uploaded after the next start
+    pub fn archive<O: ObjectStore>(mut self, store: O) -> Self {
+        self.archiver = Some(Archiver::start(
+            self.directory.join(ARCHIVE_DIRECTORY),
+            Box::new(store),
+        ));
+        self
+    }
+
+    /// Seals the journal into a segment instead of writing a snapshot, and merges the sealed segments
+    /// in the background keeping only the latest command of each [`crate::Command::compaction_key`]
+    ///
+    /// A log-structured alternative to snapshots for models that are expensive to serialize but whose commands
+    /// mostly overwrite the same keys. The journal is sealed at [`crate::Engine::snapshot_command_count`],
+    /// the segments are kept in the `segments` directory next to the journal and replayed on restore before the journal.
+    /// Keyed commands must be pure overwrites, see [`crate::Command::compaction_key`].
+    /// Dropped commands with an idempotency key are kept as a [`crate::storage::TOMBSTONE_COMMAND`] with only their envelope
+    pub fn compact_journal(mut self) -> Self {
+        self.compact_journal = true;
+        self
+    }
+
+    fn model<TModel>(&self) -> &str {
+        model_id::<TModel>(self.model_id.as_deref())
+    }
+
+    /// Cipher with the current key, `None` without encryption
+    fn current_cipher(&self) -> Option<Cipher> {
+        self.keys.as_ref().map(|keys| {
+            let (key_id, key) = keys.current();
+            Cipher::new(key_id, &key).unwrap_or_else(|e| panic!("Failed to encrypt, {}", e))
+        })
+    }
+
+    fn journal_reader(&mut self) -> JournalReader<BufReader<File>> {
+        let file_len = self.journal_file.metadata().unwrap().len();
+        let mut file = self
+            .journal_file
+            .try_clone()
+            .expect("Failed to clone journal handle");
+        file.rewind().expect("Failed to rewind journal");
+
+        JournalReader::new(BufReader::with_capacity(BUFFER_CAPACITY, file), file_len)
+            .and_then(|reader| reader.with_keys(self.keys.as_deref()))
+            .unwrap_or_else(|e| panic!("Failed to read journal {:?}, {}", self.journal_path, e))
+    }
+
+    /// Truncates the journal to a header for the journal starting at `start_sequence`
+    fn reset_journal<TModel>(&mut self, codec: &str, start_sequence: u64) {
+        self.journal_resets.fetch_add(1, Ordering::AcqRel);
+        self.writer.release();
+        self.journal_file
+            .set_len(0)
+            .expect("Failed to reset journal length");
+        self.journal_file
+            .rewind()
+            .expect("Failed to rewind journal");
+
+        let cipher = self.current_cipher();
+        let encryption = cipher.as_ref().map(Cipher::encryption);
+        let mut header = FileHeader::new(
+            self.model::<TModel>(),
+            codec,
+            self.compression,
+            encryption,
+            self.commands.clone(),
+            start_sequence,
+        );
+        let dictionary = train_dictionary(self.compression, self.samples.make_contiguous());
+        header.dictionary = seal_dictionary(dictionary, cipher.as_ref())
+            .unwrap_or_else(|e| panic!("Failed to encrypt dictionary, {}", e));
+        let header_len = header
+            .write_to(&JOURNAL_MAGIC, &mut self.journal_file)
+            .expect("Failed to write journal header");
+        self.journal_file
+            .sync_all()
+            .expect("Failed to sync journal to disk");
+
+        self.journal_compressor = record_compressor(&header, cipher.as_ref())
+            .unwrap_or_else(|e| panic!("Failed to create compressor, {}", e));
+        self.journal_cipher = cipher;
+        self.command_ids = command_ids(&self.commands);
+        self.writer
+            .reset(header_len)
+            .expect("Failed to reset writer");
+        self.command_count_current = 0;
+    }
+
+    /// Rewrites a journal without header, adds the header and an envelope to every record
+    ///
+    /// The envelopes get consecutive sequences from `start_sequence`,
+    /// the modification time of the journal as timestamp and seed 0
+    fn upgrade_journal<TModel, C: Codec<TModel>>(
         &mut self,
+        reader: JournalReader<BufReader<File>>,
+        start_sequence: u64,
+    ) {
+        assert!(
+            C::NAME == BINCODE_CODEC,
+            "Journal {:?} has no header, journals without header are bincode but the engine uses {}",
+            self.journal_path,
+            C::NAME
+        );
+
+        let timestamp = self
+            .journal_file
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |modified| modified.as_millis() as u64);
+
+        let mut upgrade_path = self.journal_path.clone().into_os_string();
+        upgrade_path.push(".upgrade");
+        let upgrade_file = File::create(&upgrade_path).expect("Failed to create upgraded journal");
+        let mut writer = BufWriter::with_capacity(BUFFER_CAPACITY, &upgrade_file);
+
+        let count = reader
+            .header_count()
+            .expect("Journals without header have a record count");
+        let ids = command_ids(&self.commands);
+        FileHeader::new(
+            self.model::<TModel>(),
+            C::NAME,
+            Compression::None,
+            None,
+            self.commands.clone(),
+            start_sequence,
+        )
+        .write_to(&JOURNAL_MAGIC, &mut writer)
+        .expect("Failed to write upgraded journal");
+
+        let mut upgraded = 0;
+        let mut buffer = Vec::new();
+        for record in reader.take(count as usize) {
+            let record = record.unwrap_or_else(|e| panic!("Failed to upgrade journal, {}", e));
+            let envelope = Envelope {
+                sequence: start_sequence + upgraded,
+                timestamp,
+                seed: 0,
+                idempotency_key: None,
+                meta: CommandMeta::default(),
+            };
+            buffer.clear();
+            write_command_id(&mut buffer, &ids, &record.name);
+            bincode::encode_into_std_write(&envelope, &mut buffer, BINCODE_CONFIG)
+                .expect("Failed to serialize envelope to bytes");
+            buffer.extend_from_slice(&record.data);
+
+            frame_record(&mut buffer);
+            writer
+                .write_all(&buffer)
+                .expect("Failed to write upgraded journal");
+            upgraded += 1;
+        }
+
+        assert!(
+            upgraded == count,
+            "Failed to upgrade journal, it ended after {} of {} records",
+            upgraded,
+            count
+        );
+
+        writer.flush().expect("Failed to flush upgraded journal");
+        drop(writer);
+        upgrade_file
+            .sync_all()
+            .expect("Failed to sync upgraded journal");
+        std::fs::rename(&upgrade_path, &self.journal_path)
+            .expect("Failed to replace journal with the upgraded journal");
+
+        self.journal_file = open_journal(&self.journal_path);
+        self.writer = self.create_writer();
+
+        log::info!(
+            "Upgraded journal {:?} to format version {}",
+            self.journal_path,
+            FORMAT_VERSION
+        );
+    }
+
+    /// Sequence of the last command in the segment `chain` and the journal after the snapshot up to `sequence`,
+    /// the journal records are counted by their lengths
+    fn last_sequence(&mut self, chain: &[Segment], sequence: u64) -> u64 {
+        let sequence = chain
+            .last()
+            .map_or(sequence, |segment| segment.end.max(sequence));
+        let reader = self.journal_reader();
+        let start_sequence = match reader.header() {
+            Some(header) => header.start_sequence,
+            None => sequence + 1,
+        };
+        let count = reader
+            .count_records()
+            .unwrap_or_else(|e| panic!("Failed to read journal {:?}, {}", self.journal_path, e));
+        (start_sequence + count).saturating_sub(1).max(sequence)
+    }
+
+    /// Replays the `chain` of sealed segments that continues the snapshot, removes the segments it contains
+    fn replay_segments<TModel: Default, C: Codec<TModel>>(
+        &mut self,
+        chain: &[Segment],
         model: &mut TModel,
         restore_fns: &HashMap<String, CommandRestoreFn<TModel>>,
+        state: &mut EngineState,
+        progress: &mut ReplayProgress,
     ) {
-        let file_len = self.journal_file.metadata().unwrap().len();
+        let directory = self.directory.join(SEGMENT_DIRECTORY);
+        let snapshot_sequence = state.sequence();
+
+        for segment in chain {
+            let mut reader = JournalReader::open(&segment.path)
+                .and_then(|reader| reader.with_keys(self.keys.as_deref()))
+                .unwrap_or_else(|e| panic!("Failed to read segment {:?}, {}", segment.path, e));
+            let header = reader.header().expect("Segment has no header");
+            if let Err(e) = header.check(self.model::<TModel>(), C::NAME) {
+                panic!("Segment {:?} can't be restored, {}", segment.path, e);
+            }
 
-        if file_len <= 8 {
+            let len = reader.journal_len();
+            let decoder = reader.raw();
+            let (_, error) = replay_records(
+                reader,
+                &decoder,
+                self.replay_workers,
+                restore_fns,
+                state,
+                model,
+                progress,
+            );
+            if let Some(e) = error {
+                panic!("Segment {:?} can't be restored, {}", segment.path, e);
+            }
+            progress.file_finished(len);
+            state.skip_to(segment.end);
+            log::debug!("Loaded journal segment {}..={}", segment.start, segment.end);
+        }
+
+        // Segments contained in the snapshot or in a longer segment of the chain
+        remove_segments(&directory, &self.segments_lock, |segment| {
+            segment.end <= snapshot_sequence
+                || chain.iter().any(|replayed| {
+                    replayed.path != segment.path
+                        && replayed.start <= segment.start
+                        && segment.end <= replayed.end
+                })
+        })
+        .unwrap_or_else(|e| panic!("Failed to remove journal segments, {}", e));
+    }
+
+    /// Copies the journal up to the last commit into a segment and resets it
+    fn seal_journal<TModel>(&mut self, codec: &str, state: &EngineState) {
+        if self.command_count_current == 0 {
             return;
         }
 
-        let mut entries = [0u8; 8];
-        self.journal_file
-            .read_exact(&mut entries)
-            .expect("failed to read entries count");
+        let len = self
+            .writer
+            .position()
+            .expect("Failed to find end of journal");
+        let start = state.sequence() + 1 - self.command_count_current;
+        if let Some(archiver) = &self.archiver {
+            archiver.stage_segment(&self.journal_path, len, start, state.sequence());
+        }
 
-        let entries_count = u64::from_le_bytes(entries);
-        log::debug!("Loading {} events from journal", &entries_count);
+        let directory = self.directory.join(SEGMENT_DIRECTORY);
+        let segment_path = directory.join(segment_name(start, state.sequence()));
+        std::fs::create_dir_all(&directory)
+            .and_then(|_| File::open(&self.journal_path))
+            .and_then(|journal| {
+                write_durable(&segment_path, |file| {
+                    io::copy(&mut journal.take(len), file).map(|_| ())
+                })
+            })
+            .and_then(|_| sync_directory(&segment_path))
+            .unwrap_or_else(|e| panic!("Failed to seal journal into {:?}, {}", segment_path, e));
 
-        let mut reader = BufReader::new(&self.journal_file);
+        self.reset_journal::<TModel>(codec, state.sequence() + 1);
+        if let Some(archiver) = &self.archiver {
+            archiver.wake();
+        }
+        if let Some(compactor) = &self.compactor {
+            compactor.wake();
+        }
+    }
 
-        let mut len_header = [0u8; 8];
-        let mut name_len_header = [0u8; 8];
-        let mut data = vec![0u8; BUFFER_CAPACITY];
+    fn replay_journal<TModel: Default, C: Codec<TModel>>(
+        &mut self,
+        model: &mut TModel,
+        restore_fns: &HashMap<String, CommandRestoreFn<TModel>>,
+        state: &mut EngineState,
+        progress: &mut ReplayProgress,
+    ) {
+        let start_sequence = state.sequence() + 1;
 
-        for i in 0..entries_count {
-            match reader.read_exact(&mut len_header) {
-                Ok(_) => {
-                    let data_len = u64::from_le_bytes(len_header) as usize;
-                    data.resize(data_len, 0);
-                }
-                Err(e) if e.kind() == ErrorKind::UnexpectedEof => panic!("{:?}", i),
-                Err(e) => panic!("Journal restore failed, {:?}", e),
-            }
+        let mut reader = self.journal_reader();
+        if reader.journal_len() == 0 {
+            self.reset_journal::<TModel>(C::NAME, start_sequence);
+            return;
+        }
 
-            let command_name_length = match reader.read_exact(&mut name_len_header) {
-                Ok(_) => u64::from_le_bytes(name_len_header) as usize,
-                Err(e) => panic!("Journal restore failed, {:?}", e),
-            };
+        if reader.header().is_none() {
+            self.upgrade_journal::<TModel, C>(reader, start_sequence);
+            reader = self.journal_reader();
+        }
 
-            match reader.read_exact(&mut data) {
-                Ok(_) => {
-                    let command_name = std::str::from_utf8(&data[..command_name_length])
-                        .unwrap_or_else(|_| {
-                            panic!(
-                                "Failed to parse command({}) name bytes to utf8",
-                                self.command_count_current
-                            );
-                        });
-
-                    let restore_fn = restore_fns.get(command_name).unwrap_or_else(|| {
-                        panic!("No restore registered for command {}", command_name)
-                    });
-
-                    match restore_fn(&data[command_name_length..], model, BINCODE_CONFIG) {
-                        true => self.command_count_current += 1,
-                        false => panic!(
-                            "Corrupt journal, failed to restore {}({})",
-                            command_name, self.command_count_current
-                        ),
-                    }
-                }
-                Err(e) => panic!("Journal restore failed, {:?}", e),
+        let header = reader.header().expect("Journal has no header");
+        if let Err(e) = header.check(self.model::<TModel>(), C::NAME) {
+            panic!("Journal {:?} can't be restored, {}", self.journal_path, e);
+        }
+
+        if header.start_sequence < start_sequence {
+            // The snapshot was written or the journal sealed, but the journal wasn't reset
+            log::warn!(
+                "Journal {:?} starts at {} and is contained in the snapshot or a sealed segment, discarding it",
+                self.journal_path,
+                header.start_sequence
+            );
+            self.reset_journal::<TModel>(C::NAME, start_sequence);
+            return;
+        }
+
+        assert!(
+            header.start_sequence == start_sequence,
+            "Journal {:?} starts at {} but the snapshot ends at {}, commands are missing",
+            self.journal_path,
+            header.start_sequence,
+            start_sequence - 1
+        );
+
+        let journal_command_ids = command_ids(&header.commands);
+        let journal_cipher = header.encryption.map(|encryption| match &self.keys {
+            Some(keys) => Cipher::for_file(keys.as_ref(), encryption).unwrap_or_else(|e| {
+                panic!("Journal {:?} can't be decrypted, {}", self.journal_path, e)
+            }),
+            None => panic!(
+                "Journal {:?} is encrypted with key {} but the storage has no keys",
+                self.journal_path, encryption.key_id
+            ),
+        });
+        let journal_compressor =
+            record_compressor(header, journal_cipher.as_ref()).unwrap_or_else(|e| {
+                panic!(
+                    "Journal {:?} can't be decompressed, {}",
+                    self.journal_path, e
+                )
+            });
+        log::debug!("Loading events from journal");
+
+        let decoder = reader.raw();
+        let (replayed, error) = replay_records(
+            &mut reader,
+            &decoder,
+            self.replay_workers,
+            restore_fns,
+            state,
+            model,
+            progress,
+        );
+        self.command_count_current = replayed;
+
+        let mut torn = false;
+        match error {
+            // The last record was being written when the process stopped, it was never committed
+            Some(JournalError::Truncated { offset } | JournalError::Torn { offset }) => {
+                log::warn!(
+                    "Journal {:?} ends with an incomplete record at offset {}, discarding it",
+                    self.journal_path,
+                    offset
+                );
+                torn = true;
             }
+            Some(e) => panic!("Journal restore failed, {}", e),
+            None => {}
         }
+
+        // New records are appended after the last replayed one
+        self.journal_compressor = journal_compressor;
+        self.journal_cipher = journal_cipher;
+        self.command_ids = journal_command_ids;
+        if torn {
+            self.writer.release();
+            self.journal_file
+                .set_len(reader.offset())
+                .expect("Failed to discard incomplete record");
+        }
+        self.writer
+            .reset(reader.offset())
+            .expect("Failed to seek to end of journal");
     }
 }
 
-///
-///
-///
+fn open_journal(path: &Path) -> File {
+    File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .expect("Failed to open journal")
+}
+
 impl Storage for DiskStorage {
-    fn prepare<TModel, T: Command<TModel>>(&mut self, name: &str, command: &T) {
+    fn prepare<TModel, C: Codec<T>, T: Command<TModel>>(
+        &mut self,
+        envelope: &Envelope,
+        name: &str,
+        command: &T,
+    ) {
         self.commit_buffer.clear();
-        self.commit_buffer.write_all(&[0u8; 8]).expect(""); // reserve space for total length header
+        write_command_id(&mut self.commit_buffer, &self.command_ids, name);
 
-        let name_bytes = name.as_bytes();
-        self.commit_buffer
-            .write_all(&(name_bytes.len() as u64).to_le_bytes())
-            .expect("Failed to write name len");
+        let data_offset = self.commit_buffer.len();
+        bincode::encode_into_std_write(envelope, &mut self.commit_buffer, BINCODE_CONFIG)
+            .expect("Failed to serialize envelope to bytes");
 
-        self.commit_buffer
-            .write_all(name_bytes)
-            .expect("Failed to write identifier");
+        C::encode(command, &mut self.commit_buffer)
+            .unwrap_or_else(|e| panic!("Failed to serialize command to bytes, {}", e));
 
-        let mut len = name_bytes.len();
+        if self.compression != Compression::None {
+            if self.samples.len() == DICTIONARY_SAMPLES {
+                self.samples.pop_front();
+            }
+            self.samples
+                .push_back(self.commit_buffer[data_offset..].to_vec());
+        }
 
-        len += bincode::encode_into_std_write(command, &mut self.commit_buffer, BINCODE_CONFIG)
-            .expect("Failed to serialize command to bytes");
+        encode_record_data(
+            &mut self.commit_buffer,
+            data_offset,
+            &mut self.journal_compressor,
+            self.journal_cipher.as_ref(),
+            envelope.sequence,
+            name,
+        )
+        .unwrap_or_else(|e| panic!("Failed to compress or encrypt command, {}", e));
 
-        self.commit_buffer[..8].copy_from_slice(&(len as u64).to_le_bytes());
+        frame_record(&mut self.commit_buffer);
     }
 
     fn commit(&mut self) -> u64 {
-        self.buf_writer
-            .write_all(&self.commit_buffer)
+        self.writer
+            .append(&self.commit_buffer)
             .expect("Failed to commit command");
 
-        self.buf_writer.flush().expect("Failed to flush commit");
-        self.journal_file.sync_all().expect("Sync to disk failed");
+        self.writer.sync().expect("Sync to disk failed");
         self.command_count_current += 1;
-
-        self.journal_file
-            .write_all_at(&u64::to_le_bytes(self.command_count_current), 0)
-            .expect("msg");
-
         self.command_count_current
     }
 
-    fn snapshot<TModel: bincode::Encode>(&mut self, model: &TModel) {
-        let snapshot_path = self.directory.join("snap.origors");
-        match snapshot_write(&snapshot_path, model) {
+    fn snapshot<TModel, C: Codec<TModel>>(&mut self, state: &EngineState, model: &TModel) {
+        if self.compact_journal {
+            self.seal_journal::<TModel>(C::NAME, state);
+            return;
+        }
+        let snapshot_path = self.directory.join(SNAPSHOT_FILE);
+
+        // The journal is sealed before the snapshot, a crash in between only stages it again
+        if let (Some(archiver), 1..) = (&self.archiver, self.command_count_current) {
+            let len = self
+                .writer
+                .position()
+                .expect("Failed to find end of journal");
+            let start = state.sequence() + 1 - self.command_count_current;
+            archiver.stage_segment(&self.journal_path, len, start, state.sequence());
+        }
+
+        let cipher = self.current_cipher();
+        match snapshot_write::<TModel, C>(
+            &snapshot_path,
+            self.model::<TModel>(),
+            self.compression,
+            cipher.as_ref(),
+            state,
+            model,
+        ) {
             Ok(_) => {
-                self.journal_file
-                    .set_len(0)
-                    .expect("Failed to reset journal length");
-                self.journal_file
-                    .rewind()
-                    .expect("Failed to rewind journal");
-                self.journal_file
-                    .write_all(&[0u8; 8])
-                    .expect("Failed to reset journal header");
-                self.journal_file
-                    .flush()
-                    .expect("Failed to flush journal to disk");
-                self.journal_file
-                    .sync_all()
-                    .expect("Failed to sync journal to disk");
-
-                self.buf_writer
-                    .seek(std::io::SeekFrom::Start(8))
-                    .expect("Failed to reset writer");
-                self.command_count_current = 0;
+                if let Some(archiver) = &self.archiver {
+                    archiver.stage_snapshot(&snapshot_path, state.sequence());
+                }
+                self.reset_journal::<TModel>(C::NAME, state.sequence() + 1);
+                if let Some(archiver) = &self.archiver {
+                    archiver.wake();
+                }
+                // Segments from before compaction was turned off
+                remove_segments(
+                    &self.directory.join(SEGMENT_DIRECTORY),
+                    &self.segments_lock,
+                    |segment| segment.end <= state.sequence(),
+                )
+                .unwrap_or_else(|e| panic!("Failed to remove journal segments, {}", e));
             }
             Err(_) => {
                 panic!("Snapshot write to disk failed");
@@ -201,77 +713,328 @@ impl Storage for DiskStorage {
         }
     }
 
-    fn restore<TModel: Default + bincode::Decode>(
+    fn restore<TModel: Default, C: Codec<TModel>>(
         &mut self,
         restore_fns: &HashMap<String, CommandRestoreFn<TModel>>,
+        state: &mut EngineState,
+        snapshot_loaded: &mut dyn FnMut(&EngineState, &TModel),
     ) -> TModel {
-        let snapshot_path = self.directory.join("snap.origors");
+        let snapshot_path = self.directory.join(SNAPSHOT_FILE);
+        self.commands = restore_fns.keys().cloned().collect();
+        self.commands.sort();
 
         let mut model = match snapshot_path.exists() {
-            true => snapshot_read(&snapshot_path),
+            true => observe_snapshot(self.restore_observer.as_deref(), || {
+                snapshot_read::<TModel, C>(
+                    &snapshot_path,
+                    self.model::<TModel>(),
+                    self.keys.as_deref(),
+                    state,
+                )
+            }),
             false => TModel::default(),
         };
 
-        let instant = Instant::now();
-        self.replay_journal(&mut model, restore_fns);
+        let segments = list_segments(&self.directory.join(SEGMENT_DIRECTORY))
+            .unwrap_or_else(|e| panic!("Failed to list journal segments, {}", e));
+        let chain = segment_chain(&segments, state.sequence());
+        let bytes_total = chain
+            .iter()
+            .map(|segment| segment.path.metadata().map_or(0, |metadata| metadata.len()))
+            .sum::<u64>()
+            + self
+                .journal_file
+                .metadata()
+                .map_or(0, |metadata| metadata.len());
 
-        log::debug!(
-            "Loaded {} events from journal in {}ms",
-            &self.command_count_current,
-            instant.elapsed().as_millis()
+        let last_sequence = self.last_sequence(&chain, state.sequence());
+        let mut progress = ReplayProgress::start(
+            self.restore_observer.clone(),
+            bytes_total,
+            state.sequence(),
+            last_sequence,
         );
+        snapshot_loaded(state, &model);
+        self.replay_segments::<TModel, C>(&chain, &mut model, restore_fns, state, &mut progress);
+        self.replay_journal::<TModel, C>(&mut model, restore_fns, state, &mut progress);
+        progress.finish();
+
+        if self.compact_journal {
+            self.compactor = Some(Compactor::start(
+                self.directory.join(SEGMENT_DIRECTORY),
+                Rewrite {
+                    key_fns: std::mem::take(&mut self.compaction_key_fns),
+                    commands: self.commands.clone(),
+                    compression: self.compression,
+                    keys: self.keys.clone(),
+                    lock: self.segments_lock.clone(),
+                },
+            ));
+        }
 
         model
     }
+
+    fn compaction_keys(&mut self, key_fns: HashMap<String, CompactionKeyFn>) {
+        self.compaction_key_fns = key_fns;
+    }
+
+    fn restore_observer(&mut self, observer: Arc<dyn RestoreObserver>) {
+        self.restore_observer = Some(observer);
+    }
+
+    fn model_id(&mut self, id: &str) {
+        self.model_id = Some(id.to_owned());
+    }
+
+    fn snapshot_file(&self) -> Option<PathBuf> {
+        Some(self.directory.join(SNAPSHOT_FILE)).filter(|path| path.exists())
+    }
+
+    /// The snapshot, the sealed segments and the journal up to the last commit, the journal is copied
+    /// again if a snapshot resets it during the copy
+    fn backup_files(&mut self) -> io::Result<BackupFiles> {
+        let mut files = BackupFiles::new().resets(self.journal_resets.clone());
+        match File::open(self.directory.join(SNAPSHOT_FILE)) {
+            Ok(snapshot) => {
+                let len = snapshot.metadata()?.len();
+                files = files.file(BackupRole::Snapshot, SNAPSHOT_FILE, snapshot, len);
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let segments_lock = self.segments_lock.clone();
+        let _segments_lock = segments_lock.lock();
+        for segment in list_segments(&self.directory.join(SEGMENT_DIRECTORY))? {
+            let file = File::open(&segment.path)?;
+            let len = file.metadata()?.len();
+            let name = segment_name(segment.start, segment.end);
+            files = files.file(BackupRole::Segment, &name, file, len);
+        }
+
+        let name = self
+            .journal_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("journal.origors");
+        let len = self.writer.position()?;
+        Ok(files.file(
+            BackupRole::Journal,
+            name,
+            File::open(&self.journal_path)?,
+            len,
+        ))
+    }
 }
 
+/// Reads a snapshot, snapshots without header contain only the model
+/// and are replaced by the next snapshot
+///
 /// # Panics
 ///
-/// Panics if file can't be opened or the content can't be deserialized.
-fn snapshot_read<TModel: Default + bincode::Decode>(snapshot_file: &PathBuf) -> TModel {
+/// Panics if file can't be opened, was written for another model or the content can't be deserialized.
+fn snapshot_read<TModel: Default, C: Codec<TModel>>(
+    snapshot_path: &PathBuf,
+    model_id: &str,
+    keys: Option<&dyn KeyProvider>,
+    state: &mut EngineState,
+) -> TModel {
     let instant = Instant::now();
     let snapshot_file = File::options()
         .read(true)
-        .open(snapshot_file)
+        .open(snapshot_path)
         .expect("Failed to open snapshot");
 
-    let mut snapshot_reader = BufReader::with_capacity(BUFFER_CAPACITY, snapshot_file);
+    // SAFETY: snapshots are replaced by a rename, never modified in place
+    let data = unsafe { Mmap::map(&snapshot_file) }.expect("Failed to map snapshot");
+
+    if !data.starts_with(&SNAPSHOT_MAGIC) {
+        log::info!(
+            "Snapshot {:?} has no header, it's upgraded by the next snapshot",
+            snapshot_path
+        );
+    }
+
+    let (snapshot_state, model) = split_snapshot::<TModel, C>(&data, model_id, keys)
+        .unwrap_or_else(|e| panic!("Snapshot {:?} can't be restored, {}", snapshot_path, e));
+    state.restore_snapshot(snapshot_state);
 
-    let model = bincode::decode_from_std_read(&mut snapshot_reader, BINCODE_CONFIG).unwrap();
-    log::debug!("Loaded snapshot in {}ms", instant.elapsed().as_millis());
-    model
+    match C::decode(&model) {
+        Ok(model) => {
+            log::debug!("Loaded snapshot in {}ms", instant.elapsed().as_millis());
+            model
+        }
+        Err(e) => panic!(
+            "Snapshot {:?} is corrupt or not an origo snapshot, {}",
+            snapshot_path, e
+        ),
+    }
 }
 
-fn snapshot_write<TModel: bincode::Encode>(
-    snapshot_path: &PathBuf,
+/// Splits a snapshot into the engine state and the encoded model
+///
+/// Layout: `[header][state][padding to C::ALIGN][model]`, the model is the rest of the file.
+/// Compressed and/or encrypted snapshots are `[header][state and model]`, compressed then encrypted,
+/// without padding
+pub(crate) fn split_snapshot<'a, TModel, C: Codec<TModel>>(
+    data: &'a [u8],
+    model_id: &str,
+    keys: Option<&dyn KeyProvider>,
+) -> Result<(EngineState, Cow<'a, [u8]>), FormatError> {
+    if !data.starts_with(&SNAPSHOT_MAGIC) {
+        if C::NAME != BINCODE_CODEC {
+            return Err(FormatError::Corrupt(format!(
+                "no header, snapshots without header are bincode but the engine uses {}",
+                C::NAME
+            )));
+        }
+        return Ok((EngineState::default(), Cow::Borrowed(data)));
+    }
+
+    let mut reader = &data[SNAPSHOT_MAGIC.len()..];
+    let (header, header_len) = FileHeader::read_from(&mut reader)?;
+    header.check(model_id, C::NAME)?;
+
+    let state_offset = SNAPSHOT_MAGIC.len() + header_len as usize;
+    if header.compression != Compression::None || header.encryption.is_some() {
+        let mut body = Cow::Borrowed(&data[state_offset..]);
+        if let Some(encryption) = header.encryption {
+            let keys = keys.ok_or_else(|| {
+                FormatError::Decrypt(format!(
+                    "encrypted with key {} but no keys given",
+                    encryption.key_id
+                ))
+            })?;
+            body = Cipher::for_file(keys, encryption)
+                .and_then(|cipher| cipher.decrypt_stream(&body))
+                .map(Cow::Owned)
+                .map_err(|e| FormatError::Decrypt(e.to_string()))?;
+        }
+
+        let mut body = header
+            .compression
+            .decompress_stream(&body[..])
+            .map_err(|e| FormatError::Corrupt(e.to_string()))?;
+        let (state, state_len) = bincode::decode_from_slice(&body, BINCODE_CONFIG)
+            .map_err(|e| FormatError::Corrupt(e.to_string()))?;
+        body.drain(..state_len);
+        return Ok((state, Cow::Owned(body)));
+    }
+
+    let (state, state_len) = bincode::decode_from_slice(&data[state_offset..], BINCODE_CONFIG)
+        .map_err(|e| FormatError::Corrupt(e.to_string()))?;
+
+    let model_offset = (state_offset + state_len).next_multiple_of(C::ALIGN);
+    match data.get(model_offset..) {
+        Some(model) => Ok((state, Cow::Borrowed(model))),
+        None => Err(FormatError::Corrupt("the model is missing".to_string())),
+    }
+}
+
+/// Encodes an uncompressed and unencrypted snapshot, for storages that keep it in memory or a database
+pub(crate) fn encode_snapshot<TModel, C: Codec<TModel>>(
+    model_id: &str,
+    state: &EngineState,
+    model: &TModel,
+) -> Vec<u8> {
+    let mut data = Vec::new();
+    let header_len = FileHeader::new(
+        model_id,
+        C::NAME,
+        Compression::None,
+        None,
+        Vec::new(),
+        state.sequence() + 1,
+    )
+    .write_to(&SNAPSHOT_MAGIC, &mut data)
+    .expect("Failed to write snapshot header");
+    let offset = header_len as usize
+        + bincode::encode_into_std_write(state, &mut data, BINCODE_CONFIG)
+            .expect("Failed to serialize engine state");
+    data.resize(offset.next_multiple_of(C::ALIGN), 0);
+    C::encode(model, &mut data).unwrap_or_else(|e| panic!("Failed to serialize model, {}", e));
+    data
+}
+
+/// Writes the snapshot to a temporary file and renames it over `snapshot_path`,
+/// a crash while writing leaves the previous snapshot intact
+fn snapshot_write<TModel, C: Codec<TModel>>(
+    snapshot_path: &Path,
+    model_id: &str,
+    compression: Compression,
+    cipher: Option<&Cipher>,
+    state: &EngineState,
     model: &TModel,
 ) -> Result<(), ()> {
-    let snapshot_file = File::options().write(true).create(true).open(snapshot_path);
+    let instant = Instant::now();
+    let mut temp_path = snapshot_path.as_os_str().to_owned();
+    temp_path.push(".tmp");
 
-    match snapshot_file {
-        Ok(file) => {
+    let written = File::create(&temp_path)
+        .map_err(|e| e.to_string())
+        .and_then(|file| {
             let mut writer = BufWriter::with_capacity(BUFFER_CAPACITY, &file);
 
-            let instant = Instant::now();
+            let header = FileHeader::new(
+                model_id,
+                C::NAME,
+                compression,
+                cipher.map(Cipher::encryption),
+                Vec::new(),
+                state.sequence() + 1,
+            );
+            let mut offset = header
+                .write_to(&SNAPSHOT_MAGIC, &mut writer)
+                .map_err(|e| e.to_string())? as usize;
 
-            match bincode::encode_into_std_write(model, &mut writer, BINCODE_CONFIG) {
-                Ok(_) => {
-                    file.sync_all().expect("Sync to disk failed");
-                    log::debug!("Snapshot created in {}ms", instant.elapsed().as_millis());
-                    Ok(())
-                }
-                Err(_) => {
-                    log::error!("Snapshot write to disk failed");
-                    Err(())
-                }
+            if compression != Compression::None || cipher.is_some() {
+                let encryptor = match cipher {
+                    Some(cipher) => cipher.writer(writer).map_err(|e| e.to_string())?,
+                    None => EncryptWriter::plain(writer),
+                };
+                let mut encoder = compression.writer(encryptor).map_err(|e| e.to_string())?;
+                bincode::encode_into_std_write(state, &mut encoder, BINCODE_CONFIG)
+                    .map_err(|e| e.to_string())?;
+                C::encode(model, &mut encoder).map_err(|e| e.to_string())?;
+                writer = encoder
+                    .finish()
+                    .and_then(EncryptWriter::finish)
+                    .map_err(|e| e.to_string())?;
+            } else {
+                offset += bincode::encode_into_std_write(state, &mut writer, BINCODE_CONFIG)
+                    .map_err(|e| e.to_string())?;
+
+                let padding = offset.next_multiple_of(C::ALIGN) - offset;
+                writer
+                    .write_all(&vec![0u8; padding])
+                    .map_err(|e| e.to_string())?;
+
+                C::encode(model, &mut writer).map_err(|e| e.to_string())?;
             }
+            writer.flush().map_err(|e| e.to_string())?;
+            drop(writer);
+            file.sync_all().map_err(|e| e.to_string())
+        })
+        .and_then(|_| std::fs::rename(&temp_path, snapshot_path).map_err(|e| e.to_string()))
+        .and_then(|_| sync_directory(snapshot_path).map_err(|e| e.to_string()));
+
+    match written {
+        Ok(_) => {
+            log::debug!("Snapshot created in {}ms", instant.elapsed().as_millis());
+            Ok(())
         }
-        Err(e) => match e.kind() {
-            std::io::ErrorKind::AlreadyExists => {
-                log::error!("Snapshot already exists");
-                Err(())
-            }
-            _ => Err(()),
-        },
+        Err(e) => {
+            log::error!("Snapshot write to disk failed, {}", e);
+            Err(())
+        }
+    }
+}
+
+/// Makes a rename in the directory of `path` durable
+pub(crate) fn sync_directory(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => File::open(directory)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
     }
 }
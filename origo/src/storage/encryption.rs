@@ -0,0 +1,362 @@
+use bincode::{Decode, Encode};
+#[cfg(feature = "encryption")]
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// A 256 bit key
+pub type Key = [u8; 32];
+
+/// Encryption of a journal or snapshot, recorded in the file header
+///
+/// Files are encrypted with ChaCha20-Poly1305, the key is looked up by `key_id` on restore
+#[derive(Encode, Decode, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Encryption {
+    pub key_id: u32,
+}
+
+#[cfg(feature = "encryption")]
+impl Encryption {
+    /// Decrypts an encrypted snapshot after its header
+    pub fn decrypt_stream(&self, keys: &dyn KeyProvider, data: &[u8]) -> io::Result<Vec<u8>> {
+        Cipher::for_file(keys, *self).and_then(|cipher| cipher.decrypt_stream(data))
+    }
+}
+
+/// Supplies the keys of `DiskStorage::encryption`, exported with the `encryption` feature
+///
+/// New journals and snapshots are encrypted with the current key, files are decrypted with
+/// the key they were written with. To rotate, make a new key current and keep the old key
+/// available until no file uses it anymore, the journal and snapshot switch key on the next snapshot
+pub trait KeyProvider: Send + Sync + 'static {
+    /// Id and key to encrypt new files with
+    fn current(&self) -> (u32, Key);
+
+    /// The key with `key_id`, `None` if it isn't known
+    fn key(&self, key_id: u32) -> Option<Key>;
+}
+
+/// A single key with id 0
+#[cfg(feature = "encryption")]
+pub struct StaticKey(pub Key);
+
+#[cfg(feature = "encryption")]
+impl KeyProvider for StaticKey {
+    fn current(&self) -> (u32, Key) {
+        (0, self.0)
+    }
+
+    fn key(&self, key_id: u32) -> Option<Key> {
+        (key_id == 0).then_some(self.0)
+    }
+}
+
+/// Keys by id, for key rotation
+///
+/// ```ignore
+/// let keys = KeyRing::new(2, new_key).with_key(1, old_key);
+/// ```
+#[cfg(feature = "encryption")]
+pub struct KeyRing {
+    keys: HashMap<u32, Key>,
+    current: u32,
+}
+
+#[cfg(feature = "encryption")]
+impl KeyRing {
+    /// A key ring that encrypts with `key`
+    pub fn new(current: u32, key: Key) -> Self {
+        KeyRing {
+            keys: HashMap::from([(current, key)]),
+            current,
+        }
+    }
+
+    /// Adds a key to decrypt files written with it
+    pub fn with_key(mut self, key_id: u32, key: Key) -> Self {
+        self.keys.insert(key_id, key);
+        self
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl KeyProvider for KeyRing {
+    fn current(&self) -> (u32, Key) {
+        (self.current, self.keys[&self.current])
+    }
+
+    fn key(&self, key_id: u32) -> Option<Key> {
+        self.keys.get(&key_id).copied()
+    }
+}
+
+/// Size of the nonce stored in front of every encrypted journal record
+#[cfg(feature = "encryption")]
+const RECORD_NONCE_LEN: usize = 12;
+
+/// Plaintext size of the chunks of an encrypted snapshot
+#[cfg(feature = "encryption")]
+const CHUNK_LEN: usize = 64 * 1024;
+
+/// Size of the nonce prefix at the start of an encrypted snapshot
+#[cfg(feature = "encryption")]
+const STREAM_NONCE_LEN: usize = 7;
+
+/// ChaCha20-Poly1305 with the key of a file
+///
+/// Journal records are `[nonce][ciphertext]` with the nonce `[salt: 4 bytes][sequence: u64]`,
+/// the salt is random per cipher so a record rewritten after a crash never reuses a nonce.
+/// The command name and sequence are authenticated with the record, the reader checks the
+/// sequence against the envelope so a record can't be moved to another position.
+/// Snapshots are encrypted with the STREAM construction in chunks of `[length: u32][ciphertext]`
+/// after a random nonce prefix, truncation and reordering of chunks fail to decrypt
+#[cfg(feature = "encryption")]
+#[derive(Clone)]
+pub(crate) struct Cipher {
+    key_id: u32,
+    aead: chacha20poly1305::ChaCha20Poly1305,
+    salt: [u8; 4],
+}
+
+/// Without the `encryption` feature there is no cipher, the keys can't be set
+/// and encrypted files fail to open, see [`crate::storage::FormatError::Unsupported`]
+#[cfg(not(feature = "encryption"))]
+#[derive(Clone)]
+pub(crate) enum Cipher {}
+
+#[cfg(feature = "encryption")]
+impl Cipher {
+    pub(crate) fn new(key_id: u32, key: &Key) -> io::Result<Self> {
+        use chacha20poly1305::{
+            aead::{rand_core::RngCore, OsRng},
+            KeyInit,
+        };
+
+        let mut salt = [0u8; 4];
+        OsRng.fill_bytes(&mut salt);
+        Ok(Cipher {
+            key_id,
+            aead: chacha20poly1305::ChaCha20Poly1305::new(key.into()),
+            salt,
+        })
+    }
+
+    /// Encrypts the record with the `sequence` of its envelope
+    pub(crate) fn encrypt_record(
+        &self,
+        sequence: u64,
+        name: &[u8],
+        data: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        use chacha20poly1305::aead::{Aead, Payload};
+
+        let mut nonce = [0u8; RECORD_NONCE_LEN];
+        nonce[..4].copy_from_slice(&self.salt);
+        nonce[4..].copy_from_slice(&sequence.to_le_bytes());
+
+        let ciphertext = self
+            .aead
+            .encrypt(
+                &nonce.into(),
+                Payload {
+                    msg: data,
+                    aad: &record_aad(name, sequence),
+                },
+            )
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "encryption failed"))?;
+        Ok([&nonce[..], &ciphertext].concat())
+    }
+
+    /// Decrypts a record, returns the sequence it was encrypted with and the plaintext
+    pub(crate) fn decrypt_record(&self, name: &[u8], data: &[u8]) -> io::Result<(u64, Vec<u8>)> {
+        use chacha20poly1305::aead::{Aead, Payload};
+
+        if data.len() < RECORD_NONCE_LEN {
+            return Err(invalid("the nonce is missing"));
+        }
+        let (nonce, ciphertext) = data.split_at(RECORD_NONCE_LEN);
+        let sequence = u64::from_le_bytes(nonce[4..].try_into().unwrap());
+        let plaintext = self
+            .aead
+            .decrypt(
+                nonce.into(),
+                Payload {
+                    msg: ciphertext,
+                    aad: &record_aad(name, sequence),
+                },
+            )
+            .map_err(|_| invalid("authentication failed, wrong key or corrupt record"))?;
+        Ok((sequence, plaintext))
+    }
+
+    pub(crate) fn writer<W: Write>(&self, mut writer: W) -> io::Result<EncryptWriter<W>> {
+        use chacha20poly1305::aead::{rand_core::RngCore, stream::EncryptorBE32, OsRng};
+
+        let mut nonce = [0u8; STREAM_NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        writer.write_all(&nonce)?;
+
+        Ok(EncryptWriter {
+            writer,
+            encryptor: Some((
+                EncryptorBE32::from_aead(self.aead.clone(), &nonce.into()),
+                Vec::with_capacity(CHUNK_LEN),
+            )),
+        })
+    }
+
+    /// Decrypts a snapshot written by [`Cipher::writer`]
+    pub(crate) fn decrypt_stream(&self, mut data: &[u8]) -> io::Result<Vec<u8>> {
+        use chacha20poly1305::aead::stream::DecryptorBE32;
+
+        if data.len() < STREAM_NONCE_LEN {
+            return Err(invalid("the nonce is missing"));
+        }
+        let (nonce, rest) = data.split_at(STREAM_NONCE_LEN);
+        data = rest;
+
+        let mut decryptor = DecryptorBE32::from_aead(self.aead.clone(), nonce.into());
+        let mut plaintext = Vec::with_capacity(data.len());
+        loop {
+            if data.len() < 4 {
+                return Err(invalid("truncated chunk"));
+            }
+            let len = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+            let chunk = data
+                .get(4..4 + len)
+                .ok_or_else(|| invalid("truncated chunk"))?;
+            data = &data[4 + len..];
+
+            let failed = |_| invalid("authentication failed, wrong key or corrupt snapshot");
+            if data.is_empty() {
+                plaintext.extend(decryptor.decrypt_last(chunk).map_err(failed)?);
+                return Ok(plaintext);
+            }
+            plaintext.extend(decryptor.decrypt_next(chunk).map_err(failed)?);
+        }
+    }
+
+    pub(crate) fn encryption(&self) -> Encryption {
+        Encryption {
+            key_id: self.key_id,
+        }
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+impl Cipher {
+    pub(crate) fn new(_key_id: u32, _key: &Key) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "encryption needs the cargo feature encryption",
+        ))
+    }
+
+    pub(crate) fn encrypt_record(&self, _: u64, _: &[u8], _: &[u8]) -> io::Result<Vec<u8>> {
+        match *self {}
+    }
+
+    pub(crate) fn decrypt_record(&self, _: &[u8], _: &[u8]) -> io::Result<(u64, Vec<u8>)> {
+        match *self {}
+    }
+
+    pub(crate) fn writer<W: Write>(&self, _: W) -> io::Result<EncryptWriter<W>> {
+        match *self {}
+    }
+
+    pub(crate) fn decrypt_stream(&self, _: &[u8]) -> io::Result<Vec<u8>> {
+        match *self {}
+    }
+
+    pub(crate) fn encryption(&self) -> Encryption {
+        match *self {}
+    }
+}
+
+impl Cipher {
+    /// Cipher for a file encrypted with `encryption`
+    pub(crate) fn for_file(keys: &dyn KeyProvider, encryption: Encryption) -> io::Result<Self> {
+        match keys.key(encryption.key_id) {
+            Some(key) => Cipher::new(encryption.key_id, &key),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("key {} isn't known to the key provider", encryption.key_id),
+            )),
+        }
+    }
+}
+
+/// Authenticated data of a record, the command name followed by the sequence
+#[cfg(feature = "encryption")]
+fn record_aad(name: &[u8], sequence: u64) -> Vec<u8> {
+    [name, &sequence.to_le_bytes()].concat()
+}
+
+#[cfg(feature = "encryption")]
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Streaming encryptor, [`EncryptWriter::finish`] must be called to write the last chunk.
+/// Writes through unencrypted when created with [`EncryptWriter::plain`]
+pub(crate) struct EncryptWriter<W: Write> {
+    writer: W,
+    #[cfg(feature = "encryption")]
+    encryptor: Option<(
+        chacha20poly1305::aead::stream::EncryptorBE32<chacha20poly1305::ChaCha20Poly1305>,
+        Vec<u8>,
+    )>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub(crate) fn plain(writer: W) -> Self {
+        EncryptWriter {
+            writer,
+            #[cfg(feature = "encryption")]
+            encryptor: None,
+        }
+    }
+
+    pub(crate) fn finish(self) -> io::Result<W> {
+        #[cfg(feature = "encryption")]
+        if let Some((encryptor, buffer)) = self.encryptor {
+            let mut writer = self.writer;
+            let chunk = encryptor
+                .encrypt_last(&buffer[..])
+                .map_err(|_| invalid("encryption failed"))?;
+            write_chunk(&mut writer, &chunk)?;
+            return Ok(writer);
+        }
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(feature = "encryption")]
+        if let Some((encryptor, buffer)) = &mut self.encryptor {
+            // The last chunk is written by finish, a full buffer is written once more data follows
+            if buffer.len() == CHUNK_LEN {
+                let chunk = encryptor
+                    .encrypt_next(&buffer[..])
+                    .map_err(|_| invalid("encryption failed"))?;
+                write_chunk(&mut self.writer, &chunk)?;
+                buffer.clear();
+            }
+
+            let len = buf.len().min(CHUNK_LEN - buffer.len());
+            buffer.extend_from_slice(&buf[..len]);
+            return Ok(len);
+        }
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(feature = "encryption")]
+fn write_chunk<W: Write>(writer: &mut W, chunk: &[u8]) -> io::Result<()> {
+    writer.write_all(&(chunk.len() as u32).to_le_bytes())?;
+    writer.write_all(chunk)
+}
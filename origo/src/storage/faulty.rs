@@ -0,0 +1,303 @@
+use crate::{
+    codec::Codec,
+    context::Envelope,
+    engine::{Command, CommandRestoreFn, CompactionKeyFn},
+    restore::RestoreObserver,
+    state::EngineState,
+    storage::{writer::Writer, BackupFiles, Storage},
+};
+
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// A fault injected by [`FaultyStorage`], the storage "crashes" by panicking
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The write fails, nothing of the record reaches the journal
+    FailWrite,
+    /// The write fails after the first `bytes` of the record reached the journal, the rest is lost
+    TornWrite { bytes: u64 },
+    /// The record is written but the sync fails, only the first `bytes` of it reached the disk
+    FailSync { bytes: u64 },
+    /// The process stops before the snapshot is written
+    FailSnapshot,
+    /// The snapshot is written but the process stops before the journal is reset
+    InterruptSnapshot,
+}
+
+/// Faults to inject and what happened, shared between the test and the storage
+#[derive(Clone, Default)]
+pub struct Faults {
+    inner: Arc<Mutex<FaultsState>>,
+}
+
+#[derive(Default)]
+struct FaultsState {
+    /// Faults by the number of the commit or snapshot they happen in, counting from 1
+    commits: HashMap<u64, Fault>,
+    snapshots: HashMap<u64, Fault>,
+    commit_count: u64,
+    snapshot_count: u64,
+    crashed: Option<Fault>,
+    /// The write or sync fault of the current commit, applied by the [`SimulatedFile`]
+    armed: Option<Fault>,
+}
+
+impl Faults {
+    pub fn new() -> Self {
+        Faults::default()
+    }
+
+    /// Injects `fault` in the `n`th commit, or in the `n`th snapshot for the snapshot faults
+    pub fn at(self, n: u64, fault: Fault) -> Self {
+        {
+            let mut state = self.inner.lock();
+            match fault {
+                Fault::FailSnapshot | Fault::InterruptSnapshot => state.snapshots.insert(n, fault),
+                _ => state.commits.insert(n, fault),
+            };
+        }
+        self
+    }
+
+    /// The fault that crashed the storage, if any
+    pub fn crashed(&self) -> Option<Fault> {
+        self.inner.lock().crashed
+    }
+
+    /// Takes the armed fault if `applies` to it
+    fn take_armed(&self, applies: impl Fn(Fault) -> bool) -> Option<Fault> {
+        let mut state = self.inner.lock();
+        state.armed.take_if(|fault| applies(*fault))
+    }
+}
+
+/// The journal file under a [`Writer`] as the disk holds it, see [`crate::storage::DiskStorage::faults`]
+///
+/// Keeps track of the synced end of the journal. A [`Fault::TornWrite`] fails the write and a
+/// [`Fault::FailSync`] fails the sync, both leave only the first `bytes` written since the last
+/// sync in the file, like a crash before the page cache was written back
+pub(crate) struct SimulatedFile {
+    writer: Writer,
+    file: File,
+    faults: Faults,
+    synced: u64,
+}
+
+impl SimulatedFile {
+    pub(crate) fn new(writer: Writer, file: &File, faults: Faults) -> io::Result<Self> {
+        Ok(SimulatedFile {
+            writer,
+            file: file.try_clone()?,
+            faults,
+            synced: 0,
+        })
+    }
+
+    pub(crate) fn writer(&mut self) -> &mut Writer {
+        &mut self.writer
+    }
+
+    /// The journal up to `position` was synced when it was written or read
+    pub(crate) fn reset(&mut self, position: u64) -> io::Result<()> {
+        self.writer.reset(position)?;
+        self.synced = position;
+        Ok(())
+    }
+
+    pub(crate) fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        self.writer.append(data)?;
+        match self
+            .faults
+            .take_armed(|fault| matches!(fault, Fault::TornWrite { .. }))
+        {
+            Some(fault @ Fault::TornWrite { bytes }) => self.lose_unsynced(bytes, fault),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn sync(&mut self) -> io::Result<()> {
+        match self
+            .faults
+            .take_armed(|fault| matches!(fault, Fault::FailSync { .. }))
+        {
+            Some(fault @ Fault::FailSync { bytes }) => self.lose_unsynced(bytes, fault),
+            _ => {
+                self.writer.sync()?;
+                self.synced = self.writer.position()?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Keeps `bytes` of what was written since the last sync and fails with `fault`
+    fn lose_unsynced(&mut self, bytes: u64, fault: Fault) -> io::Result<()> {
+        // Writes the buffered records through, then takes back what didn't reach the disk
+        self.writer.sync()?;
+        let end = self.writer.position()?;
+        let kept = end.min(self.synced + bytes);
+        if self.file.metadata()?.len() > end {
+            // Preallocated space reads as zeros
+            self.file
+                .write_all_at(&vec![0; (end - kept) as usize], kept)?;
+        } else {
+            self.file.set_len(kept)?;
+        }
+        self.file.sync_data()?;
+        Err(io::Error::other(format!("Injected fault, {:?}", fault)))
+    }
+}
+
+/// Wraps a storage and injects [`Faults`] to test that restore survives crashes
+///
+/// An injected fault panics, with `panic = "abort"` that stops the process like a crash.
+/// Without abort every later call panics as well, like a process that stopped.
+/// [`Fault::TornWrite`] and [`Fault::FailSync`] are applied by the journal file of a
+/// [`crate::storage::DiskStorage`] with [`crate::storage::DiskStorage::faults`], the storage fails
+/// on the error of the write or sync. [`Fault::InterruptSnapshot`] needs the journal file,
+/// see [`FaultyStorage::journal_file`]
+///
+/// ```ignore
+/// let faults = Faults::new().at(17, Fault::TornWrite { bytes: 3 });
+/// let disk = DiskStorage::new(&path).faults(faults.clone());
+/// let storage = FaultyStorage::new(disk, faults.clone()).journal_file(&path);
+/// ```
+pub struct FaultyStorage<S> {
+    inner: S,
+    faults: Faults,
+    journal_path: Option<PathBuf>,
+}
+
+impl<S: Storage> FaultyStorage<S> {
+    pub fn new(inner: S, faults: Faults) -> Self {
+        FaultyStorage {
+            inner,
+            faults,
+            journal_path: None,
+        }
+    }
+
+    /// The journal file of the wrapped storage
+    pub fn journal_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.journal_path = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// The journal file fails the current commit with `fault`, see [`SimulatedFile`]
+    fn arm(&self, fault: Fault) {
+        let mut state = self.faults.inner.lock();
+        state.armed = Some(fault);
+        state.crashed = Some(fault);
+    }
+
+    fn crash(&self, fault: Fault) -> ! {
+        self.faults.inner.lock().crashed = Some(fault);
+        panic!("Injected fault, {:?}", fault);
+    }
+
+    fn check_crashed(&self) {
+        if let Some(fault) = self.faults.crashed() {
+            panic!("Storage crashed earlier, {:?}", fault);
+        }
+    }
+}
+
+impl<S: Storage> Storage for FaultyStorage<S> {
+    fn prepare<TModel, C: Codec<T>, T: Command<TModel>>(
+        &mut self,
+        envelope: &Envelope,
+        command_name: &str,
+        command: &T,
+    ) {
+        self.check_crashed();
+        self.inner
+            .prepare::<TModel, C, T>(envelope, command_name, command);
+    }
+
+    fn commit(&mut self) -> u64 {
+        self.check_crashed();
+        let fault = {
+            let mut state = self.faults.inner.lock();
+            state.commit_count += 1;
+            state.commits.get(&state.commit_count).copied()
+        };
+
+        match fault {
+            None => self.inner.commit(),
+            Some(Fault::FailWrite) => self.crash(Fault::FailWrite),
+            Some(fault @ (Fault::TornWrite { .. } | Fault::FailSync { .. })) => {
+                self.arm(fault);
+                self.inner.commit();
+                panic!(
+                    "{:?} wasn't applied, it needs a DiskStorage with DiskStorage::faults",
+                    fault
+                )
+            }
+            Some(fault) => self.crash(fault),
+        }
+    }
+
+    fn snapshot<TModel, C: Codec<TModel>>(&mut self, state: &EngineState, model: &TModel) {
+        self.check_crashed();
+        let fault = {
+            let mut faults = self.faults.inner.lock();
+            faults.snapshot_count += 1;
+            faults.snapshots.get(&faults.snapshot_count).copied()
+        };
+
+        match fault {
+            None => self.inner.snapshot::<TModel, C>(state, model),
+            Some(Fault::InterruptSnapshot) => {
+                let path = self
+                    .journal_path
+                    .clone()
+                    .unwrap_or_else(|| panic!("{:?} needs the journal file", fault));
+                let journal = std::fs::read(&path).expect("Failed to read journal");
+                self.inner.snapshot::<TModel, C>(state, model);
+                // Puts back the journal as it was before the reset
+                std::fs::write(&path, journal).expect("Failed to restore journal");
+                self.crash(Fault::InterruptSnapshot)
+            }
+            Some(fault) => self.crash(fault),
+        }
+    }
+
+    fn restore<TModel: Default, C: Codec<TModel>>(
+        &mut self,
+        restore_fns: &HashMap<String, CommandRestoreFn<TModel>>,
+        state: &mut EngineState,
+        snapshot_loaded: &mut dyn FnMut(&EngineState, &TModel),
+    ) -> TModel {
+        self.check_crashed();
+        self.inner
+            .restore::<TModel, C>(restore_fns, state, snapshot_loaded)
+    }
+
+    fn compaction_keys(&mut self, key_fns: HashMap<String, CompactionKeyFn>) {
+        self.inner.compaction_keys(key_fns)
+    }
+
+    fn restore_observer(&mut self, observer: Arc<dyn RestoreObserver>) {
+        self.inner.restore_observer(observer)
+    }
+
+    fn model_id(&mut self, id: &str) {
+        self.inner.model_id(id)
+    }
+
+    fn snapshot_file(&self) -> Option<PathBuf> {
+        self.inner.snapshot_file()
+    }
+
+    fn backup_files(&mut self) -> io::Result<BackupFiles> {
+        self.check_crashed();
+        self.inner.backup_files()
+    }
+}
@@ -0,0 +1,218 @@
+use crate::storage::{Compression, Encryption, BINCODE_CONFIG};
+
+use bincode::{error::DecodeError, Decode, Encode};
+use std::{
+    any::type_name,
+    fmt,
+    io::{self, Read, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// First bytes of a journal file
+pub const JOURNAL_MAGIC: [u8; 8] = *b"ORIGOJNL";
+
+/// First bytes of a snapshot file
+pub const SNAPSHOT_MAGIC: [u8; 8] = *b"ORIGOSNP";
+
+/// Version of the journal and snapshot format written by this build
+///
+/// - 1: initial version, files before it have no header
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Header at the start of journal and snapshot files
+///
+/// Layout: `[magic: 8 bytes][version: u32][header length: u32][header]`,
+/// the version is outside of the encoded header so files of newer versions are
+/// recognized even if their header can't be decoded
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct FileHeader {
+    pub version: u32,
+    /// Id of the model the file was written for, see [`crate::EngineBuilder::model_id`]
+    pub model: String,
+    /// [`crate::Codec::NAME`] of the codec of the model and commands
+    pub codec: String,
+    /// Compression of the journal records, or of the snapshot after the header
+    pub compression: Compression,
+    /// Set for encrypted files, encryption happens after compression
+    pub encryption: Option<Encryption>,
+    /// Names of the registered commands, journal records refer to them by position plus one.
+    /// Empty for snapshots
+    pub commands: Vec<String>,
+    /// Creation time in milliseconds since the unix epoch
+    pub created: u64,
+    /// Sequence of the first command in the journal,
+    /// for snapshots the sequence the journal continues at
+    pub start_sequence: u64,
+    /// Dictionary the journal records are compressed with, trained on the records of the previous journal.
+    /// Empty for snapshots, uncompressed journals and the first journal, encrypted like a record in encrypted journals
+    pub dictionary: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum FormatError {
+    Io(io::Error),
+    /// Written by a newer (or unknown) version of the format
+    UnsupportedVersion(u32),
+    /// The header can't be decoded
+    CorruptHeader,
+    /// The content after the header is invalid
+    Corrupt(String),
+    /// Written for a different model
+    ModelMismatch {
+        expected: String,
+        found: String,
+    },
+    /// Written with a different codec
+    CodecMismatch {
+        expected: String,
+        found: String,
+    },
+    /// Compressed snapshots can't be accessed in place
+    Compressed(Compression),
+    /// Encrypted snapshots can't be accessed in place
+    Encrypted(Encryption),
+    /// The key is missing or the content doesn't authenticate
+    Decrypt(String),
+    /// Written with a feature this build doesn't have
+    Unsupported(String),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Io(e) => write!(f, "{}", e),
+            FormatError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported format version {}, this build supports version {}",
+                version, FORMAT_VERSION
+            ),
+            FormatError::CorruptHeader => write!(f, "corrupt file header"),
+            FormatError::Corrupt(reason) => write!(f, "corrupt file, {}", reason),
+            FormatError::ModelMismatch { expected, found } => write!(
+                f,
+                "written for model {} but the engine uses {}, \
+                 build the engine with `EngineBuilder::model_id({:?})` if it's the same model",
+                found, expected, found
+            ),
+            FormatError::CodecMismatch { expected, found } => write!(
+                f,
+                "written with codec {} but the engine uses {}",
+                found, expected
+            ),
+            FormatError::Compressed(compression) => write!(
+                f,
+                "compressed with {:?}, compressed snapshots can't be memory-mapped",
+                compression
+            ),
+            FormatError::Encrypted(encryption) => write!(
+                f,
+                "encrypted with key {}, encrypted snapshots can't be memory-mapped",
+                encryption.key_id
+            ),
+            FormatError::Decrypt(reason) => write!(f, "failed to decrypt, {}", reason),
+            FormatError::Unsupported(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for FormatError {
+    fn from(e: io::Error) -> Self {
+        FormatError::Io(e)
+    }
+}
+
+/// The id of `TModel` in file headers, `id` if it's set with [`crate::EngineBuilder::model_id`],
+/// otherwise the type name
+pub(crate) fn model_id<TModel>(id: Option<&str>) -> &str {
+    id.unwrap_or(type_name::<TModel>())
+}
+
+impl FileHeader {
+    pub fn new(
+        model: &str,
+        codec: &str,
+        compression: Compression,
+        encryption: Option<Encryption>,
+        commands: Vec<String>,
+        start_sequence: u64,
+    ) -> Self {
+        FileHeader {
+            version: FORMAT_VERSION,
+            model: model.to_owned(),
+            codec: codec.to_owned(),
+            compression,
+            encryption,
+            commands,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("System clock is before the unix epoch")
+                .as_millis() as u64,
+            start_sequence,
+            dictionary: Vec::new(),
+        }
+    }
+
+    /// Writes `magic` followed by the header, returns the amount of bytes written
+    pub fn write_to<W: Write>(&self, magic: &[u8; 8], writer: &mut W) -> io::Result<u64> {
+        let header = bincode::encode_to_vec(self, BINCODE_CONFIG)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        writer.write_all(magic)?;
+        writer.write_all(&self.version.to_le_bytes())?;
+        writer.write_all(&(header.len() as u32).to_le_bytes())?;
+        writer.write_all(&header)?;
+        Ok((magic.len() + 8 + header.len()) as u64)
+    }
+
+    /// Reads the header that follows the magic bytes,
+    /// returns it together with the amount of bytes read
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<(FileHeader, u64), FormatError> {
+        let mut prefix = [0u8; 8];
+        reader.read_exact(&mut prefix)?;
+        let version = u32::from_le_bytes(prefix[..4].try_into().unwrap());
+        let header_len = u32::from_le_bytes(prefix[4..].try_into().unwrap());
+
+        if version != FORMAT_VERSION {
+            return Err(FormatError::UnsupportedVersion(version));
+        }
+
+        let mut header = vec![0u8; header_len as usize];
+        reader.read_exact(&mut header)?;
+        let (header, _): (FileHeader, _) = bincode::decode_from_slice(&header, BINCODE_CONFIG)
+            .map_err(|e| match e {
+                DecodeError::OtherString(reason) => FormatError::Unsupported(reason),
+                _ => FormatError::CorruptHeader,
+            })?;
+
+        #[cfg(not(feature = "encryption"))]
+        if let Some(encryption) = header.encryption {
+            return Err(FormatError::Unsupported(format!(
+                "encrypted with key {}, it needs the cargo feature encryption",
+                encryption.key_id
+            )));
+        }
+
+        match header.version == version {
+            true => Ok((header, 8 + header_len as u64)),
+            false => Err(FormatError::CorruptHeader),
+        }
+    }
+
+    /// Checks that the file was written for the model with id `model` and with `codec`
+    pub fn check(&self, model: &str, codec: &str) -> Result<(), FormatError> {
+        if self.model != model {
+            return Err(FormatError::ModelMismatch {
+                expected: model.to_owned(),
+                found: self.model.clone(),
+            });
+        }
+
+        match self.codec == codec {
+            true => Ok(()),
+            false => Err(FormatError::CodecMismatch {
+                expected: codec.to_owned(),
+                found: self.codec.clone(),
+            }),
+        }
+    }
+}
@@ -0,0 +1,599 @@
+use crate::{
+    context::Envelope,
+    storage::{
+        compression::RecordCompressor, encryption::Cipher, Compression, FileHeader, FormatError,
+        KeyProvider, BINCODE_CONFIG, JOURNAL_MAGIC,
+    },
+};
+
+use bincode::{config::Configuration, error::DecodeError};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+/// A raw record of the journal
+///
+/// Layout: `[length: varint][crc32: u32][command id: varint][envelope][command]`, where `length` covers
+/// everything after it, the checksum everything after the checksum,
+/// and the id is the position in [`FileHeader::commands`] plus one.
+/// Commands missing from the table have id 0 followed by `[name length: varint][name]`.
+/// Journals written before the file header existed use `[length: u64][name length: u64][name][command]`,
+/// where `length` covers everything after the name length.
+/// With compression the envelope and command are compressed together,
+/// encrypted records are `[nonce][ciphertext]` after the command id or name
+pub struct JournalRecord {
+    /// Offset of the record in the journal
+    pub offset: u64,
+    /// Size of the whole record including the headers
+    pub length: u64,
+    pub name: String,
+    /// The encoded envelope followed by the encoded command, decrypted and decompressed
+    /// unless the reader is [`JournalReader::raw`]
+    pub data: Vec<u8>,
+}
+
+impl JournalRecord {
+    /// Decodes the envelope, returns it together with the encoded command
+    pub fn envelope(&self, config: Configuration) -> Result<(Envelope, &[u8]), DecodeError> {
+        let (envelope, length) = bincode::decode_from_slice(&self.data, config)?;
+        Ok((envelope, &self.data[length..]))
+    }
+}
+
+#[derive(Debug)]
+pub enum JournalError {
+    Io(io::Error),
+    /// The record at `offset` continues past the end of the journal
+    Truncated {
+        offset: u64,
+    },
+    /// The record at `offset` doesn't match its checksum and only zeros follow it,
+    /// a write cut off by a crash before it was synced
+    Torn {
+        offset: u64,
+    },
+    /// The record at `offset` doesn't match its checksum, or has a zero length, and more data follows it
+    Checksum {
+        offset: u64,
+    },
+    /// The command name of the record at `offset` isn't valid utf8
+    InvalidName {
+        offset: u64,
+    },
+    /// The command id of the record at `offset` isn't in the command table of the header
+    UnknownCommandId {
+        offset: u64,
+        id: u64,
+    },
+    /// The file header is invalid
+    Format(FormatError),
+    /// The envelope and command of the record at `offset` can't be decompressed
+    Decompress {
+        offset: u64,
+        reason: String,
+    },
+    /// The record at `offset` can't be decrypted
+    Decrypt {
+        offset: u64,
+        reason: String,
+    },
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalError::Io(e) => write!(f, "{}", e),
+            JournalError::Truncated { offset } => {
+                write!(f, "record at offset {} is truncated", offset)
+            }
+            JournalError::Torn { offset } => {
+                write!(
+                    f,
+                    "record at offset {} is torn, only zeros follow it",
+                    offset
+                )
+            }
+            JournalError::Checksum { offset } => write!(
+                f,
+                "record at offset {} doesn't match its checksum and more data follows it",
+                offset
+            ),
+            JournalError::InvalidName { offset } => {
+                write!(f, "record at offset {} has a non utf8 command name", offset)
+            }
+            JournalError::UnknownCommandId { offset, id } => write!(
+                f,
+                "record at offset {} has command id {} which isn't in the header",
+                offset, id
+            ),
+            JournalError::Format(e) => write!(f, "{}", e),
+            JournalError::Decompress { offset, reason } => write!(
+                f,
+                "record at offset {} can't be decompressed, {}",
+                offset, reason
+            ),
+            JournalError::Decrypt { offset, reason } => write!(
+                f,
+                "record at offset {} can't be decrypted, {}",
+                offset, reason
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for JournalError {
+    fn from(e: io::Error) -> Self {
+        JournalError::Io(e)
+    }
+}
+
+impl From<FormatError> for JournalError {
+    fn from(e: FormatError) -> Self {
+        match e {
+            FormatError::Io(e) => JournalError::Io(e),
+            e => JournalError::Format(e),
+        }
+    }
+}
+
+/// Reads the raw records of a journal, used by [`crate::storage::DiskStorage`] to replay
+/// and by tools to inspect journals
+///
+/// Layout: `[file header][records]`, see [`FileHeader`], the records are counted by reading them.
+/// Journals written before the header existed start with a record count
+/// and their records don't contain an envelope
+///
+/// Encrypted journals need the keys, see [`JournalReader::keys`]. Stops after the first error,
+/// or at a zero record length followed by zeros, the space preallocated by the journal writers
+pub struct JournalReader<R> {
+    reader: R,
+    decoder: RecordDecoder,
+    /// Created with the first compressed record, the dictionary needs the cipher
+    compressor: Option<RecordCompressor>,
+    /// Whether the data of the records is left for a [`RecordDecoder`] of another thread
+    raw: bool,
+    header_count: Option<u64>,
+    records_offset: u64,
+    offset: u64,
+    journal_len: u64,
+    done: bool,
+}
+
+impl JournalReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, JournalError> {
+        let file = File::open(path)?;
+        let journal_len = file.metadata()?.len();
+        JournalReader::new(BufReader::new(file), journal_len)
+    }
+}
+
+impl<R: Read> JournalReader<R> {
+    /// `reader` must be positioned at the start of the journal,
+    /// `journal_len` is used to detect records that continue past the end
+    pub fn new(mut reader: R, journal_len: u64) -> Result<Self, JournalError> {
+        let mut header = None;
+        let mut records_offset = 0;
+
+        let mut prefix = [0u8; 8];
+        let header_count = match reader.read_exact(&mut prefix) {
+            Ok(_) if prefix == JOURNAL_MAGIC => {
+                let (file_header, header_len) = FileHeader::read_from(&mut reader)?;
+                header = Some(file_header);
+                records_offset += prefix.len() as u64 + header_len;
+                None
+            }
+            Ok(_) => {
+                records_offset = 8;
+                Some(u64::from_le_bytes(prefix))
+            }
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(JournalReader {
+            reader,
+            decoder: RecordDecoder {
+                header,
+                cipher: None,
+            },
+            compressor: None,
+            raw: false,
+            header_count,
+            records_offset: records_offset.min(journal_len),
+            offset: records_offset.min(journal_len),
+            journal_len,
+            done: false,
+        })
+    }
+
+    /// Decrypts the records with the key of the journal, if it's encrypted
+    #[cfg(feature = "encryption")]
+    pub fn keys(self, keys: &dyn KeyProvider) -> Result<Self, JournalError> {
+        self.with_keys(Some(keys))
+    }
+
+    pub(crate) fn with_keys(
+        mut self,
+        keys: Option<&dyn KeyProvider>,
+    ) -> Result<Self, JournalError> {
+        if let (Some(keys), Some(encryption)) =
+            (keys, self.header().and_then(|header| header.encryption))
+        {
+            let cipher = Cipher::for_file(keys, encryption)
+                .map_err(|e| FormatError::Decrypt(e.to_string()))?;
+            self.decoder.cipher = Some(cipher);
+        }
+        Ok(self)
+    }
+
+    /// Leaves the data of the following records encrypted and compressed,
+    /// the returned decoder decodes it on another thread
+    pub(crate) fn raw(&mut self) -> RecordDecoder {
+        self.raw = true;
+        self.decoder.clone()
+    }
+
+    /// The file header, `None` for empty journals and journals without a header
+    pub fn header(&self) -> Option<&FileHeader> {
+        self.decoder.header.as_ref()
+    }
+
+    /// The record count at the start of journals without a header, `None` for journals with a header
+    pub fn header_count(&self) -> Option<u64> {
+        self.header_count
+    }
+
+    /// Offset of the first record
+    pub fn records_offset(&self) -> u64 {
+        self.records_offset
+    }
+
+    /// Offset of the next record, after the last record once the reader is done
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn journal_len(&self) -> u64 {
+        self.journal_len
+    }
+
+    fn read_record(&mut self) -> Result<Option<JournalRecord>, JournalError> {
+        let offset = self.offset;
+        if offset == self.journal_len {
+            return Ok(None);
+        }
+
+        let record = match self.header() {
+            Some(_) => self.read_compact(offset)?,
+            None => self.read_fixed(offset)?,
+        };
+        let Some((length, name, data)) = record else {
+            return Ok(None);
+        };
+
+        let mut record = JournalRecord {
+            offset,
+            length,
+            name,
+            data,
+        };
+        if !self.raw {
+            self.decoder.decode(&mut record, &mut self.compressor)?;
+        }
+        self.offset += length;
+        Ok(Some(record))
+    }
+
+    /// Reads a record of a journal without header, returns its length, name and data
+    fn read_fixed(&mut self, offset: u64) -> Result<Option<(u64, String, Vec<u8>)>, JournalError> {
+        if offset.saturating_add(16) > self.journal_len {
+            return Err(JournalError::Truncated { offset });
+        }
+
+        let mut header = [0u8; 16];
+        self.reader.read_exact(&mut header)?;
+        let data_len = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let name_len = u64::from_le_bytes(header[8..].try_into().unwrap());
+
+        let length = data_len.saturating_add(16);
+        if data_len < name_len || offset.saturating_add(length) > self.journal_len {
+            return Err(JournalError::Truncated { offset });
+        }
+
+        let mut name = vec![0u8; name_len as usize];
+        self.reader.read_exact(&mut name)?;
+        let name = String::from_utf8(name).map_err(|_| JournalError::InvalidName { offset })?;
+
+        let mut data = vec![0u8; (data_len - name_len) as usize];
+        self.reader.read_exact(&mut data)?;
+        Ok(Some((length, name, data)))
+    }
+
+    /// Reads a record with a command id, returns its length, name and data.
+    /// `None` at the zeroed space preallocated by [`crate::storage::JournalWriter`]
+    fn read_compact(
+        &mut self,
+        offset: u64,
+    ) -> Result<Option<(u64, String, Vec<u8>)>, JournalError> {
+        let truncated = |_| JournalError::Truncated { offset };
+
+        let (record_len, prefix_len) = read_varint(&mut self.reader).map_err(truncated)?;
+        if record_len == 0 {
+            return match self.only_zeros_follow(offset + prefix_len)? {
+                true => Ok(None),
+                false => Err(JournalError::Checksum { offset }),
+            };
+        }
+        let length = record_len.saturating_add(prefix_len);
+        if offset.saturating_add(length) > self.journal_len {
+            return Err(JournalError::Truncated { offset });
+        }
+
+        let mut record = vec![0u8; record_len as usize];
+        self.reader.read_exact(&mut record)?;
+
+        // A torn write can leave a valid length in front of zeros or stale bytes
+        let valid = record.len() >= 4
+            && u32::from_le_bytes(record[..4].try_into().unwrap()) == crc32fast::hash(&record[4..]);
+        if !valid {
+            return match self.only_zeros_follow(offset + length)? {
+                true => Err(JournalError::Torn { offset }),
+                false => Err(JournalError::Checksum { offset }),
+            };
+        }
+
+        let mut rest = &record[4..];
+        let (id, _) = read_varint(&mut rest).map_err(truncated)?;
+        let name = match id {
+            0 => {
+                let (name_len, _) = read_varint(&mut rest).map_err(truncated)?;
+                if name_len > rest.len() as u64 {
+                    return Err(JournalError::Truncated { offset });
+                }
+                let (name, data) = rest.split_at(name_len as usize);
+                rest = data;
+                String::from_utf8(name.to_vec())
+                    .map_err(|_| JournalError::InvalidName { offset })?
+            }
+            id => self
+                .header()
+                .and_then(|header| header.commands.get(id as usize - 1))
+                .cloned()
+                .ok_or(JournalError::UnknownCommandId { offset, id })?,
+        };
+
+        Ok(Some((length, name, rest.to_vec())))
+    }
+
+    /// Whether the journal after `position` is all zeros, reads it to the end
+    fn only_zeros_follow(&mut self, position: u64) -> io::Result<bool> {
+        let mut rest = (&mut self.reader).take(self.journal_len.saturating_sub(position));
+        let mut buffer = [0u8; 8192];
+        loop {
+            match rest.read(&mut buffer) {
+                Ok(0) => return Ok(true),
+                Ok(read) if buffer[..read].iter().all(|byte| *byte == 0) => {}
+                Ok(_) => return Ok(false),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> JournalReader<R> {
+    /// Counts the records after the current offset by their lengths, without reading or verifying them,
+    /// so a torn last record is counted. Journals without a header return their record count
+    pub(crate) fn count_records(mut self) -> io::Result<u64> {
+        if self.header().is_none() {
+            return Ok(self.header_count.unwrap_or(0));
+        }
+
+        let mut count = 0;
+        let mut offset = self.offset;
+        while offset < self.journal_len {
+            let (record_len, prefix_len) = match read_varint(&mut self.reader) {
+                Ok(varint) => varint,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            offset += prefix_len + record_len;
+            if record_len == 0 || offset > self.journal_len {
+                break;
+            }
+            count += 1;
+            self.reader.seek(SeekFrom::Current(record_len as i64))?;
+        }
+        Ok(count)
+    }
+}
+
+impl<R: Read> Iterator for JournalReader<R> {
+    type Item = Result<JournalRecord, JournalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let record = self.read_record().transpose();
+        self.done = !matches!(record, Some(Ok(_)));
+        record
+    }
+}
+
+/// Decrypts and decompresses the data of the records of a journal
+#[derive(Clone)]
+pub(crate) struct RecordDecoder {
+    header: Option<FileHeader>,
+    cipher: Option<Cipher>,
+}
+
+impl RecordDecoder {
+    /// Replaces the data of a record read by a [`JournalReader::raw`] reader with the encoded
+    /// envelope and command, `compressor` is created with the first compressed record
+    pub(crate) fn decode(
+        &self,
+        record: &mut JournalRecord,
+        compressor: &mut Option<RecordCompressor>,
+    ) -> Result<(), JournalError> {
+        let offset = record.offset;
+        let decrypt_error = |reason: String| JournalError::Decrypt { offset, reason };
+        let mut encrypted_sequence = None;
+        if let Some(encryption) = self.header.as_ref().and_then(|header| header.encryption) {
+            let (sequence, data) = match &self.cipher {
+                Some(cipher) => cipher.decrypt_record(record.name.as_bytes(), &record.data),
+                None => Err(io::Error::other(format!(
+                    "encrypted with key {}, no keys given",
+                    encryption.key_id
+                ))),
+            }
+            .map_err(|e| decrypt_error(e.to_string()))?;
+            record.data = data;
+            encrypted_sequence = Some(sequence);
+        }
+
+        if let Some(header) = self
+            .header
+            .as_ref()
+            .filter(|header| header.compression != Compression::None)
+        {
+            let decompress_error = |e: io::Error| JournalError::Decompress {
+                offset,
+                reason: e.to_string(),
+            };
+            if compressor.is_none() {
+                *compressor = Some(
+                    record_compressor(header, self.cipher.as_ref()).map_err(decompress_error)?,
+                );
+            }
+            record.data = compressor
+                .as_mut()
+                .expect("Compressor is created above")
+                .decompress(&record.data)
+                .map_err(decompress_error)?;
+        }
+
+        // The sequence is authenticated, a record copied to another position doesn't match its envelope
+        if let Some(sequence) = encrypted_sequence {
+            let (envelope, _) = record
+                .envelope(BINCODE_CONFIG)
+                .map_err(|e| decrypt_error(format!("the envelope can't be decoded, {}", e)))?;
+            if envelope.sequence != sequence {
+                return Err(decrypt_error(format!(
+                    "encrypted with sequence {}, the envelope has sequence {}",
+                    sequence, envelope.sequence
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Appends the command id of `name` to a compact record, ids are the positions in the command
+/// table plus one, commands missing from the table are written as id 0 followed by the name
+pub(crate) fn write_command_id(buffer: &mut Vec<u8>, ids: &HashMap<String, u64>, name: &str) {
+    match ids.get(name) {
+        Some(id) => write_varint(buffer, *id),
+        None => {
+            write_varint(buffer, 0);
+            write_varint(buffer, name.len() as u64);
+            buffer.extend_from_slice(name.as_bytes());
+        }
+    }
+}
+
+/// Compresses and encrypts the envelope and command of a record, everything in `buffer` after `data_offset`
+pub(crate) fn encode_record_data(
+    buffer: &mut Vec<u8>,
+    data_offset: usize,
+    compressor: &mut RecordCompressor,
+    cipher: Option<&Cipher>,
+    sequence: u64,
+    name: &str,
+) -> io::Result<()> {
+    if !compressor.is_none() {
+        let compressed = compressor.compress(&buffer[data_offset..])?;
+        buffer.truncate(data_offset);
+        buffer.extend_from_slice(&compressed);
+    }
+
+    if let Some(cipher) = cipher {
+        let encrypted = cipher.encrypt_record(sequence, name.as_bytes(), &buffer[data_offset..])?;
+        buffer.truncate(data_offset);
+        buffer.extend_from_slice(&encrypted);
+    }
+    Ok(())
+}
+
+/// Authenticated data of an encrypted dictionary, sequence 0 is never used by a record
+const DICTIONARY_AAD: &[u8] = b"dictionary";
+
+/// Encrypts a dictionary for the header of a journal written with `cipher`
+pub(crate) fn seal_dictionary(dictionary: Vec<u8>, cipher: Option<&Cipher>) -> io::Result<Vec<u8>> {
+    match cipher {
+        Some(cipher) if !dictionary.is_empty() => {
+            cipher.encrypt_record(0, DICTIONARY_AAD, &dictionary)
+        }
+        _ => Ok(dictionary),
+    }
+}
+
+/// The compressor of the records of the journal with `header`, `cipher` decrypts its dictionary
+pub(crate) fn record_compressor(
+    header: &FileHeader,
+    cipher: Option<&Cipher>,
+) -> io::Result<RecordCompressor> {
+    let dictionary = match cipher {
+        Some(cipher) if !header.dictionary.is_empty() => {
+            cipher.decrypt_record(DICTIONARY_AAD, &header.dictionary)?.1
+        }
+        _ => header.dictionary.clone(),
+    };
+    RecordCompressor::new(header.compression, dictionary)
+}
+
+/// Prefixes a compact record with its length and the checksum of the record
+pub(crate) fn frame_record(buffer: &mut Vec<u8>) {
+    let checksum = crc32fast::hash(buffer).to_le_bytes();
+    let mut prefix = Vec::with_capacity(14);
+    write_varint(&mut prefix, (buffer.len() + checksum.len()) as u64);
+    prefix.extend_from_slice(&checksum);
+    buffer.splice(0..0, prefix);
+}
+
+/// Command ids of the command table of a journal header
+pub(crate) fn command_ids(commands: &[String]) -> HashMap<String, u64> {
+    commands
+        .iter()
+        .enumerate()
+        .map(|(position, name)| (name.clone(), position as u64 + 1))
+        .collect()
+}
+
+/// Appends `value` as LEB128
+pub(crate) fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+/// Reads a LEB128 value, returns it together with the amount of bytes read
+pub(crate) fn read_varint<R: Read>(reader: &mut R) -> io::Result<(u64, u64)> {
+    let mut value = 0u64;
+    for position in 0..10 {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7f) as u64) << (7 * position);
+        if byte[0] & 0x80 == 0 {
+            return Ok((value, position + 1));
+        }
+    }
+    Err(io::Error::new(ErrorKind::InvalidData, "varint is too long"))
+}
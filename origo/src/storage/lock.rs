@@ -0,0 +1,61 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+/// Name of the lock file in the data directory
+pub const LOCK_FILE: &str = "origo.lock";
+
+/// Exclusive advisory lock (`flock`) on a data directory
+///
+/// Released when dropped (or when the process exits),
+/// the pid of the holder is written to the lock file for error messages
+pub struct DirectoryLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl DirectoryLock {
+    /// Fails with [`io::ErrorKind::WouldBlock`] when the lock is held by someone else
+    pub fn try_lock<P: AsRef<Path>>(directory: P) -> io::Result<Self> {
+        let path = directory.as_ref().join(LOCK_FILE);
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        // SAFETY: the descriptor is valid for the lifetime of `file`
+        match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } {
+            0 => {
+                file.set_len(0)?;
+                (&file).write_all(std::process::id().to_string().as_bytes())?;
+                Ok(DirectoryLock { file, path })
+            }
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// Pid of the process holding (or last holding) the lock on `directory`
+    pub fn holder<P: AsRef<Path>>(directory: P) -> Option<u32> {
+        std::fs::read_to_string(directory.as_ref().join(LOCK_FILE))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for DirectoryLock {
+    fn drop(&mut self) {
+        // SAFETY: the descriptor is valid for the lifetime of `file`
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
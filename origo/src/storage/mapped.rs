@@ -0,0 +1,92 @@
+use crate::{
+    codec::{Codec, Rkyv},
+    state::EngineState,
+    storage::{disk::split_snapshot, Compression, FileHeader, FormatError, SNAPSHOT_MAGIC},
+};
+
+use memmap2::Mmap;
+use rkyv::{
+    api::high::{HighDeserializer, HighValidator},
+    bytecheck::CheckBytes,
+    rancor, Archive, Deserialize,
+};
+use std::{any::type_name, fs::File, marker::PhantomData, path::Path};
+
+/// A snapshot written with the [`Rkyv`] codec, memory-mapped and validated once on open
+///
+/// Opening costs a validation pass over the mapping but no allocations,
+/// the archived model can be queried in place. [`crate::EngineBuilder::build_mapped`] serves reads from it while the engine restores.
+/// It's the state at the time of the snapshot, commands in the journal aren't applied.
+/// Compressed and encrypted snapshots can't be mapped
+///
+/// ```ignore
+/// let snapshot = MappedSnapshot::<EcomModel>::open("data/snap.origors")?;
+/// let count = snapshot.archived().orders.len();
+/// ```
+pub struct MappedSnapshot<TModel> {
+    data: Mmap,
+    state: EngineState,
+    model_offset: usize,
+    model: PhantomData<fn() -> TModel>,
+}
+
+impl<TModel> MappedSnapshot<TModel>
+where
+    TModel: Archive,
+    TModel::Archived: for<'a> CheckBytes<HighValidator<'a, rancor::Error>>
+        + Deserialize<TModel, HighDeserializer<rancor::Error>>,
+    Rkyv: Codec<TModel>,
+{
+    /// Opens a snapshot of an engine without [`crate::EngineBuilder::model_id`]
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, FormatError> {
+        MappedSnapshot::open_with_model_id(path, type_name::<TModel>())
+    }
+
+    /// Opens a snapshot written for the model with `model_id`, see [`crate::EngineBuilder::model_id`]
+    pub fn open_with_model_id<P: AsRef<Path>>(
+        path: P,
+        model_id: &str,
+    ) -> Result<Self, FormatError> {
+        let file = File::open(path)?;
+        // SAFETY: snapshots are replaced by a rename, never modified in place
+        let data = unsafe { Mmap::map(&file) }?;
+
+        if data.starts_with(&SNAPSHOT_MAGIC) {
+            let (header, _) = FileHeader::read_from(&mut &data[SNAPSHOT_MAGIC.len()..])?;
+            if header.compression != Compression::None {
+                return Err(FormatError::Compressed(header.compression));
+            }
+            if let Some(encryption) = header.encryption {
+                return Err(FormatError::Encrypted(encryption));
+            }
+        }
+
+        let (state, model) = split_snapshot::<TModel, Rkyv>(&data, model_id, None)?;
+        rkyv::access::<TModel::Archived, rancor::Error>(&model)
+            .map_err(|e| FormatError::Corrupt(e.to_string()))?;
+
+        let model_offset = data.len() - model.len();
+        Ok(MappedSnapshot {
+            data,
+            state,
+            model_offset,
+            model: PhantomData,
+        })
+    }
+
+    /// The archived model
+    pub fn archived(&self) -> &TModel::Archived {
+        // SAFETY: validated in `open` and the mapping is read-only
+        unsafe { rkyv::access_unchecked::<TModel::Archived>(&self.data[self.model_offset..]) }
+    }
+
+    /// The engine state at the time of the snapshot
+    pub fn state(&self) -> &EngineState {
+        &self.state
+    }
+
+    pub fn deserialize(&self) -> TModel {
+        rkyv::deserialize::<TModel, rancor::Error>(self.archived())
+            .expect("Failed to deserialize validated snapshot")
+    }
+}
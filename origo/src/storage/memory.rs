@@ -0,0 +1,253 @@
+use crate::{
+    codec::Codec,
+    context::Envelope,
+    engine::{Command, CommandRestoreFn},
+    restore::{observe_snapshot, ReplayProgress, RestoreObserver},
+    state::EngineState,
+    storage::{
+        disk::{encode_snapshot, split_snapshot},
+        format::model_id,
+        journal::{command_ids, frame_record, write_command_id},
+        replay_record, BackupFiles, BackupRole, Compression, FileHeader, JournalError,
+        JournalReader, Storage, BINCODE_CONFIG, JOURNAL_MAGIC, SNAPSHOT_FILE,
+    },
+};
+
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    io::{self, Cursor},
+    sync::Arc,
+};
+
+/// Keeps the journal and the snapshot in memory, in the format of the [`crate::storage::DiskStorage`] files
+///
+/// Clones share the buffers, an engine restored from a clone replays what the first engine committed.
+/// Meant for tests that check replay without touching the filesystem
+///
+/// ```
+/// # use bincode::{Decode, Encode};
+/// # use origo::{storage::MemoryStorage, Command, EngineBuilder, ExecutionContext};
+/// # #[derive(Encode, Decode, Default, Clone, PartialEq, Debug)]
+/// # struct Model { orders: Vec<u64> }
+/// # #[derive(Encode, Decode)]
+/// # struct InsertOrder(u64);
+/// # impl Command<Model> for InsertOrder {
+/// #     fn execute(&self, model: &mut Model, _ctx: &mut ExecutionContext) { model.orders.push(self.0) }
+/// # }
+/// let storage = MemoryStorage::new();
+/// let db = EngineBuilder::new(Model::default(), storage.clone())
+///     .register_command::<InsertOrder>("InsertOrder")
+///     .build();
+/// db.execute(InsertOrder(7));
+/// let expected = db.query(|model| model.clone());
+/// drop(db);
+///
+/// let db = EngineBuilder::new(Model::default(), storage)
+///     .register_command::<InsertOrder>("InsertOrder")
+///     .build();
+/// assert_eq!(expected, db.query(|model| model.clone()));
+/// ```
+#[derive(Default)]
+pub struct MemoryStorage {
+    journal: Arc<Mutex<Vec<u8>>>,
+    snapshot: Arc<Mutex<Vec<u8>>>,
+    command_count_current: u64,
+    commit_buffer: Vec<u8>,
+    /// Names of the registered commands, the command table of new journals
+    commands: Vec<String>,
+    /// Command ids of the current journal
+    command_ids: HashMap<String, u64>,
+    restore_observer: Option<Arc<dyn RestoreObserver>>,
+    model_id: Option<String>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+
+    /// Copy of the journal, can be written to a file and inspected with the tool
+    pub fn journal(&self) -> Vec<u8> {
+        self.journal.lock().clone()
+    }
+
+    /// Copy of the snapshot, empty until the first snapshot
+    pub fn snapshot(&self) -> Vec<u8> {
+        self.snapshot.lock().clone()
+    }
+
+    fn model<TModel>(&self) -> &str {
+        model_id::<TModel>(self.model_id.as_deref())
+    }
+
+    /// Replaces the journal with a header for the journal starting at `start_sequence`
+    fn reset_journal<TModel>(&mut self, codec: &str, start_sequence: u64) {
+        let mut journal = self.journal.lock();
+        journal.clear();
+        FileHeader::new(
+            model_id::<TModel>(self.model_id.as_deref()),
+            codec,
+            Compression::None,
+            None,
+            self.commands.clone(),
+            start_sequence,
+        )
+        .write_to(&JOURNAL_MAGIC, &mut *journal)
+        .expect("Failed to write journal header");
+
+        self.command_ids = command_ids(&self.commands);
+        self.command_count_current = 0;
+    }
+}
+
+/// Shares the buffers, not the pending record
+impl Clone for MemoryStorage {
+    fn clone(&self) -> Self {
+        MemoryStorage {
+            journal: self.journal.clone(),
+            snapshot: self.snapshot.clone(),
+            ..MemoryStorage::default()
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn prepare<TModel, C: Codec<T>, T: Command<TModel>>(
+        &mut self,
+        envelope: &Envelope,
+        command_name: &str,
+        command: &T,
+    ) {
+        let mut record = Vec::new();
+        write_command_id(&mut record, &self.command_ids, command_name);
+        bincode::encode_into_std_write(envelope, &mut record, BINCODE_CONFIG)
+            .expect("Failed to serialize envelope to bytes");
+        C::encode(command, &mut record)
+            .unwrap_or_else(|e| panic!("Failed to serialize command to bytes, {}", e));
+
+        frame_record(&mut record);
+        self.commit_buffer = record;
+    }
+
+    fn commit(&mut self) -> u64 {
+        self.journal.lock().extend_from_slice(&self.commit_buffer);
+        self.command_count_current += 1;
+        self.command_count_current
+    }
+
+    fn snapshot<TModel, C: Codec<TModel>>(&mut self, state: &EngineState, model: &TModel) {
+        let snapshot = encode_snapshot::<TModel, C>(self.model::<TModel>(), state, model);
+        *self.snapshot.lock() = snapshot;
+        self.reset_journal::<TModel>(C::NAME, state.sequence() + 1);
+    }
+
+    fn restore<TModel: Default, C: Codec<TModel>>(
+        &mut self,
+        restore_fns: &HashMap<String, CommandRestoreFn<TModel>>,
+        state: &mut EngineState,
+        snapshot_loaded: &mut dyn FnMut(&EngineState, &TModel),
+    ) -> TModel {
+        self.commands = restore_fns.keys().cloned().collect();
+        self.commands.sort();
+
+        let mut model = {
+            let snapshot = self.snapshot.lock();
+            match snapshot.is_empty() {
+                true => TModel::default(),
+                false => observe_snapshot(self.restore_observer.as_deref(), || {
+                    let (snapshot_state, model) =
+                        split_snapshot::<TModel, C>(&snapshot, self.model::<TModel>(), None)
+                            .unwrap_or_else(|e| panic!("Snapshot can't be restored, {}", e));
+                    state.restore_snapshot(snapshot_state);
+                    C::decode(&model).unwrap_or_else(|e| panic!("Snapshot is corrupt, {}", e))
+                }),
+            }
+        };
+
+        let start_sequence = state.sequence() + 1;
+        let journal = self.journal.clone();
+        let journal = journal.lock();
+        if journal.is_empty() {
+            snapshot_loaded(state, &model);
+            drop(journal);
+            self.reset_journal::<TModel>(C::NAME, start_sequence);
+            return model;
+        }
+
+        let reader = JournalReader::new(&journal[..], journal.len() as u64)
+            .unwrap_or_else(|e| panic!("Failed to read journal, {}", e));
+        let header = reader.header().expect("Journal has no header");
+        if let Err(e) = header.check(self.model::<TModel>(), C::NAME) {
+            panic!("Journal can't be restored, {}", e);
+        }
+        assert!(
+            header.start_sequence == start_sequence,
+            "Journal starts at {} but the snapshot ends at {}",
+            header.start_sequence,
+            start_sequence - 1
+        );
+        self.command_ids = command_ids(&header.commands);
+
+        let count = JournalReader::new(Cursor::new(&journal[..]), journal.len() as u64)
+            .and_then(|reader| reader.count_records().map_err(JournalError::from))
+            .unwrap_or_else(|e| panic!("Failed to read journal, {}", e));
+        let mut progress = ReplayProgress::start(
+            self.restore_observer.clone(),
+            journal.len() as u64,
+            state.sequence(),
+            state.sequence() + count,
+        );
+        snapshot_loaded(state, &model);
+        for record in reader {
+            let record = record.unwrap_or_else(|e| panic!("Journal restore failed, {}", e));
+            replay_record(
+                restore_fns,
+                state,
+                &mut model,
+                &record.name,
+                &record.data,
+                BINCODE_CONFIG,
+            );
+            progress.applied(
+                &record.name,
+                record.offset + record.length,
+                state.sequence(),
+            );
+        }
+        self.command_count_current = progress.finish();
+
+        model
+    }
+
+    fn restore_observer(&mut self, observer: Arc<dyn RestoreObserver>) {
+        self.restore_observer = Some(observer);
+    }
+
+    fn model_id(&mut self, id: &str) {
+        self.model_id = Some(id.to_owned());
+    }
+
+    /// Copies of the buffers, as the files of a [`crate::storage::DiskStorage`] with the journal `journal.origors`
+    fn backup_files(&mut self) -> io::Result<BackupFiles> {
+        let mut files = BackupFiles::new();
+        let snapshot = self.snapshot.lock().clone();
+        if !snapshot.is_empty() {
+            let len = snapshot.len() as u64;
+            files = files.file(
+                BackupRole::Snapshot,
+                SNAPSHOT_FILE,
+                Cursor::new(snapshot),
+                len,
+            );
+        }
+        let journal = self.journal.lock().clone();
+        let len = journal.len() as u64;
+        Ok(files.file(
+            BackupRole::Journal,
+            "journal.origors",
+            Cursor::new(journal),
+            len,
+        ))
+    }
+}
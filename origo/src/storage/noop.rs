@@ -1,20 +1,31 @@
-use crate::storage::Storage;
+use crate::{codec::Codec, storage::Storage};
 
 pub struct NoopStorage;
 
 impl Storage for NoopStorage {
-    fn prepare<TModel, T: crate::Command<TModel>>(&mut self, _command_name: &str, _command: &T) {}
+    fn prepare<TModel, C: Codec<T>, T: crate::Command<TModel>>(
+        &mut self,
+        _envelope: &crate::Envelope,
+        _command_name: &str,
+        _command: &T,
+    ) {
+    }
 
     fn commit(&mut self) -> u64 {
         0u64
     }
 
-    fn snapshot<TModel: bincode::Encode>(&mut self, _model: &TModel) {}
+    fn snapshot<TModel, C: Codec<TModel>>(&mut self, _state: &crate::EngineState, _model: &TModel) {
+    }
 
-    fn restore<TModel: Default + bincode::Decode>(
+    fn restore<TModel: Default, C: Codec<TModel>>(
         &mut self,
         _restore_fns: &std::collections::HashMap<String, crate::CommandRestoreFn<TModel>>,
+        state: &mut crate::EngineState,
+        snapshot_loaded: &mut dyn FnMut(&crate::EngineState, &TModel),
     ) -> TModel {
-        TModel::default()
+        let model = TModel::default();
+        snapshot_loaded(state, &model);
+        model
     }
 }
@@ -0,0 +1,142 @@
+use crate::{
+    engine::CommandRestoreFn,
+    restore::ReplayProgress,
+    state::EngineState,
+    storage::{
+        apply_record, decode_record, journal::RecordDecoder, JournalError, JournalRecord,
+        BINCODE_CONFIG,
+    },
+};
+
+use std::{collections::HashMap, sync::mpsc, thread};
+
+/// Records the reader hands to a decode worker at once
+const BATCH_RECORDS: usize = 256;
+
+/// Batches a worker decodes ahead of the replay
+const BATCHES_AHEAD: usize = 4;
+
+/// Upper bound of the decode workers, unless they're set with [`crate::storage::DiskStorage::replay_workers`]
+const MAX_WORKERS: usize = 8;
+
+/// Replays the raw `records` on the calling thread in journal order, while a thread reads them
+/// and workers decrypt and decompress them with `decoder` and decode their envelopes and commands
+///
+/// Batches are handed to the workers in turn and collected in the same turn, so the order is kept
+/// without sorting. Stops at the first error of `records` or of the decoder and returns it together
+/// with the amount of replayed records, the records before the error are replayed.
+/// `workers` defaults to one less than the available parallelism.
+/// Every applied record is counted in `progress`
+///
+/// # Panics
+///
+/// Panics like [`crate::storage::replay_record`] if a record can't be restored
+pub(crate) fn replay_records<TModel, I>(
+    records: I,
+    decoder: &RecordDecoder,
+    workers: Option<usize>,
+    restore_fns: &HashMap<String, CommandRestoreFn<TModel>>,
+    state: &mut EngineState,
+    model: &mut TModel,
+    progress: &mut ReplayProgress,
+) -> (u64, Option<JournalError>)
+where
+    I: Iterator<Item = Result<JournalRecord, JournalError>> + Send,
+{
+    let workers = workers.unwrap_or_else(|| {
+        thread::available_parallelism()
+            .map_or(1, |parallelism| parallelism.get().saturating_sub(1))
+            .clamp(1, MAX_WORKERS)
+    });
+
+    thread::scope(|scope| {
+        let mut batch_senders = Vec::with_capacity(workers);
+        let mut decoded_receivers = Vec::with_capacity(workers);
+        for _ in 0..workers {
+            let (batch_sender, batches) = mpsc::sync_channel::<Vec<JournalRecord>>(BATCHES_AHEAD);
+            let (decoded_sender, decoded) = mpsc::sync_channel(BATCHES_AHEAD);
+            scope.spawn(move || {
+                let mut compressor = None;
+                for batch in batches {
+                    let batch: Vec<_> = batch
+                        .into_iter()
+                        .map(|mut record| {
+                            decoder.decode(&mut record, &mut compressor)?;
+                            let decoded = decode_record(
+                                restore_fns,
+                                &record.name,
+                                &record.data,
+                                BINCODE_CONFIG,
+                            );
+                            Ok((record.name, record.offset + record.length, decoded))
+                        })
+                        .collect();
+                    if decoded_sender.send(batch).is_err() {
+                        return;
+                    }
+                }
+            });
+            batch_senders.push(batch_sender);
+            decoded_receivers.push(decoded);
+        }
+
+        let reader = scope.spawn(move || {
+            let mut next_worker = 0;
+            let mut send = |batch: Vec<JournalRecord>| {
+                if batch.is_empty() {
+                    return true;
+                }
+                let sent = batch_senders[next_worker].send(batch).is_ok();
+                next_worker = (next_worker + 1) % batch_senders.len();
+                sent
+            };
+
+            let mut batch = Vec::with_capacity(BATCH_RECORDS);
+            for record in records {
+                match record {
+                    Ok(record) => batch.push(record),
+                    Err(e) => {
+                        send(batch);
+                        return Some(e);
+                    }
+                }
+                if batch.len() == BATCH_RECORDS
+                    && !send(std::mem::replace(
+                        &mut batch,
+                        Vec::with_capacity(BATCH_RECORDS),
+                    ))
+                {
+                    return None;
+                }
+            }
+            send(batch);
+            None
+        });
+
+        // A worker only runs out of batches after the reader is done
+        let mut replayed = 0;
+        let mut decode_error = None;
+        'replay: for worker in (0..workers).cycle() {
+            let Ok(batch) = decoded_receivers[worker].recv() else {
+                break;
+            };
+            for record in batch {
+                let (name, offset, decoded) = match record {
+                    Ok(record) => record,
+                    Err(e) => {
+                        decode_error = Some(e);
+                        break 'replay;
+                    }
+                };
+                apply_record(state, model, &name, decoded);
+                progress.applied(&name, offset, state.sequence());
+                replayed += 1;
+            }
+        }
+
+        // Stops the reader and the workers after a decode error or if a worker panicked
+        drop(decoded_receivers);
+        let error = reader.join().expect("Journal reader panicked");
+        (replayed, decode_error.or(error))
+    })
+}
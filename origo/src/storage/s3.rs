@@ -0,0 +1,379 @@
+use crate::storage::{backup::hex, ObjectStore};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, ErrorKind, Read},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Size of the parts of a multipart upload, S3 needs at least 5 MiB for all but the last part
+pub const S3_PART_SIZE: usize = 8 * 1024 * 1024;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Objects in a bucket of an S3-compatible service (AWS S3, MinIO, ..)
+///
+/// Requests are signed with AWS Signature Version 4 and use path-style URLs,
+/// `{endpoint}/{bucket}/{key}`, which every S3-compatible service accepts.
+/// Staged files larger than the part size are uploaded with a multipart upload, one part in memory at a time
+///
+/// ```ignore
+/// let store = S3Store::new("http://localhost:9000", "backups", "minioadmin", "minioadmin");
+/// let storage = DiskStorage::new("data/journal.origors").archive(store);
+/// ```
+pub struct S3Store {
+    agent: ureq::Agent,
+    endpoint: String,
+    host: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    part_size: usize,
+}
+
+impl S3Store {
+    /// `endpoint` is the URL of the service, like `https://s3.eu-west-1.amazonaws.com`, region `us-east-1` by default
+    pub fn new(endpoint: &str, bucket: &str, access_key: &str, secret_key: &str) -> Self {
+        let endpoint = endpoint.trim_end_matches('/').to_owned();
+        let (scheme, authority) = endpoint
+            .split_once("://")
+            .unwrap_or_else(|| panic!("Endpoint {} is not a URL", endpoint));
+        let authority = authority.split('/').next().unwrap_or_default();
+        let host = match (scheme, authority.rsplit_once(':')) {
+            ("http", Some((host, "80"))) | ("https", Some((host, "443"))) => host,
+            _ => authority,
+        }
+        .to_owned();
+
+        S3Store {
+            agent: agent(CONNECT_TIMEOUT, IO_TIMEOUT),
+            endpoint,
+            host,
+            bucket: bucket.to_owned(),
+            region: "us-east-1".to_owned(),
+            access_key: access_key.to_owned(),
+            secret_key: secret_key.to_owned(),
+            part_size: S3_PART_SIZE,
+        }
+    }
+
+    /// Timeouts of the requests, 10s to connect and 60s to read or write by default
+    pub fn timeouts(mut self, connect: Duration, io: Duration) -> Self {
+        self.agent = agent(connect, io);
+        self
+    }
+
+    /// Size of the parts of multipart uploads, [`S3_PART_SIZE`] by default
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is 0
+    pub fn part_size(mut self, bytes: usize) -> Self {
+        assert!(bytes > 0, "Part size must be larger than 0");
+        self.part_size = bytes;
+        self
+    }
+
+    /// Region the requests are signed for, services that don't have regions accept `us-east-1`
+    pub fn region(mut self, region: &str) -> Self {
+        self.region = region.to_owned();
+        self
+    }
+
+    /// Sends a signed request, `key` empty for requests on the bucket
+    fn request(
+        &self,
+        method: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<ureq::Response> {
+        let path = match key {
+            "" => format!("/{}", uri_encode(&self.bucket, true)),
+            key => format!(
+                "/{}/{}",
+                uri_encode(&self.bucket, true),
+                uri_encode(key, false)
+            ),
+        };
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(name, value)| (uri_encode(name, true), uri_encode(value, true)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let timestamp = amz_timestamp(SystemTime::now());
+        let date = &timestamp[..8];
+        let payload_hash = hex(&Sha256::digest(body));
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, query, self.host, payload_hash, timestamp, SIGNED_HEADERS, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let signing_key = [date, &self.region, "s3", "aws4_request"].iter().fold(
+            format!("AWS4{}", self.secret_key).into_bytes(),
+            |key, part| hmac(&key, part.as_bytes()),
+        );
+        let signature = hex(&hmac(&signing_key, string_to_sign.as_bytes()));
+
+        let url = match query.as_str() {
+            "" => format!("{}{}", self.endpoint, path),
+            query => format!("{}{}?{}", self.endpoint, path, query),
+        };
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, SIGNED_HEADERS, signature
+        );
+        let result = self
+            .agent
+            .request(method, &url)
+            .set("host", &self.host)
+            .set("x-amz-content-sha256", &payload_hash)
+            .set("x-amz-date", &timestamp)
+            .set("authorization", &authorization)
+            .send_bytes(body);
+
+        match result {
+            Ok(response) => Ok(response),
+            Err(ureq::Error::Status(404, _)) => Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("{} {} not found", method, url),
+            )),
+            Err(ureq::Error::Status(status, response)) => Err(io::Error::other(format!(
+                "{} {} failed with {}, {}",
+                method,
+                url,
+                status,
+                response.into_string().unwrap_or_default()
+            ))),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+
+    /// Uploads `len` bytes of `reader` in parts, returns the ETags of the parts
+    fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        reader: &mut dyn Read,
+        len: u64,
+    ) -> io::Result<Vec<String>> {
+        let mut part = vec![0u8; self.part_size];
+        let mut etags = Vec::new();
+        let mut left = len;
+        while left > 0 {
+            let part = &mut part[..left.min(self.part_size as u64) as usize];
+            reader.read_exact(part)?;
+            let number = (etags.len() + 1).to_string();
+            let response = self.request(
+                "PUT",
+                key,
+                &[("partNumber", &number), ("uploadId", upload_id)],
+                part,
+            )?;
+            let etag = response.header("etag").ok_or_else(|| {
+                io::Error::other(format!("part {} of {} has no ETag", number, key))
+            })?;
+            etags.push(etag.to_owned());
+            left -= part.len() as u64;
+        }
+        Ok(etags)
+    }
+
+    fn complete_upload(&self, key: &str, upload_id: &str, etags: &[String]) -> io::Result<()> {
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (index, etag) in etags.iter().enumerate() {
+            body.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                index + 1,
+                xml_escape(etag)
+            ));
+        }
+        body.push_str("</CompleteMultipartUpload>");
+
+        // Fails with status 200 and an error in the body if it fails after it started
+        let response = self
+            .request("POST", key, &[("uploadId", upload_id)], body.as_bytes())?
+            .into_string()
+            .map_err(io::Error::other)?;
+        match xml_values(&response, "Code").pop() {
+            Some(code) => Err(io::Error::other(format!(
+                "completing the upload of {} failed with {}",
+                key, code
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+impl ObjectStore for S3Store {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        self.request("PUT", key, &[], data).map(|_| ())
+    }
+
+    fn put_reader(&self, key: &str, reader: &mut dyn Read, len: u64) -> io::Result<()> {
+        if len <= self.part_size as u64 {
+            let mut data = Vec::with_capacity(len as usize);
+            reader.take(len).read_to_end(&mut data)?;
+            return self.put(key, &data);
+        }
+
+        let response = self
+            .request("POST", key, &[("uploads", "")], &[])?
+            .into_string()
+            .map_err(io::Error::other)?;
+        let upload_id = xml_values(&response, "UploadId").pop().ok_or_else(|| {
+            io::Error::other(format!(
+                "starting the upload of {} returned no UploadId",
+                key
+            ))
+        })?;
+
+        let uploaded = self
+            .upload_parts(key, &upload_id, reader, len)
+            .and_then(|etags| self.complete_upload(key, &upload_id, &etags));
+        if uploaded.is_err() {
+            // The service keeps the parts until the upload is aborted
+            let _ = self.request("DELETE", key, &[("uploadId", &upload_id)], &[]);
+        }
+        uploaded
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.request("GET", key, &[], &[])?
+            .into_reader()
+            .read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut continuation: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2")];
+            if let Some(token) = &continuation {
+                query.push(("continuation-token", token.as_str()));
+            }
+            let body = self
+                .request("GET", "", &query, &[])?
+                .into_string()
+                .map_err(io::Error::other)?;
+
+            keys.extend(xml_values(&body, "Key"));
+            continuation = match xml_values(&body, "IsTruncated").first().map(String::as_str) {
+                Some("true") => xml_values(&body, "NextContinuationToken").pop(),
+                _ => None,
+            };
+            if continuation.is_none() {
+                return Ok(keys);
+            }
+        }
+    }
+}
+
+fn agent(connect: Duration, io: Duration) -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(connect)
+        .timeout_read(io)
+        .timeout_write(io)
+        .build()
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encodes all but the unreserved characters, and `/` unless `encode_slash`
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// `YYYYMMDDTHHMMSSZ` in UTC
+fn amz_timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .expect("Clock is before 1970")
+        .as_secs();
+    let (days, seconds) = (seconds / 86_400, seconds % 86_400);
+
+    // Civil date from days since 1970-01-01, http://howardhinnant.github.io/date_algorithms.html
+    let days = days as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3_600,
+        seconds % 3_600 / 60,
+        seconds % 60
+    )
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// The unescaped text of the elements named `name`
+fn xml_values(xml: &str, name: &str) -> Vec<String> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    xml.split(open.as_str())
+        .skip(1)
+        .filter_map(|part| part.split_once(close.as_str()))
+        .map(|(value, _)| {
+            value
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&")
+        })
+        .collect()
+}
@@ -0,0 +1,304 @@
+use crate::{
+    codec::Codec,
+    context::Envelope,
+    engine::{Command, CommandRestoreFn},
+    restore::{observe_snapshot, ReplayProgress, RestoreObserver},
+    state::EngineState,
+    storage::{
+        disk::{encode_snapshot, split_snapshot},
+        format::model_id,
+        replay_record, Storage, BINCODE_CONFIG, FORMAT_VERSION,
+    },
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+/// When a commit is durable, mapped to the `synchronous` setting of SQLite in WAL mode
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// `synchronous = FULL`, every commit is synced before it returns
+    #[default]
+    Full,
+    /// `synchronous = NORMAL`, a crash of the process loses nothing but a power loss
+    /// can lose the commits since the last checkpoint of the write-ahead log
+    Normal,
+}
+
+/// Keeps the journal and the snapshot in a single SQLite database
+///
+/// Tables, readable with the sqlite3 CLI:
+/// - `journal (seq, name, payload, ts)`: a row per command, the payload is the bincode envelope followed by
+///   the command encoded with the codec of the engine, `ts` is the commit time in milliseconds
+/// - `snapshot (id, seq, created, data)`: the latest snapshot, `data` has the layout of a snapshot file,
+///   `seq` is the last command it contains
+/// - `origo (model, codec, format_version)`: what the database was created for
+///
+/// A snapshot replaces the previous one and deletes the journal rows it contains in one transaction.
+/// The database is opened in exclusive locking mode, only one engine at a time can use it
+pub struct SqliteStorage {
+    connection: Connection,
+    command_count_current: u64,
+    /// The record of [`Storage::prepare`]
+    pending: Option<(u64, String, Vec<u8>, u64)>,
+    restore_observer: Option<Arc<dyn RestoreObserver>>,
+    /// Id of the model in the database, see [`crate::EngineBuilder::model_id`]
+    model_id: Option<String>,
+}
+
+impl SqliteStorage {
+    /// Opens (or creates) the database at `path` with [`Durability::Full`]
+    ///
+    /// # Panics
+    ///
+    /// Panics if the database can't be opened or is used by another process
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        if let Some(directory) = path.as_ref().parent() {
+            std::fs::create_dir_all(directory).expect("Failed to create directory structure");
+        }
+
+        let connection = Connection::open(path.as_ref())
+            .unwrap_or_else(|e| panic!("Failed to open database {:?}, {}", path.as_ref(), e));
+        connection
+            .execute_batch(
+                "PRAGMA locking_mode = EXCLUSIVE;
+                 PRAGMA journal_mode = WAL;
+                 PRAGMA synchronous = FULL;
+                 CREATE TABLE IF NOT EXISTS journal (
+                     seq INTEGER PRIMARY KEY,
+                     name TEXT NOT NULL,
+                     payload BLOB NOT NULL,
+                     ts INTEGER NOT NULL
+                 );
+                 CREATE TABLE IF NOT EXISTS snapshot (
+                     id INTEGER PRIMARY KEY CHECK (id = 0),
+                     seq INTEGER NOT NULL,
+                     created INTEGER NOT NULL,
+                     data BLOB NOT NULL
+                 );
+                 CREATE TABLE IF NOT EXISTS origo (
+                     id INTEGER PRIMARY KEY CHECK (id = 0),
+                     model TEXT NOT NULL,
+                     codec TEXT NOT NULL,
+                     format_version INTEGER NOT NULL
+                 );",
+            )
+            .unwrap_or_else(|e| {
+                panic!(
+                    "Failed to open database {:?}, it may be used by another process, {}",
+                    path.as_ref(),
+                    e
+                )
+            });
+
+        SqliteStorage {
+            connection,
+            command_count_current: 0,
+            pending: None,
+            restore_observer: None,
+            model_id: None,
+        }
+    }
+
+    /// When commits are durable, [`Durability::Full`] by default
+    pub fn durability(self, durability: Durability) -> Self {
+        let synchronous = match durability {
+            Durability::Full => "FULL",
+            Durability::Normal => "NORMAL",
+        };
+        self.connection
+            .pragma_update(None, "synchronous", synchronous)
+            .expect("Failed to set durability");
+        self
+    }
+
+    fn model<TModel>(&self) -> &str {
+        model_id::<TModel>(self.model_id.as_deref())
+    }
+
+    /// Records the model and codec in a new database, checks them in an existing one
+    fn check<TModel>(&self, codec: &str) {
+        let expected = self.model::<TModel>();
+        let found: Option<(String, String)> = self
+            .connection
+            .query_row("SELECT model, codec FROM origo", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()
+            .expect("Failed to read database info");
+
+        match found {
+            None => {
+                self.connection
+                    .execute(
+                        "INSERT INTO origo (id, model, codec, format_version) VALUES (0, ?1, ?2, ?3)",
+                        params![expected, codec, FORMAT_VERSION],
+                    )
+                    .expect("Failed to write database info");
+            }
+            Some((model, _)) if model != expected => panic!(
+                "Database was written for model {} but the engine uses {}, \
+                 build the engine with `EngineBuilder::model_id({:?})` if it's the same model",
+                model, expected, model
+            ),
+            Some((_, found)) if found != codec => panic!(
+                "Database was written with codec {} but the engine uses {}",
+                found, codec
+            ),
+            Some(_) => {}
+        }
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn prepare<TModel, C: Codec<T>, T: Command<TModel>>(
+        &mut self,
+        envelope: &Envelope,
+        command_name: &str,
+        command: &T,
+    ) {
+        let mut payload = Vec::new();
+        bincode::encode_into_std_write(envelope, &mut payload, BINCODE_CONFIG)
+            .expect("Failed to serialize envelope to bytes");
+        C::encode(command, &mut payload)
+            .unwrap_or_else(|e| panic!("Failed to serialize command to bytes, {}", e));
+
+        self.pending = Some((
+            envelope.sequence,
+            command_name.to_owned(),
+            payload,
+            envelope.timestamp,
+        ));
+    }
+
+    fn commit(&mut self) -> u64 {
+        let (sequence, name, payload, timestamp) =
+            self.pending.take().expect("Commit without prepare");
+        self.connection
+            .prepare_cached("INSERT INTO journal (seq, name, payload, ts) VALUES (?1, ?2, ?3, ?4)")
+            .and_then(|mut statement| {
+                statement.execute(params![sequence as i64, name, payload, timestamp as i64])
+            })
+            .expect("Failed to commit command");
+
+        self.command_count_current += 1;
+        self.command_count_current
+    }
+
+    fn snapshot<TModel, C: Codec<TModel>>(&mut self, state: &EngineState, model: &TModel) {
+        let data = encode_snapshot::<TModel, C>(self.model::<TModel>(), state, model);
+        let created = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_millis() as i64);
+        let transaction = self
+            .connection
+            .transaction()
+            .expect("Snapshot write failed");
+        transaction
+            .execute(
+                "INSERT OR REPLACE INTO snapshot (id, seq, created, data) VALUES (0, ?1, ?2, ?3)",
+                params![state.sequence() as i64, created, data],
+            )
+            .and_then(|_| {
+                transaction.execute(
+                    "DELETE FROM journal WHERE seq <= ?1",
+                    params![state.sequence() as i64],
+                )
+            })
+            .expect("Snapshot write failed");
+        transaction.commit().expect("Snapshot write failed");
+        self.command_count_current = 0;
+    }
+
+    fn restore<TModel: Default, C: Codec<TModel>>(
+        &mut self,
+        restore_fns: &HashMap<String, CommandRestoreFn<TModel>>,
+        state: &mut EngineState,
+        snapshot_loaded: &mut dyn FnMut(&EngineState, &TModel),
+    ) -> TModel {
+        self.check::<TModel>(C::NAME);
+
+        let snapshot: Option<Vec<u8>> = self
+            .connection
+            .query_row("SELECT data FROM snapshot WHERE id = 0", [], |row| {
+                row.get(0)
+            })
+            .optional()
+            .expect("Failed to read snapshot");
+        let mut model = match snapshot {
+            Some(data) => observe_snapshot(self.restore_observer.as_deref(), || {
+                let (snapshot_state, model) =
+                    split_snapshot::<TModel, C>(&data, self.model::<TModel>(), None)
+                        .unwrap_or_else(|e| panic!("Snapshot can't be restored, {}", e));
+                state.restore_snapshot(snapshot_state);
+                C::decode(&model).unwrap_or_else(|e| panic!("Snapshot is corrupt, {}", e))
+            }),
+            None => TModel::default(),
+        };
+
+        // The payloads after the snapshot are the bytes of the replay
+        let bytes_total: i64 = self
+            .connection
+            .query_row(
+                "SELECT coalesce(sum(length(payload)), 0) FROM journal WHERE seq > ?1",
+                params![state.sequence() as i64],
+                |row| row.get(0),
+            )
+            .expect("Failed to read journal");
+        let last_sequence: i64 = self
+            .connection
+            .query_row("SELECT coalesce(max(seq), 0) FROM journal", [], |row| {
+                row.get(0)
+            })
+            .expect("Failed to read journal");
+        let mut progress = ReplayProgress::start(
+            self.restore_observer.clone(),
+            bytes_total as u64,
+            state.sequence(),
+            last_sequence as u64,
+        );
+        snapshot_loaded(state, &model);
+        let mut bytes_read = 0;
+
+        let mut statement = self
+            .connection
+            .prepare("SELECT seq, name, payload FROM journal WHERE seq > ?1 ORDER BY seq")
+            .expect("Failed to read journal");
+        let mut rows = statement
+            .query(params![state.sequence() as i64])
+            .expect("Failed to read journal");
+        while let Some(row) = rows.next().expect("Failed to read journal") {
+            let sequence: i64 = row.get(0).expect("Failed to read journal");
+            let name: String = row.get(1).expect("Failed to read journal");
+            let payload: Vec<u8> = row.get(2).expect("Failed to read journal");
+            assert!(
+                sequence as u64 == state.sequence() + 1,
+                "Journal continues at {} after {}, commands are missing",
+                sequence,
+                state.sequence()
+            );
+
+            replay_record(
+                restore_fns,
+                state,
+                &mut model,
+                &name,
+                &payload,
+                BINCODE_CONFIG,
+            );
+            bytes_read += payload.len() as u64;
+            progress.applied(&name, bytes_read, state.sequence());
+        }
+
+        self.command_count_current = progress.finish();
+        model
+    }
+
+    fn restore_observer(&mut self, observer: Arc<dyn RestoreObserver>) {
+        self.restore_observer = Some(observer);
+    }
+
+    fn model_id(&mut self, id: &str) {
+        self.model_id = Some(id.to_owned());
+    }
+}
@@ -0,0 +1,264 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+};
+
+#[cfg(feature = "mmap-writer")]
+use memmap2::MmapMut;
+
+#[cfg(feature = "fault-injection")]
+use crate::storage::faulty::SimulatedFile;
+
+const BUFFER_CAPACITY: usize = 32 * 1024;
+
+/// How [`crate::storage::DiskStorage`] appends journal records
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JournalWriter {
+    /// `BufWriter<File>` and `fdatasync` on every commit
+    #[default]
+    Buffered,
+    /// Like `Buffered`, but the journal is preallocated with `fallocate` in segments of
+    /// `segment_len` bytes, so most commits don't change the file size.
+    /// The preallocated space is trimmed when the storage is dropped
+    Preallocated { segment_len: u64 },
+    /// Records are copied into a memory-mapped journal that is preallocated in steps of
+    /// `grow_by` bytes, commits only `fdatasync` as the file size doesn't change.
+    /// The preallocated space is trimmed when the storage is dropped
+    #[cfg(feature = "mmap-writer")]
+    Mapped { grow_by: u64 },
+}
+
+/// Appends records to the journal, positioned with [`Writer::reset`]
+pub(crate) enum Writer {
+    Buffered(BufWriter<File>),
+    Preallocated(PreallocatedWriter),
+    #[cfg(feature = "mmap-writer")]
+    Mapped(MappedWriter),
+    /// Writes through another writer and injects faults, see [`crate::storage::DiskStorage::faults`]
+    #[cfg(feature = "fault-injection")]
+    Simulated(Box<SimulatedFile>),
+}
+
+impl Writer {
+    pub(crate) fn new(kind: JournalWriter, file: &File) -> io::Result<Self> {
+        let file = file.try_clone()?;
+        Ok(match kind {
+            JournalWriter::Buffered => {
+                Writer::Buffered(BufWriter::with_capacity(BUFFER_CAPACITY, file))
+            }
+            JournalWriter::Preallocated { segment_len } => {
+                Writer::Preallocated(PreallocatedWriter {
+                    writer: BufWriter::with_capacity(BUFFER_CAPACITY, file),
+                    position: None,
+                    allocated: 0,
+                    segment_len: segment_len.max(1),
+                })
+            }
+            #[cfg(feature = "mmap-writer")]
+            JournalWriter::Mapped { grow_by } => Writer::Mapped(MappedWriter {
+                file,
+                map: None,
+                position: None,
+                grow_by: grow_by.max(1),
+            }),
+        })
+    }
+
+    /// Must be called before the journal is truncated
+    pub(crate) fn release(&mut self) {
+        if let Writer::Preallocated(writer) = self {
+            writer.position = None;
+        }
+        #[cfg(feature = "mmap-writer")]
+        if let Writer::Mapped(writer) = self {
+            writer.map = None;
+            writer.position = None;
+        }
+        #[cfg(feature = "fault-injection")]
+        if let Writer::Simulated(file) = self {
+            file.writer().release();
+        }
+    }
+
+    /// Continues writing at `position`
+    pub(crate) fn reset(&mut self, position: u64) -> io::Result<()> {
+        match self {
+            Writer::Buffered(writer) => writer.seek(SeekFrom::Start(position)).map(|_| ()),
+            Writer::Preallocated(writer) => {
+                writer.writer.seek(SeekFrom::Start(position))?;
+                writer.position = Some(position);
+                writer.allocated = writer.writer.get_ref().metadata()?.len();
+                Ok(())
+            }
+            #[cfg(feature = "mmap-writer")]
+            Writer::Mapped(writer) => {
+                writer.map = None;
+                writer.position = Some(position);
+                Ok(())
+            }
+            #[cfg(feature = "fault-injection")]
+            Writer::Simulated(file) => file.reset(position),
+        }
+    }
+
+    /// Offset of the next record, the end of the records in a preallocated journal
+    pub(crate) fn position(&mut self) -> io::Result<u64> {
+        match self {
+            Writer::Buffered(writer) => writer.stream_position(),
+            Writer::Preallocated(PreallocatedWriter {
+                position: Some(position),
+                ..
+            }) => Ok(*position),
+            Writer::Preallocated(writer) => writer.writer.stream_position(),
+            #[cfg(feature = "mmap-writer")]
+            Writer::Mapped(MappedWriter {
+                position: Some(position),
+                ..
+            }) => Ok(*position),
+            #[cfg(feature = "mmap-writer")]
+            Writer::Mapped(writer) => writer.file.metadata().map(|metadata| metadata.len()),
+            #[cfg(feature = "fault-injection")]
+            Writer::Simulated(file) => file.writer().position(),
+        }
+    }
+
+    pub(crate) fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Writer::Buffered(writer) => writer.write_all(data),
+            Writer::Preallocated(writer) => writer.append(data),
+            #[cfg(feature = "mmap-writer")]
+            Writer::Mapped(writer) => writer.append(data),
+            #[cfg(feature = "fault-injection")]
+            Writer::Simulated(file) => file.append(data),
+        }
+    }
+
+    /// Makes the appended records durable
+    pub(crate) fn sync(&mut self) -> io::Result<()> {
+        match self {
+            // fdatasync skips the metadata that isn't needed to read the data, like the modification time
+            Writer::Buffered(writer) | Writer::Preallocated(PreallocatedWriter { writer, .. }) => {
+                writer.flush()?;
+                writer.get_ref().sync_data()
+            }
+            // fdatasync covers pages written through the mapping
+            #[cfg(feature = "mmap-writer")]
+            Writer::Mapped(writer) => writer.file.sync_data(),
+            #[cfg(feature = "fault-injection")]
+            Writer::Simulated(file) => file.sync(),
+        }
+    }
+}
+
+pub(crate) struct PreallocatedWriter {
+    writer: BufWriter<File>,
+    /// Offset of the next record, `None` until positioned
+    position: Option<u64>,
+    /// Length of the file, the space after `position` is preallocated
+    allocated: u64,
+    segment_len: u64,
+}
+
+impl PreallocatedWriter {
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        let position = self
+            .position
+            .ok_or_else(|| io::Error::other("journal writer isn't positioned"))?;
+        let end = position + data.len() as u64;
+
+        if end > self.allocated {
+            self.allocated = end.next_multiple_of(self.segment_len);
+            allocate(self.writer.get_ref(), self.allocated)?;
+        }
+
+        self.writer.write_all(data)?;
+        self.position = Some(end);
+        Ok(())
+    }
+}
+
+impl Drop for PreallocatedWriter {
+    /// Trims the preallocated space
+    fn drop(&mut self) {
+        if let Some(position) = self.position {
+            if let Err(e) = self
+                .writer
+                .flush()
+                .and_then(|_| self.writer.get_ref().set_len(position))
+            {
+                log::warn!("Failed to trim preallocated journal, {}", e);
+            }
+        }
+    }
+}
+
+/// Extends `file` to `len` bytes of allocated, zeroed blocks,
+/// sparse where the file system doesn't support `fallocate`
+fn allocate(file: &File, len: u64) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        // SAFETY: the descriptor is owned by `file`
+        match unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) } {
+            0 => return Ok(()),
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                    return Err(e);
+                }
+            }
+        }
+    }
+    file.set_len(len)
+}
+
+#[cfg(feature = "mmap-writer")]
+pub(crate) struct MappedWriter {
+    file: File,
+    map: Option<MmapMut>,
+    /// Offset of the next record, `None` until positioned
+    position: Option<u64>,
+    grow_by: u64,
+}
+
+#[cfg(feature = "mmap-writer")]
+impl MappedWriter {
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        let position = self
+            .position
+            .ok_or_else(|| io::Error::other("journal writer isn't positioned"))?;
+        let end = position + data.len() as u64;
+
+        let mapped_len = self.map.as_ref().map_or(0, |map| map.len() as u64);
+        if end > mapped_len {
+            self.map = None;
+            let file_len = self.file.metadata()?.len();
+            if end > file_len {
+                allocate(&self.file, end.next_multiple_of(self.grow_by))?;
+                // The new size must be durable before commits only sync data
+                self.file.sync_all()?;
+            }
+            // SAFETY: the journal is locked by the storage and only truncated after release
+            self.map = Some(unsafe { MmapMut::map_mut(&self.file) }?);
+        }
+
+        let map = self.map.as_mut().expect("Journal is mapped");
+        map[position as usize..end as usize].copy_from_slice(data);
+        self.position = Some(end);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "mmap-writer")]
+impl Drop for MappedWriter {
+    /// Trims the preallocated space
+    fn drop(&mut self) {
+        self.map = None;
+        if let Some(position) = self.position {
+            if let Err(e) = self.file.set_len(position) {
+                log::warn!("Failed to trim preallocated journal, {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+mod common;
+
+use bincode::{Decode, Encode};
+use common::{data_directory, wait_for_unlock};
+use origo::{
+    origo_engine,
+    storage::{
+        restore_from_backup, BackupManifest, BackupRole, DiskStorage, MemoryStorage,
+        BACKUP_MANIFEST,
+    },
+    Command, EngineBuilder, ExecutionContext,
+};
+use std::{io::ErrorKind, thread};
+
+#[derive(Encode, Decode, Default, Clone, PartialEq, Debug)]
+struct Log {
+    values: Vec<u64>,
+}
+
+#[derive(Encode, Decode)]
+struct Push(u64);
+
+impl Command<Log> for Push {
+    fn execute(&self, model: &mut Log, _ctx: &mut ExecutionContext) {
+        model.values.push(self.0);
+    }
+}
+
+fn commands(engine: EngineBuilder<Log, DiskStorage>) -> EngineBuilder<Log, DiskStorage> {
+    engine.register_command::<Push>("Push")
+}
+
+#[test]
+fn backup_restores_to_the_sequence_of_its_manifest() {
+    let data = tempfile::tempdir().expect("Failed to create directory");
+    let backup = tempfile::tempdir().expect("Failed to create directory");
+    let path = data.path().join("journal.origors");
+
+    let db = common::open(&path, commands);
+    db.snapshot_command_count(10);
+    for i in 1..=10 {
+        db.execute(Push(i));
+    }
+    let writer = {
+        let db = db.clone();
+        thread::spawn(move || {
+            for i in 11..=400 {
+                db.execute(Push(i));
+            }
+        })
+    };
+    // Snapshots reset the journal while it's copied
+    db.snapshot_command_count(7);
+    let manifest = db.backup_to(backup.path()).expect("Backup failed");
+    writer.join().expect("Writer failed");
+    drop(db);
+    wait_for_unlock(data.path());
+
+    assert!(manifest.sequence >= 10, "{:?}", manifest);
+    assert_eq!(
+        BackupManifest::read_from(backup.path()).expect("Failed to read manifest"),
+        manifest
+    );
+    manifest
+        .verify(backup.path())
+        .expect("Backup doesn't match its manifest");
+    assert!(manifest
+        .files
+        .iter()
+        .any(|file| file.role == BackupRole::Journal));
+
+    let (_restored, restored_path) = data_directory();
+    assert_eq!(
+        restore_from_backup(backup.path(), &restored_path).expect("Restore failed"),
+        manifest.sequence
+    );
+    let db = common::open(&restored_path, commands);
+    let expected: Vec<u64> = (1..=manifest.sequence).collect();
+    assert_eq!(db.query(|log| log.values.clone()), expected);
+}
+
+#[test]
+fn corrupt_backup_is_not_restored() {
+    let backup = tempfile::tempdir().expect("Failed to create directory");
+    let storage = MemoryStorage::new();
+    let db = origo_engine! { Log, storage, Push, };
+    for i in 1..=5 {
+        db.execute(Push(i));
+    }
+    let manifest = db.backup_to(backup.path()).expect("Backup failed");
+    assert_eq!(manifest.sequence, 5);
+
+    let journal = backup.path().join(&manifest.files[0].name);
+    let mut bytes = std::fs::read(&journal).unwrap();
+    *bytes.last_mut().unwrap() ^= 1;
+    std::fs::write(&journal, bytes).unwrap();
+
+    let (_restored, restored_path) = data_directory();
+    let error =
+        restore_from_backup(backup.path(), &restored_path).expect_err("Restored corrupt backup");
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert!(error.to_string().contains("is corrupt"), "{}", error);
+    assert!(!restored_path.exists());
+}
+
+#[test]
+fn backup_is_only_restored_into_an_empty_directory() {
+    let backup = tempfile::tempdir().expect("Failed to create directory");
+    let db = origo_engine! { Log, MemoryStorage::new(), Push, };
+    db.execute(Push(1));
+    db.backup_to(backup.path()).expect("Backup failed");
+    assert!(backup.path().join(BACKUP_MANIFEST).exists());
+
+    let (_data, path) = data_directory();
+    let db = common::open(&path, commands);
+    db.execute(Push(2));
+    drop(db);
+
+    let error = restore_from_backup(backup.path(), &path).expect_err("Overwrote a journal");
+    assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+    assert_eq!(
+        common::open(&path, commands).query(|log| log.values.clone()),
+        [2]
+    );
+}
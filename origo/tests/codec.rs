@@ -0,0 +1,175 @@
+use bincode::{Decode, Encode};
+use origo::{
+    origo_engine,
+    storage::{JournalReader, MemoryStorage, BINCODE_CONFIG},
+    Bincode, Codec, Command, ExecutionContext,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, thread, time::Duration};
+
+#[derive(Encode, Decode, Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+struct Catalog {
+    items: BTreeMap<u32, Item>,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+struct Item {
+    name: String,
+    price: i64,
+    tags: Vec<String>,
+    discontinued: Option<bool>,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+struct Put {
+    id: u32,
+    item: Item,
+}
+
+impl Command<Catalog> for Put {
+    fn execute(&self, model: &mut Catalog, _ctx: &mut ExecutionContext) {
+        model.items.insert(self.id, self.item.clone());
+    }
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+struct Remove(u32);
+
+impl Command<Catalog> for Remove {
+    fn execute(&self, model: &mut Catalog, _ctx: &mut ExecutionContext) {
+        model.items.remove(&self.0);
+    }
+}
+
+fn put(i: u32) -> Put {
+    Put {
+        id: i % 9,
+        item: Item {
+            name: format!("item \"{}\" ünïcode", i),
+            price: i as i64 * 100 - 450,
+            tags: (0..i % 4).map(|tag| format!("tag-{}", tag)).collect(),
+            discontinued: i.is_multiple_of(3).then_some(i.is_multiple_of(2)),
+        },
+    }
+}
+
+/// Encodes `value`, checks that it decodes to an equal value and that trailing bytes are rejected
+fn value_round_trip<C: Codec<T>, T: PartialEq + std::fmt::Debug>(value: &T) {
+    let mut encoded = Vec::new();
+    C::encode(value, &mut encoded).expect("Failed to encode");
+    assert_eq!(&C::decode(&encoded).expect("Failed to decode"), value);
+
+    encoded.push(0);
+    assert!(
+        C::decode(&encoded).is_err(),
+        "{} decoded a value with a trailing byte",
+        C::NAME
+    );
+}
+
+/// Writes a snapshot and a journal with the codec `C`, restores them and reads the records back
+fn round_trip<C>()
+where
+    C: Codec<Catalog> + Codec<Put> + Codec<Remove>,
+{
+    value_round_trip::<C, _>(&put(3));
+    value_round_trip::<C, _>(&Remove(4));
+
+    let storage = MemoryStorage::new();
+    let db = origo_engine! { codec C, Catalog, storage.clone(), Put, Remove, };
+    db.snapshot_command_count(10);
+    for i in 0..10 {
+        db.execute(put(i));
+    }
+    while storage.snapshot().is_empty() {
+        thread::sleep(Duration::from_millis(1));
+    }
+    db.snapshot_command_count(0);
+    // Waits for the snapshot, it holds the storage until the journal is reset
+    db.execute(Remove(2));
+    for i in 10..14 {
+        db.execute(put(i));
+    }
+    let expected = db.query(|catalog| catalog.clone());
+    drop(db);
+
+    let journal = storage.journal();
+    let reader =
+        JournalReader::new(&journal[..], journal.len() as u64).expect("Failed to read journal");
+    assert_eq!(
+        reader.header().expect("Journal has a header").codec,
+        <C as Codec<Catalog>>::NAME
+    );
+    let records: Vec<_> = reader
+        .map(|record| record.expect("Failed to read record"))
+        .collect();
+    assert_eq!(records.len(), 5);
+    let (_, payload) = records[0]
+        .envelope(BINCODE_CONFIG)
+        .expect("Failed to decode envelope");
+    assert_eq!(<C as Codec<Remove>>::decode(payload).unwrap(), Remove(2));
+    let (_, payload) = records[4]
+        .envelope(BINCODE_CONFIG)
+        .expect("Failed to decode envelope");
+    assert_eq!(<C as Codec<Put>>::decode(payload).unwrap(), put(13));
+
+    let db = origo_engine! { codec C, Catalog, storage, Put, Remove, };
+    assert_eq!(db.query(|catalog| catalog.clone()), expected);
+}
+
+#[test]
+fn bincode_round_trips() {
+    round_trip::<Bincode>();
+}
+
+#[cfg(feature = "postcard")]
+#[test]
+fn postcard_round_trips() {
+    round_trip::<origo::Postcard>();
+}
+
+#[cfg(feature = "msgpack")]
+#[test]
+fn msgpack_round_trips() {
+    round_trip::<origo::MessagePack>();
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json_round_trips() {
+    round_trip::<origo::Json>();
+}
+
+#[cfg(feature = "rkyv")]
+#[test]
+fn rkyv_round_trips() {
+    round_trip::<origo::Rkyv>();
+}
+
+#[cfg(feature = "json")]
+#[test]
+#[should_panic(expected = "written with codec json but the engine uses bincode")]
+fn restore_fails_with_another_codec() {
+    let storage = MemoryStorage::new();
+    let db = origo_engine! { codec origo::Json, Catalog, storage.clone(), Put, Remove, };
+    db.execute(put(1));
+    drop(db);
+
+    origo_engine! { Catalog, storage, Put, Remove, };
+}
@@ -0,0 +1,88 @@
+//! Fixtures shared by the integration tests
+#![allow(dead_code)]
+
+use origo::{
+    storage::{DirectoryLock, DiskStorage, SNAPSHOT_FILE},
+    Engine, EngineBuilder,
+};
+use std::{
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+use tempfile::TempDir;
+
+/// How long [`wait_for_unlock`] waits for the engines on a directory to be dropped
+pub const UNLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A temporary data directory and the path of its journal, the directory is removed when dropped
+pub fn data_directory() -> (TempDir, PathBuf) {
+    let directory = tempfile::tempdir().expect("Failed to create directory");
+    let path = directory.path().join("journal.origors");
+    (directory, path)
+}
+
+/// Waits until snapshot, seal and replay threads dropped their clones of the engine
+///
+/// # Panics
+///
+/// Panics if the directory is still locked after [`UNLOCK_TIMEOUT`], an engine leaked
+pub fn wait_for_unlock(directory: &Path) {
+    let started = Instant::now();
+    while DirectoryLock::try_lock(directory).is_err() {
+        assert!(
+            started.elapsed() < UNLOCK_TIMEOUT,
+            "Data directory {:?} is still locked after {:?}, an engine wasn't dropped",
+            directory,
+            UNLOCK_TIMEOUT
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Opens the journal at `path` once the engines that used it are gone
+pub fn storage(path: &Path) -> DiskStorage {
+    wait_for_unlock(path.parent().expect("Journal is in a directory"));
+    DiskStorage::new(path)
+}
+
+/// Registers the commands of a model
+pub type Commands<TModel> =
+    fn(EngineBuilder<TModel, DiskStorage>) -> EngineBuilder<TModel, DiskStorage>;
+
+/// Builds an engine on the journal at `path` with `commands`, see [`storage`]
+pub fn open<TModel>(path: &Path, commands: Commands<TModel>) -> Engine<TModel, DiskStorage>
+where
+    TModel: Default + Send + Sync + bincode::Encode + bincode::Decode + 'static,
+{
+    open_with(storage(path), commands)
+}
+
+/// Like [`open`] with a configured storage, create it with [`storage`]
+pub fn open_with<TModel>(
+    storage: DiskStorage,
+    commands: Commands<TModel>,
+) -> Engine<TModel, DiskStorage>
+where
+    TModel: Default + Send + Sync + bincode::Encode + bincode::Decode + 'static,
+{
+    commands(EngineBuilder::new(TModel::default(), storage)).build()
+}
+
+/// Waits until the snapshot thread of an engine on `directory` wrote the first snapshot
+///
+/// # Panics
+///
+/// Panics if there's no snapshot after [`UNLOCK_TIMEOUT`]
+pub fn wait_for_snapshot(directory: &Path) {
+    let started = Instant::now();
+    while !directory.join(SNAPSHOT_FILE).exists() {
+        assert!(
+            started.elapsed() < UNLOCK_TIMEOUT,
+            "No snapshot in {:?} after {:?}",
+            directory,
+            UNLOCK_TIMEOUT
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+}
@@ -0,0 +1,148 @@
+mod common;
+
+use bincode::{Decode, Encode};
+use common::{data_directory, wait_for_unlock, UNLOCK_TIMEOUT};
+use origo::{
+    storage::{DiskStorage, JournalReader, SEGMENT_DIRECTORY, TOMBSTONE_COMMAND},
+    Command, Engine, EngineBuilder, ExecutionContext, Idempotent,
+};
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Encode, Decode, Default, Clone, PartialEq, Debug)]
+struct Settings {
+    values: BTreeMap<String, u64>,
+    notes: Vec<String>,
+}
+
+/// Keyed, overwrites the value of `key`
+#[derive(Encode, Decode)]
+struct Set {
+    key: String,
+    value: u64,
+}
+
+impl Command<Settings> for Set {
+    fn execute(&self, model: &mut Settings, _ctx: &mut ExecutionContext) {
+        model.values.insert(self.key.clone(), self.value);
+    }
+
+    fn compaction_key(&self) -> Option<String> {
+        Some(self.key.clone())
+    }
+}
+
+/// Without a key, always kept
+#[derive(Encode, Decode)]
+struct Note(String);
+
+impl Command<Settings> for Note {
+    fn execute(&self, model: &mut Settings, _ctx: &mut ExecutionContext) {
+        model.notes.push(self.0.clone());
+    }
+}
+
+fn commands(engine: EngineBuilder<Settings, DiskStorage>) -> EngineBuilder<Settings, DiskStorage> {
+    engine
+        .register_command::<Set>("Set")
+        .register_command::<Note>("Note")
+}
+
+fn open(path: &Path) -> Engine<Settings, DiskStorage> {
+    common::open_with(common::storage(path).compact_journal(), commands)
+}
+
+fn set(key: &str, value: u64) -> Set {
+    Set {
+        key: key.to_string(),
+        value,
+    }
+}
+
+fn segments(directory: &Path) -> Vec<(u64, u64)> {
+    let mut segments: Vec<(u64, u64)> = std::fs::read_dir(directory.join(SEGMENT_DIRECTORY))
+        .map(|entries| {
+            entries
+                .filter_map(|entry| {
+                    let name = entry.ok()?.file_name().into_string().ok()?;
+                    let range = name.strip_prefix("segment-")?.strip_suffix(".origors")?;
+                    let (start, end) = range.split_once('-')?;
+                    Some((start.parse().ok()?, end.parse().ok()?))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    segments.sort();
+    segments
+}
+
+/// Waits until the seal on another thread wrote a segment up to `sequence`
+fn wait_for_seal(directory: &Path, sequence: u64) {
+    let started = Instant::now();
+    while segments(directory).iter().all(|(_, end)| *end < sequence) {
+        assert!(
+            started.elapsed() < UNLOCK_TIMEOUT,
+            "No segment up to {} after {:?}",
+            sequence,
+            UNLOCK_TIMEOUT
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn compacted_segments_replay_to_the_same_model() {
+    let (directory, path) = data_directory();
+
+    let db = open(&path);
+    db.snapshot_command_count(4);
+    db.execute_idempotent("first-a", set("a", 1));
+    db.execute(set("b", 1));
+    db.execute(Note("one".to_string()));
+    db.execute(set("a", 2));
+    wait_for_seal(directory.path(), 4);
+    db.execute(set("b", 2));
+    db.execute_idempotent("second-a", set("a", 3));
+    db.execute(Note("two".to_string()));
+    db.execute(set("c", 1));
+    wait_for_seal(directory.path(), 8);
+    db.execute(set("a", 4));
+    db.execute(set("c", 2));
+    let expected = db.query(|model| model.clone());
+    drop(db);
+
+    // The compactor merges the segments when the engine is restored, dropping the engine waits for it
+    let db = open(&path);
+    assert_eq!(db.query(|model| model.clone()), expected);
+    drop(db);
+    wait_for_unlock(directory.path());
+    assert_eq!(segments(directory.path()), vec![(1, 8)]);
+
+    let names: Vec<String> = JournalReader::open(
+        directory
+            .path()
+            .join(SEGMENT_DIRECTORY)
+            .join(format!("segment-{:020}-{:020}.origors", 1, 8)),
+    )
+    .expect("Failed to open segment")
+    .map(|record| record.expect("Failed to read record").name)
+    .collect();
+    // a(1) with first-a is kept as a tombstone, b(1) and a(2) are dropped,
+    // a(4) is in the journal, which isn't compacted
+    assert_eq!(
+        names,
+        [TOMBSTONE_COMMAND, "Note", "Set", "Set", "Note", "Set"]
+    );
+
+    let db = open(&path);
+    assert_eq!(db.query(|model| model.clone()), expected);
+    assert!(matches!(
+        db.execute_idempotent("first-a", set("a", 1)),
+        Idempotent::Duplicate(receipt) if receipt.sequence == 1
+    ));
+    assert_eq!(db.query(|model| model.values["a"]), 4);
+}
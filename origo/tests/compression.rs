@@ -0,0 +1,84 @@
+#![cfg(any(feature = "zstd", feature = "lz4"))]
+
+use bincode::{Decode, Encode};
+use origo::{
+    origo_engine,
+    storage::{Compression, DiskStorage, JournalReader},
+    Command, ExecutionContext,
+};
+use std::path::Path;
+
+#[derive(Encode, Decode, Default, PartialEq, Debug)]
+struct Notes {
+    notes: Vec<String>,
+}
+
+#[derive(Encode, Decode)]
+struct AddNote(String);
+
+impl Command<Notes> for AddNote {
+    fn execute(&self, model: &mut Notes, _ctx: &mut ExecutionContext) {
+        model.notes.push(self.0.clone());
+    }
+}
+
+fn note(i: usize) -> String {
+    format!(
+        "Order {} shipped to customer {} in city {}",
+        i,
+        i % 70,
+        i % 9
+    )
+}
+
+fn storage(path: &Path, compression: Compression) -> DiskStorage {
+    let storage = DiskStorage::new(path).compression(compression);
+    #[cfg(feature = "encryption")]
+    let storage = storage.encryption(origo::storage::StaticKey([7; 32]));
+    storage
+}
+
+/// Writes a journal, a snapshot that trains the dictionary and a second journal, restores all of it
+fn round_trip(compression: Compression) {
+    let directory = tempfile::tempdir().expect("Failed to create directory");
+    let path = directory.path().join("journal.origors");
+
+    let db = origo_engine! { Notes, storage(&path, compression), AddNote, };
+    db.snapshot_command_count(300);
+    for i in 0..400 {
+        db.execute(AddNote(note(i)));
+    }
+    let expected = db.query(|notes| notes.notes.clone());
+    drop(db);
+
+    let header = JournalReader::open(&path)
+        .expect("Failed to open journal")
+        .header()
+        .cloned()
+        .expect("Journal has a header");
+    assert_eq!(header.compression, compression);
+    assert!(
+        !header.dictionary.is_empty(),
+        "No dictionary after the snapshot"
+    );
+
+    let db = origo_engine! { Notes, storage(&path, compression), AddNote, };
+    assert_eq!(db.query(|notes| notes.notes.clone()), expected);
+    db.execute(AddNote(note(400)));
+    drop(db);
+
+    let db = origo_engine! { Notes, storage(&path, compression), AddNote, };
+    assert_eq!(db.query(|notes| notes.notes.len()), 401);
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn zstd_records_round_trip_with_a_dictionary() {
+    round_trip(Compression::Zstd { level: 3 });
+}
+
+#[cfg(feature = "lz4")]
+#[test]
+fn lz4_records_round_trip_with_a_dictionary() {
+    round_trip(Compression::Lz4);
+}
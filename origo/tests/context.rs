@@ -0,0 +1,150 @@
+use bincode::{Decode, Encode};
+use origo::{
+    origo_engine,
+    storage::{JournalReader, MemoryStorage, BINCODE_CONFIG},
+    Command, CommandMeta, EngineBuilder, ExecutionContext,
+};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::UNIX_EPOCH,
+};
+
+#[derive(Encode, Decode, Default, Clone, PartialEq, Debug)]
+struct Audit {
+    entries: Vec<(u64, CommandMeta)>,
+}
+
+/// Records the metadata it's executed with
+#[derive(Encode, Decode)]
+struct Record;
+
+impl Command<Audit> for Record {
+    fn execute(&self, model: &mut Audit, ctx: &mut ExecutionContext) {
+        model.entries.push((ctx.sequence(), ctx.meta().clone()));
+    }
+}
+
+#[test]
+fn command_meta_round_trips_through_the_journal() {
+    let storage = MemoryStorage::new();
+    let db = origo_engine! { Audit, storage.clone(), Record, };
+    let meta = CommandMeta::default()
+        .actor("alice")
+        .correlation_id("request-7")
+        .header("ip", "10.0.0.1")
+        .header("client", "cli");
+    db.execute_with(meta.clone(), Record);
+    db.execute(Record);
+    let expected = db.query(|audit| audit.clone());
+    assert_eq!(expected.entries[0], (1, meta.clone()));
+    drop(db);
+
+    let journal = storage.journal();
+    let records: Vec<_> = JournalReader::new(&journal[..], journal.len() as u64)
+        .expect("Failed to read journal")
+        .map(|record| record.expect("Failed to read record"))
+        .collect();
+    let (envelope, _) = records[0]
+        .envelope(BINCODE_CONFIG)
+        .expect("Failed to decode envelope");
+    assert_eq!(envelope.meta, meta);
+    let (envelope, _) = records[1]
+        .envelope(BINCODE_CONFIG)
+        .expect("Failed to decode envelope");
+    assert_eq!(envelope.meta, CommandMeta::default());
+
+    // The replayed commands get the journaled metadata
+    let db = origo_engine! { Audit, storage, Record, };
+    assert_eq!(db.query(|audit| audit.clone()), expected);
+}
+
+#[derive(Encode, Decode, Default, Clone, PartialEq, Debug)]
+struct Draws {
+    draws: Vec<Draw>,
+}
+
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
+struct Draw {
+    now_ms: u128,
+    random: u64,
+    in_range: u64,
+    ids: [u64; 2],
+}
+
+/// Takes the time, randomness and ids from the context
+#[derive(Encode, Decode)]
+struct Roll;
+
+impl Command<Draws> for Roll {
+    fn execute(&self, model: &mut Draws, ctx: &mut ExecutionContext) {
+        let now_ms = ctx
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Commit time is after the epoch")
+            .as_millis();
+        let random = ctx.rng().next_u64();
+        let in_range = ctx.rng().gen_range(10..20);
+        let ids = [ctx.next_id(), ctx.next_id()];
+        model.draws.push(Draw {
+            now_ms,
+            random,
+            in_range,
+            ids,
+        });
+    }
+}
+
+/// Counts its executions outside of the model, a different result every time
+#[derive(Encode, Decode)]
+struct Count;
+
+static EXECUTIONS: AtomicU64 = AtomicU64::new(0);
+
+impl Command<Draws> for Count {
+    fn execute(&self, model: &mut Draws, _ctx: &mut ExecutionContext) {
+        let random = EXECUTIONS.fetch_add(1, Ordering::Relaxed);
+        model.draws.push(Draw {
+            now_ms: 0,
+            random,
+            in_range: 0,
+            ids: [0; 2],
+        });
+    }
+}
+
+#[test]
+fn context_values_replay_to_the_same_values() {
+    let storage = MemoryStorage::new();
+    let db = origo_engine! { Draws, storage.clone(), Roll, };
+    for _ in 0..20 {
+        db.execute(Roll);
+    }
+    let expected = db.query(|draws| draws.clone());
+    drop(db);
+
+    let first = &expected.draws[0];
+    assert!((10..20).contains(&first.in_range));
+    assert_ne!(first.ids[0], first.ids[1]);
+    assert_ne!(first.random, expected.draws[1].random);
+
+    let db = origo_engine! { Draws, storage, Roll, };
+    assert_eq!(db.query(|draws| draws.clone()), expected);
+}
+
+#[cfg(debug_assertions)]
+#[test]
+#[should_panic(expected = "Command Count(1) is not deterministic")]
+fn debug_builds_verify_determinism() {
+    let db = origo_engine! { Draws, MemoryStorage::new(), Count, };
+    db.execute(Count);
+}
+
+#[test]
+fn determinism_check_can_be_turned_off() {
+    let db = EngineBuilder::new(Draws::default(), MemoryStorage::new())
+        .register_command::<Count>("Count")
+        .verify_determinism(false)
+        .build();
+    db.execute(Count);
+    assert_eq!(db.query(|draws| draws.draws.len()), 1);
+}
@@ -0,0 +1,333 @@
+//! Crash-consistency check of `DiskStorage`
+//!
+//! Every scenario executes a random command sequence in a child process and injects a fault that
+//! crashes it, the child aborts on the injected panic. The test restores and checks that the model
+//! equals the acknowledged history, plus the command that was being committed if its record survived.
+//! The restored journal must accept new commands.
+//!
+//! `ORIGO_CRASH_RUNS=1000 ORIGO_CRASH_SEED=1 cargo test -p origo --test crash` runs more scenarios,
+//! a failure names the seed to reproduce it with `ORIGO_CRASH_RUNS=1`
+
+mod common;
+
+use bincode::{Decode, Encode};
+use origo::{
+    storage::{DiskStorage, Fault, Faults, FaultyStorage, JournalWriter, NoopStorage, Storage},
+    Command, Engine, EngineBuilder, ExecutionContext,
+};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Write,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    process::Command as Process,
+};
+
+/// Set in the child process, the seed of its scenario
+const CHILD_SEED: &str = "ORIGO_CRASH_CHILD_SEED";
+const CHILD_DIRECTORY: &str = "ORIGO_CRASH_CHILD_DIRECTORY";
+
+#[derive(Encode, Decode, Default, Clone, PartialEq, Debug)]
+struct Bank {
+    accounts: BTreeMap<u32, Account>,
+}
+
+#[derive(Encode, Decode, Default, Clone, PartialEq, Debug)]
+struct Account {
+    owner: String,
+    balance: i64,
+}
+
+#[derive(Encode, Decode)]
+struct Open {
+    account: u32,
+    owner: String,
+}
+
+impl Command<Bank> for Open {
+    fn execute(&self, model: &mut Bank, _ctx: &mut ExecutionContext) {
+        model.accounts.entry(self.account).or_default().owner = self.owner.clone();
+    }
+}
+
+#[derive(Encode, Decode)]
+struct Transfer {
+    from: u32,
+    to: u32,
+    amount: i64,
+}
+
+impl Command<Bank> for Transfer {
+    fn execute(&self, model: &mut Bank, _ctx: &mut ExecutionContext) {
+        model.accounts.entry(self.from).or_default().balance -= self.amount;
+        model.accounts.entry(self.to).or_default().balance += self.amount;
+    }
+}
+
+/// SplitMix64, the scenarios are reproducible from their seed
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self, below: u64) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        (z ^ (z >> 31)) % below
+    }
+}
+
+enum Action {
+    Open(u32, String),
+    Transfer(u32, u32, i64),
+}
+
+/// A crash scenario, derived from its seed in the test and in the child
+struct Scenario {
+    actions: Vec<Action>,
+    snapshot_every: u64,
+    journal_writer: JournalWriter,
+    fault: (u64, Fault),
+}
+
+impl Scenario {
+    fn new(seed: u64) -> Self {
+        let mut rng = Rng(seed);
+        let commands = 10 + rng.next(90);
+        let snapshot_every = 3 + rng.next(30);
+        let snapshots = (commands / snapshot_every).max(1);
+        let fault = match rng.next(5) {
+            0 => (1 + rng.next(commands), Fault::FailWrite),
+            1 => (
+                1 + rng.next(commands),
+                Fault::TornWrite {
+                    bytes: rng.next(256),
+                },
+            ),
+            2 => (
+                1 + rng.next(commands),
+                Fault::FailSync {
+                    bytes: rng.next(256),
+                },
+            ),
+            3 => (1 + rng.next(snapshots), Fault::FailSnapshot),
+            _ => (1 + rng.next(snapshots), Fault::InterruptSnapshot),
+        };
+        let journal_writer = match rng.next(3) {
+            0 => JournalWriter::Buffered,
+            #[cfg(feature = "mmap-writer")]
+            1 => JournalWriter::Mapped {
+                grow_by: 1 + rng.next(4096),
+            },
+            _ => JournalWriter::Preallocated {
+                segment_len: 1 + rng.next(4096),
+            },
+        };
+
+        let actions = (0..commands + 5)
+            .map(|_| match rng.next(4) {
+                0 => Action::Open(rng.next(16) as u32, "x".repeat(rng.next(200) as usize)),
+                _ => Action::Transfer(
+                    rng.next(16) as u32,
+                    rng.next(16) as u32,
+                    rng.next(1_000) as i64,
+                ),
+            })
+            .collect();
+
+        Scenario {
+            actions,
+            snapshot_every,
+            journal_writer,
+            fault,
+        }
+    }
+
+    /// The actions up to the crash, the remaining five are executed after the restore
+    fn before_crash(&self) -> &[Action] {
+        &self.actions[..self.actions.len() - 5]
+    }
+}
+
+fn build<S: Storage>(storage: S) -> Engine<Bank, S> {
+    EngineBuilder::new(Bank::default(), storage)
+        .register_command::<Open>("Open")
+        .register_command::<Transfer>("Transfer")
+        .build()
+}
+
+fn execute<S: Storage + Send + 'static>(db: &Engine<Bank, S>, action: &Action) {
+    match action {
+        Action::Open(account, owner) => db.execute(Open {
+            account: *account,
+            owner: owner.clone(),
+        }),
+        Action::Transfer(from, to, amount) => db.execute(Transfer {
+            from: *from,
+            to: *to,
+            amount: *amount,
+        }),
+    };
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    payload
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| payload.downcast_ref::<&str>().copied())
+        .unwrap_or_default()
+}
+
+/// Executes the scenario of the parent with the fault injected and appends every
+/// acknowledged command to `acks`, does nothing when the test isn't run as a child
+#[test]
+fn crash_child() {
+    let (Ok(seed), Ok(directory)) = (std::env::var(CHILD_SEED), std::env::var(CHILD_DIRECTORY))
+    else {
+        return;
+    };
+    let directory = Path::new(&directory);
+
+    // An injected fault stops the process like a crash, without unwinding
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if panic_message(info.payload()).contains("Injected fault") {
+            std::process::abort();
+        }
+        default_hook(info);
+    }));
+
+    let scenario = Scenario::new(seed.parse().expect("Seed is a number"));
+    let (n, fault) = scenario.fault;
+    let faults = Faults::new().at(n, fault);
+    let path = directory.join("data").join("journal.origors");
+    let disk = DiskStorage::new(&path)
+        .journal_writer(scenario.journal_writer)
+        .faults(faults.clone());
+    let db = build(FaultyStorage::new(disk, faults).journal_file(&path));
+    db.snapshot_command_count(scenario.snapshot_every);
+
+    // Written through to the kernel, the acknowledgements survive the abort
+    let mut acks = File::create(directory.join("acks")).expect("Failed to create acks");
+    for action in scenario.before_crash() {
+        execute(&db, action);
+        writeln!(acks, "ack").expect("Failed to acknowledge");
+    }
+}
+
+/// Runs one crash scenario in a child process, returns whether it crashed
+fn run(seed: u64) -> Result<bool, String> {
+    let directory = tempfile::tempdir().expect("Failed to create directory");
+    let scenario = Scenario::new(seed);
+    let describe = || {
+        let (n, fault) = scenario.fault;
+        format!(
+            "seed {}: {:?} in {} of {} commands, snapshots every {}, {:?}",
+            seed,
+            fault,
+            n,
+            scenario.before_crash().len(),
+            scenario.snapshot_every,
+            scenario.journal_writer
+        )
+    };
+
+    let status = Process::new(std::env::current_exe().expect("Failed to find the test binary"))
+        .args(["crash_child", "--exact", "--nocapture", "--test-threads=1"])
+        .env(CHILD_SEED, seed.to_string())
+        .env(CHILD_DIRECTORY, directory.path())
+        .output()
+        .expect("Failed to run the child");
+    // Aborted by an injected fault, a failure of the child exits with an error code instead
+    let crashed = status.status.code().is_none();
+    if !crashed && !status.status.success() {
+        return Err(format!(
+            "{}, the child failed\n{}",
+            describe(),
+            String::from_utf8_lossy(&status.stdout)
+        ));
+    }
+    let acknowledged = std::fs::read_to_string(directory.path().join("acks"))
+        .unwrap_or_default()
+        .lines()
+        .count();
+
+    // The models after every action
+    let reference = build(NoopStorage);
+    let mut history = vec![Bank::default()];
+    for action in &scenario.actions {
+        execute(&reference, action);
+        history.push(reference.query(|model| model.clone()));
+    }
+
+    let path = directory.path().join("data").join("journal.origors");
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let db = build(DiskStorage::new(&path).journal_writer(scenario.journal_writer));
+        let restored = db.query(|model| model.clone());
+        let in_flight = history.get(acknowledged + 1).filter(|_| crashed);
+        if restored != history[acknowledged] && Some(&restored) != in_flight {
+            return Err(format!(
+                "{}, the restored model isn't the {} acknowledged commands",
+                describe(),
+                acknowledged
+            ));
+        }
+
+        for action in &scenario.actions[scenario.actions.len() - 5..] {
+            execute(&db, action);
+        }
+        let expected = db.query(|model| model.clone());
+        drop(db);
+
+        // A snapshot thread may still hold the storage
+        let db = build(common::storage(&path).journal_writer(scenario.journal_writer));
+        if db.query(|model| model.clone()) != expected {
+            return Err(format!(
+                "{}, commands after the restore were lost",
+                describe()
+            ));
+        }
+        Ok(crashed)
+    }))
+    .unwrap_or_else(|payload| {
+        Err(format!(
+            "{}, the restore panicked: {}",
+            describe(),
+            panic_message(payload.as_ref())
+        ))
+    })
+}
+
+#[test]
+fn restore_after_crash() {
+    let number = |name: &str, default| {
+        std::env::var(name).map_or(default, |value| value.parse().expect("Number expected"))
+    };
+    let runs = number("ORIGO_CRASH_RUNS", 60);
+    let seed = number("ORIGO_CRASH_SEED", 1);
+
+    let mut crashes = 0;
+    let mut failures = Vec::new();
+    for run_seed in seed..seed + runs {
+        match run(run_seed) {
+            Ok(crashed) => crashes += crashed as u64,
+            Err(e) => failures.push(e),
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "{} of {} scenarios failed\n{}",
+        failures.len(),
+        runs,
+        failures.join("\n")
+    );
+    // Snapshot faults past the last snapshot don't crash
+    assert!(
+        crashes > runs / 2,
+        "Only {} of {} scenarios crashed",
+        crashes,
+        runs
+    );
+}
@@ -0,0 +1,181 @@
+#![cfg(feature = "encryption")]
+
+mod common;
+
+use bincode::{Decode, Encode};
+use common::data_directory;
+use origo::{
+    origo_engine,
+    storage::{DiskStorage, JournalReader, KeyProvider, KeyRing, StaticKey, SNAPSHOT_FILE},
+    Command, ExecutionContext,
+};
+use std::path::Path;
+
+const SECRET: &str = "patient 4711 diagnosed with";
+
+#[derive(Encode, Decode, Default, Clone, PartialEq, Debug)]
+struct Records {
+    entries: Vec<String>,
+}
+
+#[derive(Encode, Decode)]
+struct Append(String);
+
+impl Command<Records> for Append {
+    fn execute(&self, model: &mut Records, _ctx: &mut ExecutionContext) {
+        model.entries.push(self.0.clone());
+    }
+}
+
+fn entry(i: usize) -> String {
+    format!("{} condition {}", SECRET, i)
+}
+
+fn open<K: KeyProvider>(path: &Path, keys: K) -> origo::Engine<Records, DiskStorage> {
+    origo_engine! { Records, common::storage(path).encryption(keys), Append, }
+}
+
+/// Executes `count` commands, with the last one the journal has `snapshot_at` records and is snapshotted
+fn write<K: KeyProvider>(path: &Path, keys: K, count: usize, snapshot_at: u64) -> Records {
+    let db = open(path, keys);
+    db.snapshot_command_count(snapshot_at);
+    let start = db.query(|records| records.entries.len());
+    for i in start..start + count {
+        db.execute(Append(entry(i)));
+    }
+    let expected = db.query(|records| records.clone());
+    drop(db);
+    // The snapshot thread holds a clone of the engine until the snapshot is written
+    common::wait_for_unlock(path.parent().expect("Journal is in a directory"));
+    expected
+}
+
+fn journal_key_id(path: &Path) -> Option<u32> {
+    JournalReader::open(path)
+        .expect("Failed to open journal")
+        .header()
+        .expect("Journal has a header")
+        .encryption
+        .map(|encryption| encryption.key_id)
+}
+
+fn contains(haystack: &[u8], needle: &str) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle.as_bytes())
+}
+
+#[test]
+fn journal_and_snapshot_are_encrypted_at_rest() {
+    let (directory, path) = data_directory();
+    write(&path, StaticKey([3; 32]), 20, 20);
+    let expected = write(&path, StaticKey([3; 32]), 10, 0);
+
+    assert_eq!(journal_key_id(&path), Some(0));
+    for file in [path.clone(), directory.path().join(SNAPSHOT_FILE)] {
+        let bytes = std::fs::read(&file).expect("Failed to read file");
+        assert!(!contains(&bytes, SECRET), "{:?} has plaintext", file);
+    }
+
+    let db = open(&path, StaticKey([3; 32]));
+    assert_eq!(db.query(|records| records.clone()), expected);
+}
+
+#[test]
+fn rotated_keys_read_files_of_the_previous_key() {
+    let (_directory, path) = data_directory();
+    write(&path, KeyRing::new(1, [1; 32]), 5, 5);
+    write(&path, KeyRing::new(1, [1; 32]), 5, 0);
+    assert_eq!(journal_key_id(&path), Some(1));
+
+    // The snapshot and the journal of key 1 are read, the next snapshot switches to key 2
+    let rotated = || KeyRing::new(2, [2; 32]).with_key(1, [1; 32]);
+    let db = open(&path, rotated());
+    assert_eq!(db.query(|records| records.entries.len()), 10);
+    drop(db);
+
+    let expected = write(&path, rotated(), 3, 8);
+    assert_eq!(expected.entries.len(), 13);
+    assert_eq!(journal_key_id(&path), Some(2));
+    let db = open(&path, rotated());
+    assert_eq!(db.query(|records| records.clone()), expected);
+}
+
+#[test]
+#[should_panic(expected = "key 1 isn't known to the key provider")]
+fn journal_needs_the_key_it_was_written_with() {
+    let (_directory, path) = data_directory();
+    write(&path, KeyRing::new(1, [1; 32]), 3, 0);
+
+    open(&path, KeyRing::new(2, [2; 32]));
+}
+
+#[test]
+#[should_panic(expected = "Journal restore failed")]
+fn journal_written_with_another_key_fails_to_restore() {
+    let (_directory, path) = data_directory();
+    write(&path, StaticKey([1; 32]), 3, 0);
+
+    open(&path, StaticKey([9; 32]));
+}
+
+#[test]
+#[should_panic(expected = "encrypted with sequence 1, the envelope has sequence 2")]
+fn record_sealed_for_another_sequence_fails_to_restore() {
+    use chacha20poly1305::{
+        aead::{Aead, Payload},
+        ChaCha20Poly1305, KeyInit,
+    };
+
+    let (_directory, path) = data_directory();
+    write(&path, StaticKey([1; 32]), 2, 0);
+    let records: Vec<(u64, u64)> = JournalReader::open(&path)
+        .and_then(|reader| reader.keys(&StaticKey([1; 32])))
+        .expect("Failed to open journal")
+        .map(|record| record.expect("Failed to read record"))
+        .map(|record| (record.offset, record.length))
+        .collect();
+
+    // Reseals the second record with the nonce and authenticated data of the first
+    let journal = std::fs::read(&path).expect("Failed to read journal");
+    let (offset, length) = records[1];
+    let record = &journal[offset as usize..(offset + length) as usize];
+    let prefix_len = record.iter().position(|byte| byte & 0x80 == 0).unwrap() + 1;
+    // The command id of `Append` is a single byte, the nonce is the salt followed by the sequence
+    let (nonce, ciphertext) = record[prefix_len + 5..].split_at(12);
+    let aead = ChaCha20Poly1305::new(&[1; 32].into());
+    let aad = |sequence: u64| [&b"Append"[..], &sequence.to_le_bytes()].concat();
+    let plaintext = aead
+        .decrypt(
+            nonce.into(),
+            Payload {
+                msg: ciphertext,
+                aad: &aad(2),
+            },
+        )
+        .expect("Failed to decrypt record");
+    let forged_nonce = [&nonce[..4], &1u64.to_le_bytes()].concat();
+    let forged = aead
+        .encrypt(
+            forged_nonce[..].into(),
+            Payload {
+                msg: &plaintext,
+                aad: &aad(1),
+            },
+        )
+        .unwrap();
+
+    let body = [
+        &record[prefix_len + 4..prefix_len + 5],
+        &forged_nonce,
+        &forged,
+    ]
+    .concat();
+    let mut journal = journal[..records[0].0 as usize].to_vec();
+    journal.extend_from_slice(&record[..prefix_len]);
+    journal.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+    journal.extend_from_slice(&body);
+    std::fs::write(&path, journal).expect("Failed to write journal");
+
+    open(&path, StaticKey([1; 32]));
+}
@@ -0,0 +1,113 @@
+use bincode::{Decode, Encode};
+use origo::{
+    origo_engine, storage::MemoryStorage, Command, Engine, EngineBuilder, ExecutionContext,
+    Idempotent,
+};
+use std::{thread, time::Duration};
+
+#[derive(Encode, Decode, Default)]
+struct Payments {
+    total: u64,
+}
+
+#[derive(Encode, Decode)]
+struct Pay(u64);
+
+impl Command<Payments> for Pay {
+    fn execute(&self, model: &mut Payments, _ctx: &mut ExecutionContext) {
+        model.total += self.0;
+    }
+}
+
+fn open(
+    storage: MemoryStorage,
+    max_keys: usize,
+    max_age: Duration,
+) -> Engine<Payments, MemoryStorage> {
+    EngineBuilder::new(Payments::default(), storage)
+        .register_command::<Pay>("Pay")
+        .idempotency_limits(max_keys, max_age)
+        .build()
+}
+
+fn executed(result: Idempotent) -> bool {
+    matches!(result, Idempotent::Executed(_))
+}
+
+#[test]
+fn duplicate_key_returns_the_original_receipt() {
+    let db = origo_engine! { Payments, MemoryStorage::new(), Pay, };
+    let Idempotent::Executed(receipt) = db.execute_idempotent("payment-1", Pay(10)) else {
+        panic!("First execution is a duplicate");
+    };
+
+    db.execute(Pay(1));
+    assert_eq!(
+        db.execute_idempotent("payment-1", Pay(10)),
+        Idempotent::Duplicate(receipt)
+    );
+    assert_eq!(db.query(|payments| payments.total), 11);
+}
+
+#[test]
+fn keys_survive_snapshot_and_replay() {
+    let storage = MemoryStorage::new();
+    let db = origo_engine! { Payments, storage.clone(), Pay, };
+    db.snapshot_command_count(2);
+    let before_snapshot = db.execute_idempotent("before-snapshot", Pay(1));
+    db.execute(Pay(2));
+    while storage.snapshot().is_empty() {
+        thread::sleep(Duration::from_millis(1));
+    }
+    db.snapshot_command_count(0);
+    // Journaled after the snapshot
+    let after_snapshot = db.execute_idempotent("after-snapshot", Pay(4));
+    drop(db);
+
+    let db = origo_engine! { Payments, storage, Pay, };
+    let Idempotent::Executed(before_snapshot) = before_snapshot else {
+        panic!("First execution is a duplicate");
+    };
+    let Idempotent::Executed(after_snapshot) = after_snapshot else {
+        panic!("First execution is a duplicate");
+    };
+    assert_eq!(
+        db.execute_idempotent("before-snapshot", Pay(1)),
+        Idempotent::Duplicate(before_snapshot)
+    );
+    assert_eq!(
+        db.execute_idempotent("after-snapshot", Pay(4)),
+        Idempotent::Duplicate(after_snapshot)
+    );
+    assert_eq!(db.query(|payments| payments.total), 7);
+}
+
+#[test]
+fn count_limit_evicts_the_oldest_keys() {
+    let db = open(MemoryStorage::new(), 2, Duration::from_secs(3600));
+    for key in ["a", "b", "c"] {
+        assert!(executed(db.execute_idempotent(key, Pay(1))));
+    }
+
+    assert!(!executed(db.execute_idempotent("c", Pay(1))));
+    assert!(!executed(db.execute_idempotent("b", Pay(1))));
+    assert!(executed(db.execute_idempotent("a", Pay(1))));
+    assert_eq!(db.query(|payments| payments.total), 4);
+}
+
+#[test]
+fn age_limit_evicts_keys_older_than_the_latest_commit() {
+    let storage = MemoryStorage::new();
+    let db = open(storage.clone(), 100, Duration::from_millis(50));
+    assert!(executed(db.execute_idempotent("a", Pay(1))));
+    thread::sleep(Duration::from_millis(100));
+    // Evicted by the commit time of this command
+    assert!(executed(db.execute_idempotent("b", Pay(1))));
+    assert!(executed(db.execute_idempotent("a", Pay(1))));
+    drop(db);
+
+    // The replay evicts by the journaled commit times, like the original execution
+    let db = open(storage, 100, Duration::from_millis(50));
+    assert!(!executed(db.execute_idempotent("a", Pay(1))));
+    assert_eq!(db.query(|payments| payments.total), 3);
+}
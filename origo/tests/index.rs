@@ -0,0 +1,160 @@
+use bincode::{Decode, Encode};
+use origo::{
+    origo_engine, storage::MemoryStorage, Command, Engine, EngineBuilder, ExecutionContext, Index,
+    IndexEntries, RestoreObserver, RestorePhase,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{mpsc, Mutex},
+    time::Duration,
+};
+
+#[derive(Encode, Decode, Default, Clone)]
+struct Shipping {
+    orders: BTreeMap<u32, Order>,
+}
+
+#[derive(Encode, Decode, Clone)]
+struct Order {
+    transport: u32,
+}
+
+/// Assigns the order to a transport and reports it
+#[derive(Encode, Decode)]
+struct Assign {
+    order: u32,
+    transport: u32,
+}
+
+impl Command<Shipping> for Assign {
+    fn execute(&self, model: &mut Shipping, ctx: &mut ExecutionContext) {
+        model.orders.insert(
+            self.order,
+            Order {
+                transport: self.transport,
+            },
+        );
+        ctx.touched(self.order);
+    }
+}
+
+#[derive(Encode, Decode)]
+struct Cancel(u32);
+
+impl Command<Shipping> for Cancel {
+    fn execute(&self, model: &mut Shipping, ctx: &mut ExecutionContext) {
+        model.orders.remove(&self.0);
+        ctx.touched(self.0);
+    }
+}
+
+/// Assigns two orders but only reports the first, breaking the contract of `touched` on purpose
+#[derive(Encode, Decode)]
+struct AssignBoth {
+    reported: (u32, u32),
+    unreported: (u32, u32),
+}
+
+impl Command<Shipping> for AssignBoth {
+    fn execute(&self, model: &mut Shipping, ctx: &mut ExecutionContext) {
+        for (order, transport) in [self.reported, self.unreported] {
+            model.orders.insert(order, Order { transport });
+        }
+        ctx.touched(self.reported.0);
+    }
+}
+
+/// Reports no keys, the indexes scan the whole map
+#[derive(Encode, Decode)]
+struct Noop;
+
+impl Command<Shipping> for Noop {
+    fn execute(&self, _model: &mut Shipping, _ctx: &mut ExecutionContext) {}
+}
+
+fn add_index(db: &Engine<Shipping, MemoryStorage>) {
+    db.add_index(
+        "by_transport",
+        Index::new(
+            |model: &Shipping| &model.orders,
+            |order: &Order| order.transport,
+        ),
+    );
+}
+
+fn orders(db: &Engine<Shipping, MemoryStorage>, transport: u32) -> BTreeSet<u32> {
+    db.query_index("by_transport", |_, entries: &IndexEntries<u32, u32>| {
+        entries.get(&transport).copied().collect()
+    })
+}
+
+fn assign(order: u32, transport: u32) -> Assign {
+    Assign { order, transport }
+}
+
+#[test]
+fn touched_keys_update_only_their_entries() {
+    let db = origo_engine! { Shipping, MemoryStorage::new(), Assign, Cancel, AssignBoth, Noop, };
+    db.execute(assign(1, 10));
+    add_index(&db);
+    db.execute(assign(2, 10));
+    db.execute(assign(3, 20));
+    assert_eq!(orders(&db, 10), BTreeSet::from([1, 2]));
+
+    db.execute(AssignBoth {
+        reported: (1, 20),
+        unreported: (2, 30),
+    });
+    assert_eq!(orders(&db, 20), BTreeSet::from([1, 3]));
+    // Only the reported order was updated
+    assert_eq!(orders(&db, 10), BTreeSet::from([2]));
+    assert!(orders(&db, 30).is_empty());
+
+    // A command without keys brings the whole index up to date
+    db.execute(Noop);
+    assert!(orders(&db, 10).is_empty());
+    assert_eq!(orders(&db, 30), BTreeSet::from([2]));
+
+    db.execute(Cancel(3));
+    assert_eq!(orders(&db, 20), BTreeSet::from([1]));
+}
+
+/// Holds the replay thread at the end of the replay until the test releases it
+struct HoldReplay(Mutex<mpsc::Receiver<()>>);
+
+impl RestoreObserver for HoldReplay {
+    fn phase_finished(&self, phase: RestorePhase, _elapsed: Duration) {
+        if phase == RestorePhase::Replay {
+            let _ = self.0.lock().unwrap().recv();
+        }
+    }
+}
+
+#[test]
+fn indexes_are_rebuilt_after_the_restore() {
+    let storage = MemoryStorage::new();
+    let db = origo_engine! { Shipping, storage.clone(), Assign, Cancel, };
+    for order in 0..6 {
+        db.execute(assign(order, order % 2));
+    }
+    db.execute(Cancel(4));
+    drop(db);
+
+    let db = origo_engine! { Shipping, storage.clone(), Assign, Cancel, };
+    add_index(&db);
+    assert_eq!(orders(&db, 0), BTreeSet::from([0, 2]));
+    assert_eq!(orders(&db, 1), BTreeSet::from([1, 3, 5]));
+    drop(db);
+
+    // Added while the replay runs, the index is updated once it caught up
+    let (release, hold) = mpsc::channel();
+    let db = EngineBuilder::new(Shipping::default(), storage)
+        .register_command::<Assign>("Assign")
+        .register_command::<Cancel>("Cancel")
+        .restore_observer(HoldReplay(Mutex::new(hold)))
+        .build_lazy();
+    add_index(&db);
+    release.send(()).expect("Replay thread stopped");
+    assert_eq!(orders(&db, 0), BTreeSet::from([0, 2]));
+    assert_eq!(orders(&db, 1), BTreeSet::from([1, 3, 5]));
+}
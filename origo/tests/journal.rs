@@ -0,0 +1,279 @@
+use bincode::{Decode, Encode};
+use origo::{
+    origo_engine,
+    storage::{
+        Compression, DiskStorage, FileHeader, FormatError, JournalError, JournalReader,
+        BINCODE_CONFIG,
+    },
+    Command, EngineBuilder, ExecutionContext,
+};
+use std::{fs::File, io::Write, os::unix::fs::FileExt, path::Path};
+
+#[derive(Encode, Decode, Default)]
+struct Counter {
+    value: u64,
+}
+
+#[derive(Encode, Decode)]
+struct Add(u64);
+
+impl Command<Counter> for Add {
+    fn execute(&self, model: &mut Counter, _ctx: &mut ExecutionContext) {
+        model.value += self.0;
+    }
+}
+
+#[derive(Encode, Decode)]
+struct Subtract(u64);
+
+impl Command<Counter> for Subtract {
+    fn execute(&self, model: &mut Counter, _ctx: &mut ExecutionContext) {
+        model.value -= self.0;
+    }
+}
+
+/// Depends on the order of the commands
+#[derive(Encode, Decode, Default)]
+struct Chain {
+    hash: u64,
+}
+
+#[derive(Encode, Decode)]
+struct Link(u64);
+
+impl Command<Chain> for Link {
+    fn execute(&self, model: &mut Chain, _ctx: &mut ExecutionContext) {
+        model.hash = model.hash.wrapping_mul(31).wrapping_add(self.0);
+    }
+}
+
+/// Executes `values` in a new engine, returns the (offset, length) of the journal records
+fn write_journal(path: &Path, values: &[u64]) -> Vec<(u64, u64)> {
+    let db = origo_engine! { Counter, DiskStorage::new(path), Add, };
+    for value in values {
+        db.execute(Add(*value));
+    }
+    drop(db);
+
+    JournalReader::open(path)
+        .expect("Failed to open journal")
+        .map(|record| record.expect("Failed to read record"))
+        .map(|record| (record.offset, record.length))
+        .collect()
+}
+
+fn restore(path: &Path) -> u64 {
+    let db = origo_engine! { Counter, DiskStorage::new(path), Add, };
+    db.query(|counter| counter.value)
+}
+
+#[test]
+fn torn_record_followed_by_zeros_is_discarded() {
+    let directory = tempfile::tempdir().expect("Failed to create directory");
+    let path = directory.path().join("journal.origors");
+    let records = write_journal(&path, &[1, 2, 4]);
+
+    // The length of the last record reached the disk, its data and the rest of the segment didn't
+    let (offset, length) = records[2];
+    let journal = File::options().write(true).open(&path).unwrap();
+    journal
+        .write_all_at(&vec![0u8; length as usize - 1], offset + 1)
+        .unwrap();
+    journal.set_len(offset + length + 4096).unwrap();
+    drop(journal);
+
+    assert_eq!(restore(&path), 3);
+
+    let db = origo_engine! { Counter, DiskStorage::new(&path), Add, };
+    db.execute(Add(8));
+    drop(db);
+    assert_eq!(restore(&path), 11);
+}
+
+#[test]
+fn checksum_mismatch_before_more_records_is_an_error() {
+    let directory = tempfile::tempdir().expect("Failed to create directory");
+    let path = directory.path().join("journal.origors");
+    let records = write_journal(&path, &[1, 2, 4]);
+
+    let (offset, length) = records[1];
+    let journal = File::options().read(true).write(true).open(&path).unwrap();
+    let mut byte = [0u8; 1];
+    journal
+        .read_exact_at(&mut byte, offset + length - 1)
+        .unwrap();
+    journal
+        .write_all_at(&[byte[0] ^ 1], offset + length - 1)
+        .unwrap();
+    drop(journal);
+
+    let mut reader = JournalReader::open(&path).expect("Failed to open journal");
+    assert!(reader.next().unwrap().is_ok());
+    match reader.next() {
+        Some(Err(JournalError::Checksum { offset: at })) => assert_eq!(at, offset),
+        other => panic!(
+            "Expected a checksum error, got {:?}",
+            other.map(|r| r.err())
+        ),
+    }
+}
+
+#[test]
+fn records_refer_to_the_command_table_of_the_header() {
+    let directory = tempfile::tempdir().expect("Failed to create directory");
+    let path = directory.path().join("journal.origors");
+    write_journal(&path, &[1, 2, 4]);
+
+    // Length varint, checksum and command id
+    let overhead = 1 + 4 + 1;
+    let reader = JournalReader::open(&path).expect("Failed to open journal");
+    assert_eq!(reader.header().unwrap().commands, ["Add"]);
+    for record in reader {
+        let record = record.expect("Failed to read record");
+        assert_eq!(record.name, "Add");
+        assert_eq!(record.length, record.data.len() as u64 + overhead);
+    }
+
+    // A command registered after the journal was created isn't in its table, the record has the name
+    let db = origo_engine! { Counter, DiskStorage::new(&path), Add, Subtract, };
+    db.execute(Subtract(3));
+    drop(db);
+
+    let reader = JournalReader::open(&path).expect("Failed to open journal");
+    assert_eq!(reader.header().unwrap().commands, ["Add"]);
+    let record = reader
+        .last()
+        .expect("Journal has records")
+        .expect("Failed to read record");
+    assert_eq!(record.name, "Subtract");
+    assert_eq!(
+        record.length,
+        record.data.len() as u64 + overhead + 1 + "Subtract".len() as u64
+    );
+
+    let db = origo_engine! { Counter, DiskStorage::new(&path), Add, Subtract, };
+    assert_eq!(db.query(|counter| counter.value), 4);
+}
+
+#[test]
+fn replay_workers_apply_records_in_journal_order() {
+    let directory = tempfile::tempdir().expect("Failed to create directory");
+    let path = directory.path().join("journal.origors");
+
+    // Several batches per worker
+    let db = origo_engine! { Chain, DiskStorage::new(&path), Link, };
+    for i in 0..5_000 {
+        db.execute(Link(i));
+    }
+    let expected = db.query(|chain| chain.hash);
+    drop(db);
+
+    for workers in [1, 3, 8] {
+        let db = origo_engine! { Chain, DiskStorage::new(&path).replay_workers(workers), Link, };
+        assert_eq!(
+            db.query(|chain| chain.hash),
+            expected,
+            "{} workers",
+            workers
+        );
+    }
+}
+
+#[test]
+fn header_of_another_model_or_codec_is_rejected() {
+    let header = FileHeader::new("Counter", "bincode", Compression::None, None, Vec::new(), 1);
+    assert!(header.check("Counter", "bincode").is_ok());
+    match header.check("Ledger", "bincode") {
+        Err(FormatError::ModelMismatch { expected, found }) => {
+            assert_eq!((expected.as_str(), found.as_str()), ("Ledger", "Counter"))
+        }
+        other => panic!("Expected a model mismatch, got {:?}", other),
+    }
+    match header.check("Counter", "postcard") {
+        Err(FormatError::CodecMismatch { expected, found }) => {
+            assert_eq!((expected.as_str(), found.as_str()), ("postcard", "bincode"))
+        }
+        other => panic!("Expected a codec mismatch, got {:?}", other),
+    }
+}
+
+#[test]
+#[should_panic(
+    expected = "can't be restored, written for model Counter but the engine uses Ledger"
+)]
+fn journal_of_another_model_id_is_rejected() {
+    let directory = tempfile::tempdir().expect("Failed to create directory");
+    let path = directory.path().join("journal.origors");
+    let db = EngineBuilder::new(Counter::default(), DiskStorage::new(&path))
+        .register_command::<Add>("Add")
+        .model_id("Counter")
+        .build();
+    db.execute(Add(1));
+    drop(db);
+
+    EngineBuilder::new(Counter::default(), DiskStorage::new(&path))
+        .register_command::<Add>("Add")
+        .model_id("Ledger")
+        .build();
+}
+
+#[test]
+fn journal_without_header_is_upgraded() {
+    let directory = tempfile::tempdir().expect("Failed to create directory");
+    let path = directory.path().join("journal.origors");
+
+    // `[count: u64]` followed by `[length: u64][name length: u64][name][command]` per record
+    let values = [1u64, 2, 4];
+    let mut journal = (values.len() as u64).to_le_bytes().to_vec();
+    for value in values {
+        let command = bincode::encode_to_vec(Add(value), BINCODE_CONFIG).unwrap();
+        journal.extend_from_slice(&(("Add".len() + command.len()) as u64).to_le_bytes());
+        journal.extend_from_slice(&("Add".len() as u64).to_le_bytes());
+        journal.extend_from_slice(b"Add");
+        journal.extend_from_slice(&command);
+    }
+    File::create(&path).unwrap().write_all(&journal).unwrap();
+    assert_eq!(JournalReader::open(&path).unwrap().header_count(), Some(3));
+
+    let db = origo_engine! { Counter, DiskStorage::new(&path), Add, };
+    assert_eq!(db.query(|counter| counter.value), 7);
+    db.execute(Add(8));
+    drop(db);
+
+    let reader = JournalReader::open(&path).expect("Failed to open journal");
+    let header = reader.header().expect("Upgraded journal has a header");
+    assert_eq!(header.start_sequence, 1);
+    let sequences: Vec<u64> = reader
+        .map(|record| record.expect("Failed to read record"))
+        .map(|record| record.envelope(BINCODE_CONFIG).unwrap().0.sequence)
+        .collect();
+    assert_eq!(sequences, [1, 2, 3, 4]);
+    assert_eq!(restore(&path), 15);
+}
+
+#[cfg(not(feature = "encryption"))]
+#[test]
+fn encrypted_journal_needs_the_encryption_feature() {
+    use origo::storage::{Encryption, JOURNAL_MAGIC};
+
+    let directory = tempfile::tempdir().expect("Failed to create directory");
+    let path = directory.path().join("journal.origors");
+    let header = FileHeader::new(
+        "Counter",
+        "bincode",
+        Default::default(),
+        Some(Encryption { key_id: 1 }),
+        Vec::new(),
+        1,
+    );
+    header
+        .write_to(&JOURNAL_MAGIC, &mut File::create(&path).unwrap())
+        .unwrap();
+
+    match JournalReader::open(&path) {
+        Err(JournalError::Format(FormatError::Unsupported(reason))) => {
+            assert!(reason.contains("encryption"), "{}", reason)
+        }
+        other => panic!("Expected an unsupported file, got {:?}", other.err()),
+    }
+}
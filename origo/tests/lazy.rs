@@ -0,0 +1,79 @@
+mod common;
+
+use bincode::{Decode, Encode};
+use common::{data_directory, wait_for_snapshot, wait_for_unlock};
+use origo::{
+    storage::DiskStorage, Command, Consistency, EngineBuilder, ExecutionContext, Read,
+    RestoreObserver, RestorePhase,
+};
+use std::{
+    sync::{mpsc, Mutex},
+    time::Duration,
+};
+
+#[derive(Encode, Decode, Default, Clone, PartialEq, Debug)]
+struct Counter {
+    value: u64,
+}
+
+#[derive(Encode, Decode)]
+struct Add(u64);
+
+impl Command<Counter> for Add {
+    fn execute(&self, model: &mut Counter, _ctx: &mut ExecutionContext) {
+        model.value += self.0;
+    }
+}
+
+/// Holds the replay thread at the end of the replay until the test releases it
+struct HoldReplay(Mutex<mpsc::Receiver<()>>);
+
+impl RestoreObserver for HoldReplay {
+    fn phase_finished(&self, phase: RestorePhase, _elapsed: Duration) {
+        if phase == RestorePhase::Replay {
+            let _ = self.0.lock().unwrap().recv();
+        }
+    }
+}
+
+#[test]
+fn stale_reads_target_the_last_sequence_of_the_journal() {
+    let (directory, path) = data_directory();
+
+    let db = origo::origo_engine! { Counter, DiskStorage::new(&path), Add, };
+    db.snapshot_command_count(3);
+    for i in 1..=3 {
+        db.execute(Add(i));
+    }
+    // The snapshot is written on another thread, the next commands wait for it once it started
+    wait_for_snapshot(directory.path());
+    db.snapshot_command_count(0);
+    for i in 4..=7 {
+        db.execute(Add(i));
+    }
+    drop(db);
+    // The snapshot thread holds a clone of the engine until it ends
+    wait_for_unlock(directory.path());
+
+    let (release, hold) = mpsc::channel();
+    let db = EngineBuilder::new(Counter::default(), DiskStorage::new(&path))
+        .register_command::<Add>("Add")
+        .restore_observer(HoldReplay(Mutex::new(hold)))
+        .build_lazy();
+
+    assert_eq!(
+        db.query_with(Consistency::AllowStale, |model| model.value),
+        Read::Stale {
+            value: 6,
+            applied: 3,
+            target: 7
+        }
+    );
+
+    release.send(()).expect("Replay thread stopped");
+    assert_eq!(db.query(|model| model.value), 28);
+    assert_eq!(
+        db.query_with(Consistency::AllowStale, |model| model.value),
+        Read::CaughtUp(28)
+    );
+}
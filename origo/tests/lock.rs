@@ -0,0 +1,30 @@
+mod common;
+
+use common::data_directory;
+use origo::storage::{DirectoryLock, DiskStorage};
+use std::io::ErrorKind;
+
+#[test]
+#[should_panic(expected = "is already in use by another process")]
+fn second_storage_on_a_locked_directory_is_refused() {
+    let (_directory, path) = data_directory();
+    let _storage = DiskStorage::new(&path);
+    DiskStorage::new(&path);
+}
+
+#[test]
+fn lock_is_released_with_the_storage() {
+    let (directory, path) = data_directory();
+    let storage = DiskStorage::new(&path);
+    let error = DirectoryLock::try_lock(directory.path())
+        .err()
+        .expect("Directory is locked");
+    assert_eq!(error.kind(), ErrorKind::WouldBlock);
+    assert_eq!(
+        DirectoryLock::holder(directory.path()),
+        Some(std::process::id())
+    );
+
+    drop(storage);
+    let _lock = DirectoryLock::try_lock(directory.path()).expect("Lock is released");
+}
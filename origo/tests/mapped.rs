@@ -0,0 +1,107 @@
+#![cfg(feature = "rkyv")]
+
+mod common;
+
+use common::{data_directory, wait_for_snapshot, wait_for_unlock};
+use origo::{
+    storage::DiskStorage, Command, EngineBuilder, ExecutionContext, Read, RestoreObserver,
+    RestorePhase, Rkyv,
+};
+use std::sync::{mpsc, Mutex};
+
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Default, Clone, PartialEq, Debug)]
+struct Items {
+    items: Vec<u64>,
+}
+
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+struct Push(u64);
+
+impl Command<Items> for Push {
+    fn execute(&self, model: &mut Items, _ctx: &mut ExecutionContext) {
+        model.items.push(self.0);
+    }
+}
+
+/// Holds the replay thread before the snapshot is loaded until the test releases it
+struct HoldSnapshot(Mutex<mpsc::Receiver<()>>);
+
+impl RestoreObserver for HoldSnapshot {
+    fn phase_started(&self, phase: RestorePhase) {
+        if phase == RestorePhase::Snapshot {
+            let _ = self.0.lock().unwrap().recv();
+        }
+    }
+}
+
+#[test]
+fn mapped_snapshot_answers_until_the_journal_is_replayed() {
+    let (directory, path) = data_directory();
+
+    let db = origo::origo_engine! { codec Rkyv, Items, DiskStorage::new(&path), Push, };
+    db.snapshot_command_count(3);
+    for i in 0..3 {
+        db.execute(Push(i));
+    }
+    // The snapshot is written on another thread, the next commands wait for it once it started
+    wait_for_snapshot(directory.path());
+    for i in 3..5 {
+        db.execute(Push(i));
+    }
+    let expected = db.query(|model| model.clone());
+    drop(db);
+    // The snapshot thread holds a clone of the engine until it ends
+    wait_for_unlock(directory.path());
+
+    let (release, hold) = mpsc::channel();
+    let db = EngineBuilder::new(Items::default(), DiskStorage::new(&path))
+        .codec::<Rkyv>()
+        .register_command::<Push>("Push")
+        .restore_observer(HoldSnapshot(Mutex::new(hold)))
+        .build_mapped();
+
+    let read = db.query_archived(|model| model.items.len(), |archived| archived.items.len());
+    assert_eq!(
+        read,
+        Read::Stale {
+            value: 3,
+            applied: 3,
+            target: 3
+        }
+    );
+
+    release.send(()).expect("Replay thread stopped");
+    assert_eq!(db.query(|model| model.clone()), expected);
+    assert_eq!(
+        db.query_archived(|model| model.items.len(), |archived| archived.items.len()),
+        Read::CaughtUp(5)
+    );
+}
+
+#[test]
+fn commands_after_build_mapped_are_appended_to_the_journal() {
+    let (directory, path) = data_directory();
+    let open = || {
+        EngineBuilder::new(Items::default(), common::storage(&path))
+            .codec::<Rkyv>()
+            .register_command::<Push>("Push")
+            .build_mapped()
+    };
+
+    let db = open();
+    db.snapshot_command_count(2);
+    for i in 0..3 {
+        db.execute(Push(i));
+    }
+    wait_for_snapshot(directory.path());
+    drop(db);
+
+    // Executed before the replay thread got to the storage, it must not overwrite the journal
+    let db = open();
+    db.execute(Push(3));
+    assert_eq!(db.query(|model| model.items.clone()), vec![0, 1, 2, 3]);
+    drop(db);
+
+    let db = open();
+    assert_eq!(db.query(|model| model.items.clone()), vec![0, 1, 2, 3]);
+}
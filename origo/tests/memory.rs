@@ -0,0 +1,113 @@
+use bincode::{Decode, Encode};
+use origo::{
+    origo_engine,
+    storage::{JournalReader, MemoryStorage},
+    Command, ExecutionContext,
+};
+use std::{collections::BTreeMap, thread, time::Duration};
+
+#[derive(Encode, Decode, Default, Clone, PartialEq, Debug)]
+struct Ledger {
+    balances: BTreeMap<String, i64>,
+    entries: u64,
+}
+
+#[derive(Encode, Decode)]
+struct Deposit {
+    account: String,
+    amount: i64,
+}
+
+impl Command<Ledger> for Deposit {
+    fn execute(&self, model: &mut Ledger, _ctx: &mut ExecutionContext) {
+        *model.balances.entry(self.account.clone()).or_default() += self.amount;
+        model.entries += 1;
+    }
+}
+
+#[derive(Encode, Decode)]
+struct Close(String);
+
+impl Command<Ledger> for Close {
+    fn execute(&self, model: &mut Ledger, _ctx: &mut ExecutionContext) {
+        model.balances.remove(&self.0);
+        model.entries += 1;
+    }
+}
+
+fn deposit(i: i64) -> Deposit {
+    Deposit {
+        account: format!("account-{}", i % 7),
+        amount: i * 3 - 20,
+    }
+}
+
+/// Names of the records in the journal buffer, with the start sequence of the journal
+fn journal_names(storage: &MemoryStorage) -> (u64, Vec<String>) {
+    let journal = storage.journal();
+    let reader =
+        JournalReader::new(&journal[..], journal.len() as u64).expect("Failed to read journal");
+    let start_sequence = reader
+        .header()
+        .expect("Journal has a header")
+        .start_sequence;
+    let names = reader
+        .map(|record| record.expect("Failed to read record").name)
+        .collect();
+    (start_sequence, names)
+}
+
+#[test]
+fn replay_gives_an_equal_model() {
+    let storage = MemoryStorage::new();
+    let db = origo_engine! { Ledger, storage.clone(), Deposit, Close, };
+    for i in 0..40 {
+        db.execute(deposit(i));
+    }
+    db.execute(Close("account-3".to_string()));
+    let expected = db.query(|ledger| ledger.clone());
+    drop(db);
+
+    assert!(storage.snapshot().is_empty());
+    let (start_sequence, names) = journal_names(&storage);
+    assert_eq!(start_sequence, 1);
+    assert_eq!(names.len(), 41);
+    assert_eq!(names.last().map(String::as_str), Some("Close"));
+
+    let db = origo_engine! { Ledger, storage.clone(), Deposit, Close, };
+    assert_eq!(db.query(|ledger| ledger.clone()), expected);
+
+    // The restored engine appends to the same journal
+    db.execute(deposit(41));
+    let expected = db.query(|ledger| ledger.clone());
+    drop(db);
+    let db = origo_engine! { Ledger, storage, Deposit, Close, };
+    assert_eq!(db.query(|ledger| ledger.clone()), expected);
+}
+
+#[test]
+fn replay_continues_after_the_snapshot() {
+    let storage = MemoryStorage::new();
+    let db = origo_engine! { Ledger, storage.clone(), Deposit, Close, };
+    db.snapshot_command_count(5);
+    for i in 0..5 {
+        db.execute(deposit(i));
+    }
+    while storage.snapshot().is_empty() {
+        thread::sleep(Duration::from_millis(1));
+    }
+    db.snapshot_command_count(0);
+    // Waits for the snapshot, it holds the storage until the journal is reset
+    for i in 5..8 {
+        db.execute(deposit(i));
+    }
+    let expected = db.query(|ledger| ledger.clone());
+    drop(db);
+
+    let (start_sequence, names) = journal_names(&storage);
+    assert_eq!(start_sequence, 6);
+    assert_eq!(names, ["Deposit", "Deposit", "Deposit"]);
+
+    let db = origo_engine! { Ledger, storage, Deposit, Close, };
+    assert_eq!(db.query(|ledger| ledger.clone()), expected);
+}
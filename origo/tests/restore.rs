@@ -0,0 +1,158 @@
+mod common;
+
+use bincode::{Decode, Encode};
+use common::{data_directory, wait_for_snapshot};
+use origo::{
+    origo_engine, Command, EngineBuilder, ExecutionContext, RestoreObserver, RestorePhase,
+    RestoreProgress,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[derive(Encode, Decode, Default)]
+struct Counter {
+    value: u64,
+}
+
+#[derive(Encode, Decode)]
+struct Add(u64);
+
+impl Command<Counter> for Add {
+    fn execute(&self, model: &mut Counter, _ctx: &mut ExecutionContext) {
+        model.value += self.0;
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Started(RestorePhase),
+    Finished(RestorePhase),
+    Progress {
+        bytes_read: u64,
+        bytes_total: u64,
+        records_applied: u64,
+        sequence: u64,
+        last_sequence: u64,
+    },
+}
+
+/// Records the calls of the restore
+#[derive(Default)]
+struct Recorder(Mutex<Vec<Event>>);
+
+impl RestoreObserver for Recorder {
+    fn phase_started(&self, phase: RestorePhase) {
+        self.0.lock().unwrap().push(Event::Started(phase));
+    }
+
+    fn phase_finished(&self, phase: RestorePhase, _elapsed: Duration) {
+        self.0.lock().unwrap().push(Event::Finished(phase));
+    }
+
+    fn progress(&self, progress: &RestoreProgress) {
+        self.0.lock().unwrap().push(Event::Progress {
+            bytes_read: progress.bytes_read,
+            bytes_total: progress.bytes_total,
+            records_applied: progress.records_applied,
+            sequence: progress.sequence,
+            last_sequence: progress.last_sequence,
+        });
+    }
+}
+
+#[test]
+fn observer_sees_the_snapshot_then_the_replay_up_to_the_last_command() {
+    let (directory, path) = data_directory();
+    let db = origo_engine! { Counter, common::storage(&path), Add, };
+    db.snapshot_command_count(10);
+    for i in 0..10 {
+        db.execute(Add(i));
+    }
+    wait_for_snapshot(directory.path());
+    db.snapshot_command_count(0);
+    // Waits for the snapshot, it holds the storage until the journal is reset
+    for i in 10..2_000 {
+        db.execute(Add(i));
+    }
+    drop(db);
+
+    let recorder = Arc::new(Recorder::default());
+    let db = EngineBuilder::new(Counter::default(), common::storage(&path))
+        .register_command::<Add>("Add")
+        .restore_observer(recorder.clone())
+        .build();
+    assert_eq!(
+        db.query(|counter| counter.value),
+        (0..2_000u64).sum::<u64>()
+    );
+
+    let events = recorder.0.lock().unwrap();
+    let phases: Vec<&Event> = events
+        .iter()
+        .filter(|event| !matches!(event, Event::Progress { .. }))
+        .collect();
+    assert_eq!(
+        phases,
+        [
+            &Event::Started(RestorePhase::Snapshot),
+            &Event::Finished(RestorePhase::Snapshot),
+            &Event::Started(RestorePhase::Replay),
+            &Event::Finished(RestorePhase::Replay),
+        ]
+    );
+    // Progress is reported during the replay, the restore is done with the end of the replay
+    let replay_started = events
+        .iter()
+        .position(|event| *event == Event::Started(RestorePhase::Replay))
+        .unwrap();
+    assert_eq!(events.last(), Some(&Event::Finished(RestorePhase::Replay)));
+    let progress: Vec<(u64, u64, u64, u64, u64)> = events[replay_started..]
+        .iter()
+        .filter_map(|event| match *event {
+            Event::Progress {
+                bytes_read,
+                bytes_total,
+                records_applied,
+                sequence,
+                last_sequence,
+            } => Some((
+                bytes_read,
+                bytes_total,
+                records_applied,
+                sequence,
+                last_sequence,
+            )),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        progress.len(),
+        events.len() - replay_started - 2,
+        "Progress outside of the replay"
+    );
+
+    let (_, bytes_total, _, first_sequence, last_sequence) = progress[0];
+    assert_eq!(last_sequence, 2_000);
+    assert!(first_sequence >= 10);
+    for pair in progress.windows(2) {
+        let ((bytes, _, records, sequence, _), (next_bytes, _, next_records, next_sequence, _)) =
+            (pair[0], pair[1]);
+        assert!(bytes <= next_bytes && records <= next_records && sequence <= next_sequence);
+    }
+    for (bytes_read, total, _, _, target) in &progress {
+        assert!(*bytes_read <= bytes_total);
+        assert_eq!((*total, *target), (bytes_total, last_sequence));
+    }
+    assert_eq!(
+        progress.last(),
+        Some(&(
+            bytes_total,
+            bytes_total,
+            last_sequence - first_sequence,
+            last_sequence,
+            last_sequence
+        ))
+    );
+}
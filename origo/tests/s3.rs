@@ -0,0 +1,426 @@
+#![cfg(feature = "s3")]
+
+//! `S3Store` against a stand-in for an S3-compatible service that checks the request signatures
+
+mod common;
+
+use bincode::{Decode, Encode};
+use common::{wait_for_snapshot, wait_for_unlock};
+use hmac::{Hmac, Mac};
+use origo::{
+    origo_engine,
+    storage::{restore_archive, DiskStorage, ObjectStore, S3Store},
+    Command, ExecutionContext,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{ErrorKind, Read},
+    net::TcpListener,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use tiny_http::{Header, Request, Response, Server};
+
+const BUCKET: &str = "backups";
+const ACCESS_KEY: &str = "minio";
+const SECRET_KEY: &str = "minio-secret";
+/// Keys per page of a listing, small to cover continuation tokens
+const PAGE_SIZE: usize = 2;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Signature Version 4 of a request signed with the headers `host;x-amz-content-sha256;x-amz-date`,
+/// `query` as sent, `path` URI-encoded
+fn signature(
+    secret_key: &str,
+    method: &str,
+    path: &str,
+    query: &str,
+    host: &str,
+    payload_hash: &str,
+    timestamp: &str,
+) -> String {
+    let mut query: Vec<String> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.contains('=') {
+            true => pair.to_string(),
+            false => format!("{}=", pair),
+        })
+        .collect();
+    query.sort();
+    let canonical_request = format!(
+        "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+        method,
+        path,
+        query.join("&"),
+        host,
+        payload_hash,
+        timestamp,
+        payload_hash
+    );
+    let date = &timestamp[..8];
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}/us-east-1/s3/aws4_request\n{}",
+        timestamp,
+        date,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+    let key = [date, "us-east-1", "s3", "aws4_request"]
+        .iter()
+        .fold(format!("AWS4{}", secret_key).into_bytes(), |key, part| {
+            hmac(&key, part)
+        });
+    hex(&hmac(&key, &string_to_sign))
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                decoded.push(u8::from_str_radix(&value[i + 1..i + 3], 16).unwrap());
+                i += 3;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).unwrap()
+}
+
+fn xml_value<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let (_, rest) = xml.split_once(&format!("<{}>", name))?;
+    rest.split_once(&format!("</{}>", name))
+        .map(|(value, _)| value)
+}
+
+/// Objects and multipart uploads of one bucket, like MinIO
+#[derive(Default)]
+struct StandIn {
+    objects: Mutex<BTreeMap<String, Vec<u8>>>,
+    uploads: Mutex<HashMap<String, BTreeMap<usize, Vec<u8>>>>,
+    /// Method and query names of every request
+    requests: Mutex<Vec<String>>,
+}
+
+impl StandIn {
+    /// Serves the stand-in on a free port, returns its endpoint
+    fn start() -> (Arc<StandIn>, String) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let stand_in = Arc::new(StandIn::default());
+        let serving = stand_in.clone();
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                serving.handle(request);
+            }
+        });
+        (stand_in, endpoint)
+    }
+
+    fn handle(&self, mut request: Request) {
+        let (host, payload_hash, timestamp, authorization) = (
+            header(&request, "host"),
+            header(&request, "x-amz-content-sha256"),
+            header(&request, "x-amz-date"),
+            header(&request, "authorization"),
+        );
+        let method = request.method().to_string();
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        let mut body = Vec::new();
+        request.as_reader().read_to_end(&mut body).unwrap();
+
+        let expected = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}/us-east-1/s3/aws4_request, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+            ACCESS_KEY,
+            timestamp.get(..8).unwrap_or_default(),
+            signature(SECRET_KEY, &method, path, query, &host, &payload_hash, &timestamp)
+        );
+        if authorization != expected || payload_hash != hex(&Sha256::digest(&body)) {
+            return error(request, 403, "SignatureDoesNotMatch");
+        }
+
+        let query: HashMap<String, String> = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (percent_decode(name), percent_decode(value))
+            })
+            .collect();
+        let mut names: Vec<&str> = query.keys().map(String::as_str).collect();
+        names.sort();
+        self.requests
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", method, names.join("&")));
+
+        let key = match path.strip_prefix(&format!("/{}", BUCKET)) {
+            Some("") => None,
+            Some(key) => Some(percent_decode(key.trim_start_matches('/'))),
+            None => return error(request, 404, "NoSuchBucket"),
+        };
+        let upload_id = query.get("uploadId").cloned();
+        match (method.as_str(), key, upload_id) {
+            ("GET", None, _) => {
+                let objects = self.objects.lock().unwrap();
+                let start: usize = query
+                    .get("continuation-token")
+                    .map_or(0, |token| token.parse().unwrap());
+                let mut xml = String::from("<ListBucketResult>");
+                for key in objects.keys().skip(start).take(PAGE_SIZE) {
+                    let key = key.replace('&', "&amp;");
+                    xml.push_str(&format!("<Contents><Key>{}</Key></Contents>", key));
+                }
+                let truncated = start + PAGE_SIZE < objects.len();
+                xml.push_str(&format!("<IsTruncated>{}</IsTruncated>", truncated));
+                if truncated {
+                    xml.push_str(&format!(
+                        "<NextContinuationToken>{}</NextContinuationToken>",
+                        start + PAGE_SIZE
+                    ));
+                }
+                xml.push_str("</ListBucketResult>");
+                respond(request, Response::from_string(xml));
+            }
+            ("GET", Some(key), _) => match self.objects.lock().unwrap().get(&key) {
+                Some(data) => respond(request, Response::from_data(data.clone())),
+                None => error(request, 404, "NoSuchKey"),
+            },
+            ("PUT", Some(key), None) => {
+                self.objects.lock().unwrap().insert(key, body);
+                respond(request, Response::from_string(""));
+            }
+            ("POST", Some(_), None) if query.contains_key("uploads") => {
+                let mut uploads = self.uploads.lock().unwrap();
+                let upload_id = format!("upload-{}", uploads.len());
+                uploads.insert(upload_id.clone(), BTreeMap::new());
+                let xml = format!(
+                    "<InitiateMultipartUploadResult><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                    upload_id
+                );
+                respond(request, Response::from_string(xml));
+            }
+            ("PUT", Some(_), Some(upload_id)) => {
+                let number: usize = query["partNumber"].parse().unwrap();
+                let etag = format!("\"{}\"", hex(&Sha256::digest(&body)));
+                match self.uploads.lock().unwrap().get_mut(&upload_id) {
+                    Some(parts) => {
+                        parts.insert(number, body);
+                        let etag = Header::from_bytes("ETag", etag).unwrap();
+                        respond(request, Response::from_string("").with_header(etag));
+                    }
+                    None => error(request, 404, "NoSuchUpload"),
+                }
+            }
+            ("POST", Some(key), Some(upload_id)) => {
+                let Some(parts) = self.uploads.lock().unwrap().remove(&upload_id) else {
+                    return error(request, 404, "NoSuchUpload");
+                };
+                let completed = String::from_utf8(body).unwrap();
+                let mut data = Vec::new();
+                for (index, part) in completed.split("<Part>").skip(1).enumerate() {
+                    let number: usize = xml_value(part, "PartNumber").unwrap().parse().unwrap();
+                    let etag = xml_value(part, "ETag").unwrap();
+                    let valid = number == index + 1
+                        && parts.get(&number).is_some_and(|part| {
+                            etag == format!("\"{}\"", hex(&Sha256::digest(part)))
+                        });
+                    if !valid {
+                        // S3 answers errors after the upload started with status 200
+                        let xml = "<Error><Code>InvalidPart</Code></Error>";
+                        return respond(request, Response::from_string(xml));
+                    }
+                    data.extend_from_slice(&parts[&number]);
+                }
+                self.objects.lock().unwrap().insert(key, data);
+                let xml = "<CompleteMultipartUploadResult></CompleteMultipartUploadResult>";
+                respond(request, Response::from_string(xml));
+            }
+            ("DELETE", Some(_), Some(upload_id)) => {
+                self.uploads.lock().unwrap().remove(&upload_id);
+                respond(request, Response::from_string("").with_status_code(204));
+            }
+            _ => error(request, 400, "InvalidRequest"),
+        }
+    }
+
+    fn requests(&self) -> Vec<String> {
+        std::mem::take(&mut self.requests.lock().unwrap())
+    }
+}
+
+fn header(request: &Request, name: &str) -> String {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.to_string().eq_ignore_ascii_case(name))
+        .map(|header| header.value.to_string())
+        .unwrap_or_default()
+}
+
+fn respond<R: Read>(request: Request, response: Response<R>) {
+    request.respond(response).unwrap();
+}
+
+fn error(request: Request, status: u16, code: &str) {
+    let xml = format!("<Error><Code>{}</Code></Error>", code);
+    respond(request, Response::from_string(xml).with_status_code(status));
+}
+
+/// The signature of the stand-in matches the examples of the AWS documentation
+#[test]
+fn stand_in_signs_like_aws() {
+    let empty = hex(&Sha256::digest(b""));
+    let secret = "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY";
+    let host = "examplebucket.s3.amazonaws.com";
+    let timestamp = "20130524T000000Z";
+    assert_eq!(
+        signature(secret, "GET", "/", "lifecycle", host, &empty, timestamp),
+        "fea454ca298b7da1c68078a5d1bdbfbbe0d65c699e0f91ac7a200a0136783543"
+    );
+    assert_eq!(
+        signature(
+            secret,
+            "GET",
+            "/",
+            "prefix=J&max-keys=2",
+            host,
+            &empty,
+            timestamp
+        ),
+        "34b48302e7b5fa45bde8084f4b7868a86f0a534bc59db6670ed5711ef69dc6f7"
+    );
+}
+
+#[test]
+fn objects_are_put_listed_and_read() {
+    let (stand_in, endpoint) = StandIn::start();
+    let store = S3Store::new(&endpoint, BUCKET, ACCESS_KEY, SECRET_KEY);
+
+    let keys = ["a.origors", "b c+d.origors", "e&f.origors", "g.origors"];
+    for (i, key) in keys.iter().enumerate() {
+        store.put(key, &[i as u8; 10]).unwrap();
+    }
+    assert_eq!(store.get("b c+d.origors").unwrap(), [1; 10]);
+    assert_eq!(
+        store.get("missing.origors").unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+
+    let mut listed = store.list().unwrap();
+    listed.sort();
+    assert_eq!(listed, keys);
+    // 4 keys in pages of 2
+    assert_eq!(
+        stand_in.requests()[keys.len() + 2..],
+        ["GET list-type", "GET continuation-token&list-type"]
+    );
+
+    let wrong_secret = S3Store::new(&endpoint, BUCKET, ACCESS_KEY, "wrong");
+    let denied = wrong_secret.put("a.origors", b"denied").unwrap_err();
+    assert!(denied.to_string().contains("SignatureDoesNotMatch"));
+    assert_eq!(store.get("a.origors").unwrap(), [0; 10]);
+}
+
+#[test]
+fn large_files_are_uploaded_in_parts() {
+    let (stand_in, endpoint) = StandIn::start();
+    let store = S3Store::new(&endpoint, BUCKET, ACCESS_KEY, SECRET_KEY).part_size(1024);
+
+    let data: Vec<u8> = (0..2500u32).map(|i| (i % 251) as u8).collect();
+    store
+        .put_reader("journal.origors", &mut &data[..], data.len() as u64)
+        .unwrap();
+    assert_eq!(store.get("journal.origors").unwrap(), data);
+    assert_eq!(
+        stand_in.requests(),
+        [
+            "POST uploads",
+            "PUT partNumber&uploadId",
+            "PUT partNumber&uploadId",
+            "PUT partNumber&uploadId",
+            "POST uploadId",
+            "GET ",
+        ]
+    );
+
+    // A reader that ends early aborts the upload
+    let failed = store.put_reader("short.origors", &mut &data[..2000], data.len() as u64);
+    assert_eq!(failed.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    assert!(stand_in.uploads.lock().unwrap().is_empty());
+    assert_eq!(
+        store.get("short.origors").unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn requests_time_out() {
+    // Accepts connections but never answers
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let store = S3Store::new(&endpoint, BUCKET, ACCESS_KEY, SECRET_KEY)
+        .timeouts(Duration::from_secs(1), Duration::from_millis(100));
+
+    let started = Instant::now();
+    assert!(store.put("a.origors", b"data").is_err());
+    assert!(started.elapsed() < Duration::from_secs(5));
+    drop(listener);
+}
+
+#[derive(Encode, Decode, Default, Clone, PartialEq, Debug)]
+struct Notes {
+    notes: Vec<String>,
+}
+
+#[derive(Encode, Decode)]
+struct AddNote(String);
+
+impl Command<Notes> for AddNote {
+    fn execute(&self, model: &mut Notes, _ctx: &mut ExecutionContext) {
+        model.notes.push(self.0.clone());
+    }
+}
+
+#[test]
+fn archive_restores_from_the_stand_in() {
+    let (_stand_in, endpoint) = StandIn::start();
+    let store = || S3Store::new(&endpoint, BUCKET, ACCESS_KEY, SECRET_KEY).part_size(4096);
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("data").join("journal.origors");
+
+    let db = origo_engine! { Notes, DiskStorage::new(&path).archive(store()), AddNote, };
+    db.snapshot_command_count(200);
+    for i in 0..200 {
+        db.execute(AddNote(format!(
+            "Note {} with some text to fill the parts",
+            i
+        )));
+    }
+    wait_for_snapshot(path.parent().unwrap());
+    let expected = db.query(|model| model.clone());
+    drop(db);
+    wait_for_unlock(path.parent().unwrap());
+
+    let restored = directory.path().join("restored").join("journal.origors");
+    assert_eq!(restore_archive(&store(), &restored).unwrap(), 200);
+    let db = origo_engine! { Notes, DiskStorage::new(&restored), AddNote, };
+    assert_eq!(db.query(|model| model.clone()), expected);
+}
@@ -0,0 +1,173 @@
+#![cfg(feature = "sqlite")]
+
+mod common;
+
+use bincode::{Decode, Encode};
+use common::UNLOCK_TIMEOUT;
+use origo::{
+    origo_engine, storage::SqliteStorage, Command, EngineBuilder, ExecutionContext,
+    RestoreObserver, RestorePhase, RestoreProgress,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    panic,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use tempfile::TempDir;
+
+#[derive(Encode, Decode, Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+struct Ledger {
+    balances: BTreeMap<String, i64>,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize)]
+struct Deposit {
+    account: String,
+    amount: i64,
+}
+
+impl Command<Ledger> for Deposit {
+    fn execute(&self, model: &mut Ledger, _ctx: &mut ExecutionContext) {
+        *model.balances.entry(self.account.clone()).or_default() += self.amount;
+    }
+}
+
+#[derive(Encode, Decode, Default)]
+struct Other {
+    value: u64,
+}
+
+#[derive(Encode, Decode)]
+struct Set(u64);
+
+impl Command<Other> for Set {
+    fn execute(&self, model: &mut Other, _ctx: &mut ExecutionContext) {
+        model.value = self.0;
+    }
+}
+
+fn deposit(i: i64) -> Deposit {
+    Deposit {
+        account: format!("account-{}", i % 5),
+        amount: i * 7 - 30,
+    }
+}
+
+fn database() -> (TempDir, PathBuf) {
+    let directory = tempfile::tempdir().expect("Failed to create directory");
+    let path = directory.path().join("origo.db");
+    (directory, path)
+}
+
+/// Opens the database once the snapshot thread of the previous engine closed it,
+/// it's opened in exclusive locking mode
+fn storage(path: &Path) -> SqliteStorage {
+    let started = Instant::now();
+    loop {
+        if let Ok(storage) = panic::catch_unwind(|| SqliteStorage::new(path)) {
+            return storage;
+        }
+        assert!(
+            started.elapsed() < UNLOCK_TIMEOUT,
+            "Database {:?} is still in use after {:?}, an engine wasn't dropped",
+            path,
+            UNLOCK_TIMEOUT
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Records the phases of a restore and the replayed records
+#[derive(Default)]
+struct Recorder {
+    phases: Mutex<Vec<RestorePhase>>,
+    applied: Mutex<u64>,
+}
+
+impl RestoreObserver for Recorder {
+    fn phase_started(&self, phase: RestorePhase) {
+        self.phases.lock().unwrap().push(phase);
+    }
+
+    fn progress(&self, progress: &RestoreProgress) {
+        *self.applied.lock().unwrap() = progress.records_applied;
+    }
+}
+
+#[test]
+fn committed_commands_are_replayed() {
+    let (_directory, path) = database();
+    let db = origo_engine! { Ledger, storage(&path), Deposit, };
+    for i in 0..20 {
+        db.execute(deposit(i));
+    }
+    let expected = db.query(|ledger| ledger.clone());
+    drop(db);
+
+    let db = origo_engine! { Ledger, storage(&path), Deposit, };
+    assert_eq!(db.query(|ledger| ledger.clone()), expected);
+
+    // The restored engine continues the journal
+    db.execute(deposit(20));
+    let expected = db.query(|ledger| ledger.clone());
+    drop(db);
+    let db = origo_engine! { Ledger, storage(&path), Deposit, };
+    assert_eq!(db.query(|ledger| ledger.clone()), expected);
+}
+
+#[test]
+fn replay_continues_after_the_snapshot() {
+    let (_directory, path) = database();
+    let db = origo_engine! { Ledger, storage(&path), Deposit, };
+    db.snapshot_command_count(5);
+    for i in 0..8 {
+        db.execute(deposit(i));
+    }
+    let expected = db.query(|ledger| ledger.clone());
+    drop(db);
+
+    let recorder = Arc::new(Recorder::default());
+    let db = EngineBuilder::new(Ledger::default(), storage(&path))
+        .register_command::<Deposit>("Deposit")
+        .restore_observer(recorder.clone())
+        .build();
+    assert_eq!(db.query(|ledger| ledger.clone()), expected);
+    assert_eq!(
+        recorder.phases.lock().unwrap()[..],
+        [RestorePhase::Snapshot, RestorePhase::Replay]
+    );
+    // The snapshot contains at least the first five commands, depending on when its thread got the storage
+    assert!(*recorder.applied.lock().unwrap() <= 3);
+}
+
+#[test]
+#[should_panic(expected = "Database was written for model")]
+fn database_of_another_model_is_rejected() {
+    let (_directory, path) = database();
+    let db = origo_engine! { Ledger, storage(&path), Deposit, };
+    db.execute(deposit(1));
+    drop(db);
+
+    origo_engine! { Other, storage(&path), Set, };
+}
+
+#[cfg(feature = "postcard")]
+#[test]
+#[should_panic(expected = "Database was written with codec bincode but the engine uses postcard")]
+fn database_of_another_codec_is_rejected() {
+    use origo::Postcard;
+
+    let (_directory, path) = database();
+    let db = origo_engine! { Ledger, storage(&path), Deposit, };
+    db.execute(deposit(1));
+    drop(db);
+
+    EngineBuilder::new(Ledger::default(), storage(&path))
+        .codec::<Postcard>()
+        .register_command::<Deposit>("Deposit")
+        .build();
+}
@@ -0,0 +1,51 @@
+use bincode::{Decode, Encode};
+use origo::{origo_engine, storage::MemoryStorage, Command, ExecutionContext};
+use std::{sync::mpsc, thread};
+
+#[derive(Encode, Decode, Default, Clone)]
+struct Inventory {
+    items: Vec<u32>,
+}
+
+#[derive(Encode, Decode)]
+struct Stock(u32);
+
+impl Command<Inventory> for Stock {
+    fn execute(&self, model: &mut Inventory, _ctx: &mut ExecutionContext) {
+        model.items.push(self.0);
+    }
+}
+
+#[test]
+fn held_version_keeps_its_view_while_writes_continue() {
+    let db = origo_engine! { versioned Inventory, MemoryStorage::new(), Stock, };
+    for i in 0..10 {
+        db.execute(Stock(i));
+    }
+
+    let (held, release) = mpsc::channel();
+    let (wrote, written) = mpsc::channel();
+    let reader = {
+        let db = db.clone();
+        thread::spawn(move || {
+            let version = db.version();
+            held.send(()).unwrap();
+            // Queries against the held version between the writes see the same state
+            for _ in written {
+                assert_eq!(version.items, (0..10).collect::<Vec<_>>());
+                assert!(db.query(|model| model.items.len()) > 10);
+            }
+            version.items.len()
+        })
+    };
+
+    release.recv().unwrap();
+    for i in 10..100 {
+        db.execute(Stock(i));
+        wrote.send(()).unwrap();
+    }
+    drop(wrote);
+
+    assert_eq!(reader.join().expect("Reader failed"), 10);
+    assert_eq!(db.version().items, (0..100).collect::<Vec<_>>());
+}
@@ -1,6 +1,6 @@
 use crate::models::{EcomModel, Order};
 use bincode::{Decode, Encode};
-use origo::Command;
+use origo::{Command, ExecutionContext};
 use serde::{Deserialize, Serialize};
 
 #[derive(Encode, Decode, Serialize, Deserialize)]
@@ -11,7 +11,7 @@ pub struct InsertOrder {
 }
 
 impl Command<EcomModel> for InsertOrder {
-    fn execute(&self, model: &mut EcomModel) {
+    fn execute(&self, model: &mut EcomModel, ctx: &mut ExecutionContext) {
         model.orders.insert(
             self.order_id,
             Order {
@@ -20,5 +20,6 @@ impl Command<EcomModel> for InsertOrder {
                 transport_id: self.transport_id,
             },
         );
+        ctx.touched(self.order_id);
     }
 }
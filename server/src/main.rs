@@ -1,31 +1,139 @@
 mod commands;
 mod models;
-use std::time::Instant;
+use std::{
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
 
-use origo::storage::DiskStorage;
-use tide::{Body, Request};
+use origo::{
+    storage::DiskStorage, CommandMeta, Consistency, EngineBuilder, Idempotent, Index, IndexEntries,
+    Read, RestoreObserver, RestorePhase, RestoreProgress,
+};
+use serde::Serialize;
+use tide::{Body, Request, StatusCode};
 use {commands::*, models::*};
 
-/// Makes it easier, `req: Request<Db>` in functions
-/// instead of `req: Request<Engine<EComModel>>`
+/// Makes it easier, `db: &Db` in functions
+/// instead of `db: &Engine<EComModel, DiskStorage>`
 type Db = origo::Engine<EcomModel, DiskStorage>;
 
 /// We should take a snapshot after this amount of commited commands
 const SNAPSHOT_COMMAND_COUNT: u64 = 100;
 
+/// transport_id -> order_id
+const ORDERS_BY_TRANSPORT: &str = "orders_by_transport";
+
+/// The engine is restored in the background, requests get a 503 until it's set
+#[derive(Clone)]
+struct State {
+    db: Arc<OnceLock<Db>>,
+    restore: Arc<RestoreStatus>,
+}
+
+/// Body of `/ready`
+#[derive(Clone, Default, Serialize)]
+struct Readiness {
+    ready: bool,
+    /// `snapshot`, `replay` or `ready`, missing before the restore reported anything
+    phase: Option<&'static str>,
+    records_applied: u64,
+    bytes_read: u64,
+    bytes_total: u64,
+    eta_ms: Option<u128>,
+}
+
+/// Logs the progress of the restore and keeps it for `/ready`
+#[derive(Default)]
+struct RestoreStatus {
+    readiness: Mutex<Readiness>,
+}
+
+impl RestoreObserver for RestoreStatus {
+    fn phase_started(&self, phase: RestorePhase) {
+        log::info!("Restore: {:?} started", phase);
+        self.readiness.lock().unwrap().phase = Some(match phase {
+            RestorePhase::Snapshot => "snapshot",
+            RestorePhase::Replay => "replay",
+        });
+    }
+
+    fn phase_finished(&self, phase: RestorePhase, elapsed: Duration) {
+        log::info!("Restore: {:?} finished in {}ms", phase, elapsed.as_millis());
+    }
+
+    fn progress(&self, progress: &RestoreProgress) {
+        log::info!(
+            "Restore: {} records applied, sequence {}/{}, {}/{} bytes, last {}, eta {}",
+            progress.records_applied,
+            progress.sequence,
+            progress.last_sequence,
+            progress.bytes_read,
+            progress.bytes_total,
+            progress.command,
+            progress
+                .eta
+                .map_or("unknown".to_string(), |eta| format!("{}s", eta.as_secs()))
+        );
+        let mut readiness = self.readiness.lock().unwrap();
+        readiness.records_applied = progress.records_applied;
+        readiness.bytes_read = progress.bytes_read;
+        readiness.bytes_total = progress.bytes_total;
+        readiness.eta_ms = progress.eta.map(|eta| eta.as_millis());
+    }
+}
+
 #[async_std::main]
 async fn main() -> tide::Result<()> {
     env_logger::init();
+    let state = State {
+        db: Arc::new(OnceLock::new()),
+        restore: Arc::new(RestoreStatus::default()),
+    };
+
+    // Listen right away, so `/ready` can report the progress of a long restore
+    let restore_state = state.clone();
+    let restore = std::thread::spawn(move || restore_db(restore_state));
+    std::thread::spawn(move || {
+        if restore.join().is_err() {
+            log::error!("Restore failed, stopping");
+            std::process::exit(1);
+        }
+    });
+
+    let mut app = tide::with_state(state);
+    app.at("/ready").get(ready);
+    app.at("/orders")
+        .post(place_order)
+        .at("/:id")
+        .get(fetch_order);
+    app.at("/transports/:id/orders").get(fetch_transport_orders);
+    app.listen("127.0.0.1:8080").await?;
+    Ok(())
+}
+
+fn restore_db(state: State) {
     let instant = Instant::now();
 
-    let db = origo::origo_engine! {
-        EcomModel,
+    let db = EngineBuilder::new(
+        EcomModel::default(),
         DiskStorage::new("./data/test.origors"),
-        InsertOrder,
-    };
+    )
+    .register_command::<InsertOrder>("InsertOrder")
+    // The orders are a std `HashMap`, its encoding isn't deterministic
+    .verify_determinism(false)
+    .restore_observer(state.restore.clone())
+    .build_lazy();
 
     db.snapshot_command_count(SNAPSHOT_COMMAND_COUNT);
+    db.add_index(
+        ORDERS_BY_TRANSPORT,
+        Index::new(|m: &EcomModel| &m.orders, |o: &Order| o.transport_id),
+    );
 
+    // Orders are served from the snapshot while the journal is replayed
+    if state.db.set(db.clone()).is_err() {
+        unreachable!("The engine is only restored once");
+    }
     log::info!("Startup: {}ms", instant.elapsed().as_millis());
 
     let order_count = db.query(|m| m.orders.len());
@@ -39,13 +147,28 @@ async fn main() -> tide::Result<()> {
         log::info!("Inserted test-data");
     }
 
-    let mut app = tide::with_state(db);
-    app.at("/orders")
-        .post(place_order)
-        .at("/:id")
-        .get(fetch_order);
-    app.listen("127.0.0.1:8080").await?;
-    Ok(())
+    let mut readiness = state.restore.readiness.lock().unwrap();
+    readiness.ready = true;
+    readiness.phase = Some("ready");
+}
+
+/// The engine, or a 503 until the snapshot is loaded
+fn db(req: &Request<State>) -> tide::Result<&Db> {
+    req.state()
+        .db
+        .get()
+        .ok_or_else(|| tide::Error::from_str(StatusCode::ServiceUnavailable, "Restoring"))
+}
+
+/// 200 once the engine is restored, 503 with the progress of the restore before
+async fn ready(req: Request<State>) -> tide::Result {
+    let readiness = req.state().restore.readiness.lock().unwrap().clone();
+    let mut res = tide::Response::new(match readiness.ready {
+        true => StatusCode::Ok,
+        false => StatusCode::ServiceUnavailable,
+    });
+    res.set_body(Body::from_json(&readiness)?);
+    Ok(res)
 }
 
 fn insert_test_data(db: &Db, count: &i32) {
@@ -54,28 +177,80 @@ fn insert_test_data(db: &Db, count: &i32) {
         name: String::from("TestOrder"),
         transport_id: 2,
     };
-    _ = db.execute(test_data);
+    db.execute(test_data);
 }
 
-async fn fetch_order(req: Request<Db>) -> tide::Result {
+async fn fetch_order(req: Request<State>) -> tide::Result {
     let id = req.param("id").unwrap().parse::<usize>().unwrap();
-    let result = req.state().query(|m| m.orders.get(&id).cloned());
+    let (result, stale) =
+        match db(&req)?.query_with(Consistency::AllowStale, |m| m.orders.get(&id).cloned()) {
+            Read::CaughtUp(result) => (result, None),
+            Read::Stale {
+                value,
+                applied,
+                target,
+            } => (value, Some(format!("{applied}/{target}"))),
+        };
 
-    Ok(match result {
+    let mut res = match result {
         Some(order) => {
             let mut res = tide::Response::new(200);
             res.set_body(Body::from_json(&order).unwrap());
             res
         }
         None => tide::Response::new(404),
-    })
+    };
+    // The journal is still replayed, the order is from the snapshot
+    if let Some(stale) = stale {
+        res.insert_header("X-Stale", stale);
+    }
+    Ok(res)
 }
 
-async fn place_order<'a>(mut req: Request<Db>) -> tide::Result {
+async fn fetch_transport_orders(req: Request<State>) -> tide::Result {
+    let id = req.param("id").unwrap().parse::<usize>().unwrap();
+    let orders = db(&req)?.query_index(
+        ORDERS_BY_TRANSPORT,
+        |m, index: &IndexEntries<usize, usize>| {
+            index
+                .get(&id)
+                .filter_map(|order_id| m.orders.get(order_id))
+                .cloned()
+                .collect::<Vec<Order>>()
+        },
+    );
+
+    let mut res = tide::Response::new(200);
+    res.set_body(Body::from_json(&orders).unwrap());
+    Ok(res)
+}
+
+async fn place_order(mut req: Request<State>) -> tide::Result {
+    db(&req)?;
     match req.body_json::<InsertOrder>().await {
         Ok(command) => {
-            _ = req.state().execute(command);
-            Ok(tide::Response::new(200))
+            let mut meta = CommandMeta::default();
+            if let Some(request_id) = req.header("X-Request-Id") {
+                meta = meta.correlation_id(request_id.as_str());
+            }
+            if let Some(user_id) = req.header("X-User-Id") {
+                meta = meta.actor(user_id.as_str());
+            }
+
+            // Clients retry on timeouts, with an `Idempotency-Key` the retry isn't executed twice
+            match req.header("Idempotency-Key") {
+                Some(key) => match db(&req)?.execute_idempotent_with(key.as_str(), meta, command) {
+                    Idempotent::Executed(_) => Ok(tide::Response::new(200)),
+                    Idempotent::Duplicate(receipt) => {
+                        log::debug!("Duplicate of command {}", receipt.sequence);
+                        Ok(tide::Response::new(200))
+                    }
+                },
+                None => {
+                    db(&req)?.execute_with(meta, command);
+                    Ok(tide::Response::new(200))
+                }
+            }
         }
         Err(e) => Err(e),
     }
@@ -2,7 +2,8 @@ use bincode::{Decode, Encode};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Encode, Decode, Default)]
+/// `Clone` for `EngineBuilder::build_lazy`, it serves queries from a copy of the snapshot
+#[derive(Encode, Decode, Default, Clone)]
 pub struct EcomModel {
     pub orders: HashMap<usize, Order>,
 }
@@ -0,0 +1,640 @@
+//! Inspects origo journals and snapshots
+//!
+//! The `origo-tool` binary only knows the record layout, to decode command payloads to JSON
+//! and to validate the model of a snapshot, build a binary in your own crate with a registry:
+//! ```ignore
+//! fn main() -> std::process::ExitCode {
+//!     origo_tool::Tool::new()
+//!         .model::<EcomModel>()
+//!         .command::<InsertOrder>("InsertOrder")
+//!         .run()
+//! }
+//! ```
+//! For engines with another codec use `Tool::<Json>::with_codec()`
+#[cfg(feature = "encryption")]
+use origo::storage::KeyProvider;
+use origo::{
+    storage::{
+        restore_archive, Compression, DirectoryLock, DirectoryStore, Encryption, FileHeader,
+        JournalReader, JournalRecord, BINCODE_CONFIG, SNAPSHOT_MAGIC, TOMBSTONE_COMMAND,
+    },
+    Bincode, Codec, EngineState,
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    path::Path,
+    process::ExitCode,
+};
+
+type DecodeFn = Box<dyn Fn(&[u8]) -> Result<serde_json::Value, String>>;
+/// Validates the encoded model of a snapshot
+type ValidateFn = Box<dyn Fn(&[u8]) -> Result<(), String>>;
+
+const USAGE: &str = "Usage: origo-tool <command> <file> [options]
+
+Commands:
+    list <journal>        List records: offset, length, sequence, command name and payload size
+    verify <journal>      Verify that the records decode and their sequences are consecutive
+    stats <journal>       Records and bytes per command
+    snapshot <snapshot>   Validate a snapshot file
+    repair <journal>      Find where the journal stops being valid, changes nothing without an option
+        --truncate              Cut the journal at the first invalid record
+        --drop <offset>,...     Move the records at the offsets to <journal>.quarantine
+    restore-archive <archive> <journal>
+                          Rebuild the data directory of the journal from an archive directory";
+
+/// `C` is the [`Codec`] of the registered commands and model
+pub struct Tool<C = Bincode> {
+    commands: HashMap<String, DecodeFn>,
+    model: Option<ValidateFn>,
+    /// [`Codec::ALIGN`] of the model in uncompressed snapshots
+    model_align: usize,
+    /// Name of `C`, set once something is registered
+    codec: Option<&'static str>,
+    #[cfg(feature = "encryption")]
+    keys: Option<Box<dyn KeyProvider>>,
+    _codec: PhantomData<C>,
+}
+
+impl Default for Tool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tool {
+    pub fn new() -> Self {
+        Tool::with_codec()
+    }
+}
+
+impl<C: 'static> Tool<C> {
+    /// Tool for journals and snapshots written with codec `C`, e.g. `Tool::<Json>::with_codec()`
+    pub fn with_codec() -> Self {
+        Tool {
+            commands: HashMap::new(),
+            model: None,
+            model_align: 1,
+            codec: None,
+            #[cfg(feature = "encryption")]
+            keys: None,
+            _codec: PhantomData,
+        }
+    }
+
+    /// Register a command so its payload can be decoded, `name` is the name it's journaled with
+    pub fn command<T: serde::Serialize>(mut self, name: &str) -> Self
+    where
+        C: Codec<T>,
+    {
+        let decode_fn: DecodeFn = Box::new(|data| {
+            let command = C::decode(data).map_err(|e| e.to_string())?;
+            serde_json::to_value(&command).map_err(|e| e.to_string())
+        });
+
+        self.commands.insert(name.to_string(), decode_fn);
+        self.codec = Some(C::NAME);
+        self
+    }
+
+    /// Register the model so snapshots can be fully validated
+    pub fn model<TModel>(mut self) -> Self
+    where
+        C: Codec<TModel>,
+    {
+        self.model = Some(Box::new(|data| {
+            C::decode(data).map(|_| ()).map_err(|e| e.to_string())
+        }));
+        self.model_align = C::ALIGN;
+        self.codec = Some(C::NAME);
+        self
+    }
+
+    /// Keys to read encrypted journals and snapshots
+    #[cfg(feature = "encryption")]
+    pub fn keys<K: KeyProvider>(mut self, keys: K) -> Self {
+        self.keys = Some(Box::new(keys));
+        self
+    }
+
+    /// Runs the tool with the arguments of the process
+    pub fn run(self) -> ExitCode {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        match self.run_with(&args) {
+            Ok(_) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("{}", e);
+                ExitCode::FAILURE
+            }
+        }
+    }
+
+    pub fn run_with(&self, args: &[String]) -> Result<(), String> {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        match args[..] {
+            ["list", path] => self.list(path),
+            ["verify", path] => self.verify(path),
+            ["stats", path] => self.stats(path),
+            ["snapshot", path] => self.snapshot(path),
+            ["repair", path] => self.repair(path, Repair::DryRun),
+            ["repair", path, "--truncate"] => self.repair(path, Repair::Truncate),
+            ["repair", path, "--drop", offsets] => {
+                let offsets = offsets
+                    .split(',')
+                    .map(|offset| offset.trim().parse::<u64>())
+                    .collect::<Result<HashSet<u64>, _>>()
+                    .map_err(|_| format!("Invalid offsets {}", offsets))?;
+                self.repair(path, Repair::Drop(offsets))
+            }
+            ["restore-archive", archive, path] => {
+                let end = restore_archive(&DirectoryStore::new(archive), path)
+                    .map_err(|e| format!("{}: {}", archive, e))?;
+                println!("Restored {} up to sequence {}", path, end);
+                Ok(())
+            }
+            _ => Err(USAGE.to_string()),
+        }
+    }
+
+    /// Checks that the record can be replayed, returns the sequence of the record
+    fn check_record(&self, record: &JournalRecord) -> Result<u64, String> {
+        let (envelope, payload) = record
+            .envelope(BINCODE_CONFIG)
+            .map_err(|e| format!("corrupt envelope: {}", e))?;
+
+        match self.commands.get(&record.name) {
+            Some(decode_fn) => decode_fn(payload).map_err(|e| format!("{}: {}", record.name, e))?,
+            None if record.name == TOMBSTONE_COMMAND => serde_json::Value::Null,
+            None if !self.commands.is_empty() => {
+                return Err(format!("unknown command {}", record.name))
+            }
+            None => serde_json::Value::Null,
+        };
+
+        Ok(envelope.sequence)
+    }
+
+    #[cfg(feature = "encryption")]
+    fn decrypt_snapshot(
+        &self,
+        path: &str,
+        encryption: Encryption,
+        body: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        let keys = self.keys.as_ref().ok_or_else(|| {
+            format!(
+                "{} is encrypted with key {}, register the keys to read it",
+                path, encryption.key_id
+            )
+        })?;
+        encryption
+            .decrypt_stream(keys.as_ref(), body)
+            .map_err(|e| format!("Failed to decrypt snapshot: {}", e))
+    }
+
+    /// Opening the snapshot already fails without the feature
+    #[cfg(not(feature = "encryption"))]
+    fn decrypt_snapshot(&self, path: &str, _: Encryption, _: &[u8]) -> Result<Vec<u8>, String> {
+        Err(format!(
+            "{} is encrypted, build the tool with the encryption feature",
+            path
+        ))
+    }
+
+    /// Opens the journal, payloads can only be decoded if it's written with the codec of the registry
+    fn open(&self, path: &str) -> Result<JournalReader<std::io::BufReader<File>>, String> {
+        let reader = open(path)?;
+        match (reader.header(), self.codec) {
+            (Some(header), Some(codec)) if header.codec != codec => Err(format!(
+                "{} is written with codec {}, the registry uses {}",
+                path, header.codec, codec
+            )),
+            #[cfg(feature = "encryption")]
+            _ => match &self.keys {
+                Some(keys) => reader
+                    .keys(keys.as_ref())
+                    .map_err(|e| format!("{}: {}", path, e)),
+                None => Ok(reader),
+            },
+            #[cfg(not(feature = "encryption"))]
+            _ => Ok(reader),
+        }
+    }
+
+    fn list(&self, path: &str) -> Result<(), String> {
+        let reader = self.open(path)?;
+        if let Some(header) = reader.header() {
+            print_header(header);
+        }
+        println!(
+            "{:<12} {:<8} {:<10} {:<24} {:<8}",
+            "OFFSET", "LENGTH", "SEQUENCE", "COMMAND", "PAYLOAD"
+        );
+
+        for record in reader {
+            let record = record.map_err(|e| e.to_string())?;
+            let (envelope, payload) = record
+                .envelope(BINCODE_CONFIG)
+                .map_err(|e| format!("Record at offset {}: {}", record.offset, e))?;
+
+            let json = match self.commands.get(&record.name) {
+                Some(decode_fn) => decode_fn(payload).unwrap_or_else(|e| format!("<{}>", e).into()),
+                None => serde_json::Value::Null,
+            };
+
+            println!(
+                "{:<12} {:<8} {:<10} {:<24} {:<8} {}",
+                record.offset,
+                record.length,
+                envelope.sequence,
+                record.name,
+                payload.len(),
+                if json.is_null() {
+                    String::new()
+                } else {
+                    json.to_string()
+                }
+            );
+        }
+
+        Ok(())
+    }
+
+    fn verify(&self, path: &str) -> Result<(), String> {
+        let reader = self.open(path)?;
+        let journal_len = reader.journal_len();
+
+        let mut count = 0u64;
+        let mut previous_sequence = reader
+            .header()
+            .map(|header| header.start_sequence.saturating_sub(1));
+        let mut errors = Vec::new();
+
+        for record in reader {
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    errors.push(e.to_string());
+                    break;
+                }
+            };
+
+            count += 1;
+            match self.check_record(&record) {
+                Ok(sequence) => {
+                    if let Some(previous) = previous_sequence {
+                        if sequence != previous + 1 {
+                            errors.push(format!(
+                                "Record at offset {} has sequence {}, expected {}",
+                                record.offset,
+                                sequence,
+                                previous + 1
+                            ));
+                        }
+                    }
+                    previous_sequence = Some(sequence);
+                }
+                Err(e) => errors.push(format!("Record at offset {}: {}", record.offset, e)),
+            }
+        }
+
+        println!("{} records, {} bytes", count, journal_len);
+        match errors.is_empty() {
+            true => {
+                println!("OK");
+                Ok(())
+            }
+            false => Err(errors.join("\n")),
+        }
+    }
+
+    fn stats(&self, path: &str) -> Result<(), String> {
+        let reader = self.open(path)?;
+
+        // name -> (count, bytes)
+        let mut commands = BTreeMap::<String, (u64, u64)>::new();
+        for record in reader {
+            let record = record.map_err(|e| e.to_string())?;
+            let entry = commands.entry(record.name).or_default();
+            entry.0 += 1;
+            entry.1 += record.length;
+        }
+
+        let max = commands
+            .values()
+            .map(|(count, _)| *count)
+            .max()
+            .unwrap_or(0);
+        println!(
+            "{:<24} {:<10} {:<12} {:<8}",
+            "COMMAND", "COUNT", "BYTES", "AVG"
+        );
+        for (name, (count, bytes)) in &commands {
+            println!(
+                "{:<24} {:<10} {:<12} {:<8} {}",
+                name,
+                count,
+                bytes,
+                bytes / count,
+                "#".repeat((count * 40 / max) as usize)
+            );
+        }
+
+        Ok(())
+    }
+
+    fn snapshot(&self, path: &str) -> Result<(), String> {
+        let mut data = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        if !data.starts_with(&SNAPSHOT_MAGIC) {
+            return Err(format!(
+                "{} has no snapshot header, it's either not a snapshot or written by an older version",
+                path
+            ));
+        }
+
+        let mut header_data = &data[SNAPSHOT_MAGIC.len()..];
+        let (header, header_length) =
+            FileHeader::read_from(&mut header_data).map_err(|e| format!("{}: {}", path, e))?;
+        print_header(&header);
+        if let Some(codec) = self.codec.filter(|codec| *codec != header.codec) {
+            return Err(format!(
+                "{} is written with codec {}, the registry uses {}",
+                path, header.codec, codec
+            ));
+        }
+
+        // Compressed and encrypted snapshots are the header followed by the state and model,
+        // compressed then encrypted
+        let state_offset = SNAPSHOT_MAGIC.len() + header_length as usize;
+        let (body, align) = match (header.compression, header.encryption) {
+            (Compression::None, None) => (data, self.model_align),
+            (compression, encryption) => {
+                let mut body = data.split_off(state_offset);
+                if let Some(encryption) = encryption {
+                    body = self.decrypt_snapshot(path, encryption, &body)?;
+                }
+                let body = compression
+                    .decompress_stream(&body[..])
+                    .map_err(|e| format!("Corrupt snapshot, failed to decompress: {}", e))?;
+                ([data, body].concat(), 1)
+            }
+        };
+
+        let (state, state_length): (EngineState, usize) =
+            bincode::decode_from_slice(&body[state_offset..], BINCODE_CONFIG)
+                .map_err(|e| format!("Corrupt snapshot state: {}", e))?;
+        let model_offset = (state_offset + state_length).next_multiple_of(align);
+        let model = &body[model_offset.min(body.len())..];
+
+        println!("Sequence: {}", state.sequence());
+        println!("Idempotency keys: {}", state.idempotency_keys());
+        println!("Model size: {} bytes", model.len());
+
+        if let Some(validate_fn) = &self.model {
+            validate_fn(model).map_err(|e| format!("Corrupt snapshot model: {}", e))?;
+            println!("OK");
+        }
+
+        Ok(())
+    }
+}
+
+enum Repair {
+    DryRun,
+    Truncate,
+    Drop(HashSet<u64>),
+}
+
+impl<C: 'static> Tool<C> {
+    fn repair(&self, path: &str, repair: Repair) -> Result<(), String> {
+        let directory = Path::new(path).parent().unwrap_or(Path::new(""));
+        let _lock = match DirectoryLock::try_lock(directory) {
+            Ok(lock) => lock,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                return Err(format!(
+                    "{} is locked{}, is an engine running? Stop it before repairing",
+                    directory.display(),
+                    DirectoryLock::holder(directory)
+                        .map(|pid| format!(" by pid {}", pid))
+                        .unwrap_or_default()
+                ))
+            }
+            Err(e) => return Err(format!("Failed to lock {}: {}", directory.display(), e)),
+        };
+
+        // (offset, length) of the records that can be read, valid or not
+        let mut records = Vec::new();
+        // Records before the first invalid one
+        let mut valid = 0;
+        let mut invalid = None;
+
+        let mut reader = self.open(path)?;
+        let journal_len = reader.journal_len();
+        let records_offset = reader.records_offset();
+        for record in &mut reader {
+            match record {
+                Ok(record) => {
+                    records.push((record.offset, record.length));
+                    match self.check_record(&record) {
+                        Ok(_) if invalid.is_none() => valid += 1,
+                        Ok(_) => {}
+                        Err(e) if invalid.is_none() => invalid = Some((record.offset, e)),
+                        Err(_) => {}
+                    }
+                }
+                Err(e) if invalid.is_none() => {
+                    let offset = records.last().map_or(records_offset, |(o, l)| o + l);
+                    invalid = Some((offset, e.to_string()))
+                }
+                Err(_) => {}
+            }
+        }
+        // Can't read past a broken length header, it's cut in all cases
+        let readable_end = reader.offset();
+
+        println!("{} valid records, {} bytes", valid, journal_len);
+
+        let end = match &invalid {
+            Some((offset, reason)) => {
+                println!("Journal stops being valid at offset {}: {}", offset, reason);
+                *offset
+            }
+            // Zeroed space preallocated by the mapped journal writer
+            None => readable_end,
+        };
+
+        match repair {
+            Repair::DryRun => {
+                if invalid.is_some() || end != journal_len {
+                    println!(
+                        "Run with --truncate to cut the journal at offset {} and keep {} records",
+                        end, valid
+                    );
+                    println!(
+                        "or with --drop <offset>,... to move records to {}.quarantine",
+                        path
+                    );
+                } else {
+                    println!("Nothing to repair");
+                }
+                Ok(())
+            }
+            Repair::Truncate => {
+                let file = File::options()
+                    .write(true)
+                    .open(path)
+                    .map_err(|e| format!("{}: {}", path, e))?;
+                file.set_len(end).map_err(|e| e.to_string())?;
+                file.sync_all().map_err(|e| e.to_string())?;
+                println!("Truncated at offset {}, {} records", end, valid);
+                Ok(())
+            }
+            Repair::Drop(offsets) => {
+                if readable_end != journal_len {
+                    println!(
+                        "Records after offset {} can't be read and are cut",
+                        readable_end
+                    );
+                }
+                drop_records(path, records_offset, &records, &offsets)
+            }
+        }
+    }
+}
+
+/// Rewrites the journal without the records at `offsets`, those are appended to the quarantine file
+///
+/// The quarantine file is a journal itself with the header of the journal,
+/// so it can be inspected with the tool
+///
+/// Bytes that aren't part of `records` are left out as well
+fn drop_records(
+    path: &str,
+    records_offset: u64,
+    records: &[(u64, u64)],
+    offsets: &HashSet<u64>,
+) -> Result<(), String> {
+    let unknown: Vec<&u64> = offsets
+        .iter()
+        .filter(|offset| !records.iter().any(|(o, _)| o == *offset))
+        .collect();
+    if !unknown.is_empty() {
+        return Err(format!("No records at offsets {:?}", unknown));
+    }
+
+    let mut journal = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut header = vec![0u8; records_offset as usize];
+    journal.read_exact(&mut header).map_err(|e| e.to_string())?;
+
+    let quarantine_path = format!("{}.quarantine", path);
+    let repaired_path = format!("{}.repair", path);
+
+    let mut quarantine = File::options()
+        .read(true)
+        .create(true)
+        .append(true)
+        .open(&quarantine_path)
+        .map_err(|e| format!("{}: {}", quarantine_path, e))?;
+    match quarantine.metadata().map_err(|e| e.to_string())?.len() {
+        0 => quarantine.write_all(&header).map_err(|e| e.to_string())?,
+        _ => {
+            let existing = open(&quarantine_path)?;
+            let journal = open(path)?;
+            match (existing.header(), journal.header()) {
+                (Some(existing_header), Some(header)) if same_records(existing_header, header) => {}
+                _ => {
+                    return Err(format!(
+                        "{} holds records of an older journal (another command table, compression or key), \
+                         move it away first",
+                        quarantine_path
+                    ))
+                }
+            }
+        }
+    }
+    let mut repaired =
+        File::create(&repaired_path).map_err(|e| format!("{}: {}", repaired_path, e))?;
+
+    let mut kept = 0u64;
+    repaired.write_all(&header).map_err(|e| e.to_string())?;
+
+    let mut data = Vec::new();
+    for (offset, length) in records {
+        data.resize(*length as usize, 0);
+        journal
+            .seek(SeekFrom::Start(*offset))
+            .and_then(|_| journal.read_exact(&mut data))
+            .map_err(|e| e.to_string())?;
+
+        match offsets.contains(offset) {
+            true => quarantine.write_all(&data),
+            false => {
+                kept += 1;
+                repaired.write_all(&data)
+            }
+        }
+        .map_err(|e| e.to_string())?;
+    }
+
+    repaired
+        .sync_all()
+        .and_then(|_| quarantine.sync_all())
+        .and_then(|_| std::fs::rename(&repaired_path, path))
+        .and_then(|_| sync_directory(Path::new(path)))
+        .map_err(|e| e.to_string())?;
+
+    println!(
+        "Kept {} records, moved {} to {}",
+        kept,
+        offsets.len(),
+        quarantine_path
+    );
+    Ok(())
+}
+
+/// Makes a rename in the directory of `path` durable
+fn sync_directory(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => File::open(directory)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+fn open(path: &str) -> Result<JournalReader<std::io::BufReader<std::fs::File>>, String> {
+    let reader = JournalReader::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    match reader.header() {
+        None if reader.journal_len() > 0 => Err(format!(
+            "{} has no journal header, it was written by an older version, \
+             restore it with the engine once to upgrade it",
+            path
+        )),
+        _ => Ok(reader),
+    }
+}
+
+/// Records of both journals can be read with either header
+fn same_records(a: &FileHeader, b: &FileHeader) -> bool {
+    a.version == b.version
+        && a.compression == b.compression
+        && a.encryption == b.encryption
+        && a.commands == b.commands
+}
+
+fn print_header(header: &FileHeader) {
+    println!("Format version: {}", header.version);
+    println!("Model: {}", header.model);
+    println!("Codec: {}", header.codec);
+    println!("Compression: {:?}", header.compression);
+    match header.encryption {
+        Some(encryption) => println!("Encryption: key {}", encryption.key_id),
+        None => println!("Encryption: None"),
+    }
+    if !header.commands.is_empty() {
+        println!("Commands: {}", header.commands.join(", "));
+    }
+    println!("Created: {}", header.created);
+    println!("Start sequence: {}", header.start_sequence);
+}
@@ -0,0 +1,5 @@
+/// Inspects journals and snapshots without a registry,
+/// payloads are only decoded by tools built with [`origo_tool::Tool::command`]
+fn main() -> std::process::ExitCode {
+    origo_tool::Tool::new().run()
+}
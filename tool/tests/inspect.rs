@@ -0,0 +1,170 @@
+use bincode::{Decode, Encode};
+use origo::{
+    origo_engine,
+    storage::{DiskStorage, JournalReader},
+    Command, ExecutionContext,
+};
+use std::{fs::File, os::unix::fs::FileExt, path::Path, process::Command as Process};
+
+#[derive(Encode, Decode, Default)]
+struct Counter {
+    value: u64,
+}
+
+#[derive(Encode, Decode)]
+struct Add(u64);
+
+impl Command<Counter> for Add {
+    fn execute(&self, model: &mut Counter, _ctx: &mut ExecutionContext) {
+        model.value += self.0;
+    }
+}
+
+#[derive(Encode, Decode)]
+struct Reset;
+
+impl Command<Counter> for Reset {
+    fn execute(&self, model: &mut Counter, _ctx: &mut ExecutionContext) {
+        model.value = 0;
+    }
+}
+
+/// Runs the `origo-tool` binary, returns whether it succeeded with its stdout and stderr
+fn tool(args: &[&str]) -> (bool, String, String) {
+    let output = Process::new(env!("CARGO_BIN_EXE_origo-tool"))
+        .args(args)
+        .output()
+        .expect("Failed to run origo-tool");
+    (
+        output.status.success(),
+        String::from_utf8(output.stdout).expect("Output is utf-8"),
+        String::from_utf8(output.stderr).expect("Output is utf-8"),
+    )
+}
+
+/// Writes a journal of three `Add` and one `Reset`, returns (offset, length, name) of its records
+fn write_journal(path: &Path) -> Vec<(u64, u64, String)> {
+    let db = origo_engine! { Counter, DiskStorage::new(path), Add, Reset, };
+    db.execute(Add(1));
+    db.execute(Add(300));
+    db.execute(Reset);
+    db.execute(Add(70_000));
+    drop(db);
+
+    JournalReader::open(path)
+        .expect("Failed to open journal")
+        .map(|record| record.expect("Failed to read record"))
+        .map(|record| (record.offset, record.length, record.name))
+        .collect()
+}
+
+#[test]
+fn list_prints_the_header_and_a_line_per_record() {
+    let directory = tempfile::tempdir().expect("Failed to create directory");
+    let path = directory.path().join("journal.origors");
+    let records = write_journal(&path);
+
+    let (ok, output, _) = tool(&["list", path.to_str().unwrap()]);
+    assert!(ok);
+    let lines: Vec<&str> = output.lines().collect();
+    assert!(lines.contains(&"Codec: bincode"), "{}", output);
+    assert!(lines.contains(&"Commands: Add, Reset"), "{}", output);
+    assert!(lines.contains(&"Start sequence: 1"), "{}", output);
+
+    let table: Vec<Vec<&str>> = lines
+        .iter()
+        .skip_while(|line| !line.starts_with("OFFSET"))
+        .skip(1)
+        .map(|line| line.split_whitespace().collect())
+        .collect();
+    assert_eq!(table.len(), records.len(), "{}", output);
+    for (sequence, (row, (offset, length, name))) in table.iter().zip(&records).enumerate() {
+        assert_eq!(row[0], offset.to_string());
+        assert_eq!(row[1], length.to_string());
+        assert_eq!(row[2], (sequence + 1).to_string());
+        assert_eq!(row[3], name);
+    }
+}
+
+#[test]
+fn verify_reports_a_corrupt_record() {
+    let directory = tempfile::tempdir().expect("Failed to create directory");
+    let path = directory.path().join("journal.origors");
+    let records = write_journal(&path);
+    let journal_len = std::fs::metadata(&path).unwrap().len();
+
+    let (ok, output, _) = tool(&["verify", path.to_str().unwrap()]);
+    assert!(ok);
+    assert_eq!(
+        output,
+        format!("{} records, {} bytes\nOK\n", records.len(), journal_len)
+    );
+
+    // The last byte of the second record, the records after it are intact
+    let (offset, length, _) = records[1];
+    let journal = File::options().read(true).write(true).open(&path).unwrap();
+    let mut byte = [0u8; 1];
+    journal
+        .read_exact_at(&mut byte, offset + length - 1)
+        .unwrap();
+    journal
+        .write_all_at(&[byte[0] ^ 0xff], offset + length - 1)
+        .unwrap();
+
+    let (ok, output, errors) = tool(&["verify", path.to_str().unwrap()]);
+    assert!(!ok);
+    assert_eq!(output, format!("1 records, {} bytes\n", journal_len));
+    assert_eq!(
+        errors.trim(),
+        format!(
+            "record at offset {} doesn't match its checksum and more data follows it",
+            offset
+        )
+    );
+}
+
+#[test]
+fn stats_counts_records_and_bytes_per_command() {
+    let directory = tempfile::tempdir().expect("Failed to create directory");
+    let path = directory.path().join("journal.origors");
+    let records = write_journal(&path);
+
+    let (ok, output, _) = tool(&["stats", path.to_str().unwrap()]);
+    assert!(ok);
+    let rows: Vec<Vec<&str>> = output
+        .lines()
+        .map(|line| line.split_whitespace().collect())
+        .collect();
+    assert_eq!(rows[0], ["COMMAND", "COUNT", "BYTES", "AVG"]);
+
+    let bytes = |name: &str| -> u64 {
+        records
+            .iter()
+            .filter(|(_, _, record)| record == name)
+            .map(|(_, length, _)| length)
+            .sum()
+    };
+    let add = bytes("Add");
+    let reset = bytes("Reset");
+    assert_eq!(
+        rows[1],
+        [
+            "Add",
+            "3",
+            &add.to_string(),
+            &(add / 3).to_string(),
+            &"#".repeat(40)
+        ]
+    );
+    assert_eq!(
+        rows[2],
+        [
+            "Reset",
+            "1",
+            &reset.to_string(),
+            &reset.to_string(),
+            &"#".repeat(13)
+        ]
+    );
+    assert_eq!(rows.len(), 3);
+}
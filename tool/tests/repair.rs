@@ -0,0 +1,136 @@
+use bincode::{Decode, Encode};
+use origo::{
+    origo_engine,
+    storage::{DiskStorage, JournalReader},
+    Command, ExecutionContext,
+};
+use origo_tool::Tool;
+use std::{fs::File, io::Write, path::Path};
+
+#[derive(Encode, Decode, Default)]
+struct Counter {
+    value: u64,
+}
+
+#[derive(Encode, Decode, serde::Serialize)]
+struct Add(u64);
+
+impl Command<Counter> for Add {
+    fn execute(&self, model: &mut Counter, _ctx: &mut ExecutionContext) {
+        model.value += self.0;
+    }
+}
+
+/// Only known to the engine, the tool can't decode it
+#[derive(Encode, Decode)]
+struct Reset;
+
+impl Command<Counter> for Reset {
+    fn execute(&self, model: &mut Counter, _ctx: &mut ExecutionContext) {
+        model.value = 0;
+    }
+}
+
+fn repair(path: &str) -> Result<(), String> {
+    repair_with(path, &[])
+}
+
+fn repair_with(path: &str, args: &[&str]) -> Result<(), String> {
+    let args: Vec<String> = ["repair", path]
+        .iter()
+        .chain(args)
+        .map(|arg| arg.to_string())
+        .collect();
+    Tool::new().command::<Add>("Add").run_with(&args)
+}
+
+fn restore(path: &str) -> u64 {
+    let db = origo_engine! { Counter, DiskStorage::new(path), Add, Reset, };
+    db.query(|counter| counter.value)
+}
+
+/// (offset, name) of the records in the journal
+fn records(path: &Path) -> Vec<(u64, String)> {
+    JournalReader::open(path)
+        .expect("Failed to open journal")
+        .map(|record| record.expect("Failed to read record"))
+        .map(|record| (record.offset, record.name))
+        .collect()
+}
+
+#[test]
+fn repair_refuses_a_directory_locked_by_a_live_storage() {
+    let directory = tempfile::tempdir().expect("Failed to create directory");
+    let path = directory.path().join("journal.origors");
+    let path = path.to_str().expect("Path is utf-8");
+
+    let db = origo_engine! { Counter, DiskStorage::new(path), Add, };
+    db.execute(Add(1));
+
+    let error = repair(path).expect_err("Repair ran on a locked directory");
+    assert!(error.contains("is locked"), "{}", error);
+    assert!(
+        error.contains(&format!("pid {}", std::process::id())),
+        "{}",
+        error
+    );
+
+    drop(db);
+    repair(path).expect("Repair failed after the storage was dropped");
+}
+
+#[test]
+fn truncate_cuts_the_journal_where_it_stops_being_valid() {
+    let directory = tempfile::tempdir().expect("Failed to create directory");
+    let path = directory.path().join("journal.origors");
+    let path = path.to_str().expect("Path is utf-8");
+
+    let db = origo_engine! { Counter, DiskStorage::new(path), Add, Reset, };
+    for value in [1, 2, 4] {
+        db.execute(Add(value));
+    }
+    drop(db);
+    let valid_len = std::fs::metadata(path).unwrap().len();
+
+    // A length header that runs past the end of the file
+    let mut journal = File::options().append(true).open(path).unwrap();
+    journal.write_all(&[0x7f, 1, 2, 3]).unwrap();
+    drop(journal);
+
+    repair(path).expect("Dry run failed");
+    assert_eq!(std::fs::metadata(path).unwrap().len(), valid_len + 4);
+
+    repair_with(path, &["--truncate"]).expect("Truncate failed");
+    assert_eq!(std::fs::metadata(path).unwrap().len(), valid_len);
+    assert_eq!(restore(path), 7);
+}
+
+#[test]
+fn dropped_records_are_moved_to_the_quarantine() {
+    let directory = tempfile::tempdir().expect("Failed to create directory");
+    let path = directory.path().join("journal.origors");
+    let path_str = path.to_str().expect("Path is utf-8");
+
+    let db = origo_engine! { Counter, DiskStorage::new(&path), Add, Reset, };
+    db.execute(Add(1));
+    db.execute(Reset);
+    db.execute(Add(2));
+    drop(db);
+
+    let before = records(&path);
+    let (reset_offset, name) = before[1].clone();
+    assert_eq!(name, "Reset");
+
+    repair_with(path_str, &["--drop", "12345"]).expect_err("Dropped a record that doesn't exist");
+    repair_with(path_str, &["--drop", &reset_offset.to_string()]).expect("Drop failed");
+
+    let names: Vec<String> = records(&path).into_iter().map(|(_, name)| name).collect();
+    assert_eq!(names, ["Add", "Add"]);
+    let quarantine = directory.path().join("journal.origors.quarantine");
+    let quarantined: Vec<String> = records(&quarantine)
+        .into_iter()
+        .map(|(_, name)| name)
+        .collect();
+    assert_eq!(quarantined, ["Reset"]);
+    repair(path_str).expect("Repaired journal is invalid");
+}